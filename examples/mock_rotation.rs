@@ -0,0 +1,64 @@
+//! Steps the engine by hand over two stand-in sources, skipping images with a custom filter and
+//! handing the rest to a setter that only records them.
+//!
+//! Run with `cargo run --example mock_rotation --features testing`.
+
+#![allow(bare_trait_objects)]
+
+extern crate wallsplash;
+
+use std::env;
+use std::path::PathBuf;
+
+use wallsplash::testing::{self, MockFetcher, MockSetter};
+use wallsplash::{Engine, Fetch, Filter, FilterCtx, ImageInfo, Order, Verdict, WallpaperSetter};
+
+/// Filter that keeps images whose file name does not mention "skip".
+struct SkipNamed;
+
+impl Filter for SkipNamed {
+    fn name(&self) -> &str {
+        "skip-named"
+    }
+
+    fn accept(&self, info: &ImageInfo, _: &FilterCtx) -> Verdict {
+        let name = info.path.file_name().map(|n| n.to_string_lossy().into_owned());
+        match name {
+            Some(ref n) if n.contains("skip") => Verdict::Reject {
+                reason: "named skip".to_owned(),
+            },
+            _ => Verdict::Accept,
+        }
+    }
+}
+
+/// Write a file that looks like a JPEG to the temporary directory.
+fn fake_jpeg(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(name);
+    testing::write_jpeg(&path, 1920, 1080).expect("cannot write example image");
+    path
+}
+
+fn main() {
+    let holiday = vec![fake_jpeg("holiday-1.jpg"), fake_jpeg("holiday-skip.jpg")];
+    let nature = vec![fake_jpeg("nature-1.jpg"), fake_jpeg("nature-2.jpg")];
+
+    let sources: Vec<Box<Fetch>> = vec![
+        Box::new(MockFetcher::new("holiday", holiday)),
+        Box::new(MockFetcher::new("nature", nature)),
+    ];
+    let mut engine = Engine::with_sources(sources, &Order::Alternate).expect("invalid sources");
+    engine.add_filter(Box::new(SkipNamed));
+
+    let setter = MockSetter::new();
+    for _ in 0..4 {
+        let info = engine.next_image().expect("no image");
+        println!("{}: {}", info.source, info.path.display());
+        setter.set(&info.path).expect("cannot set wallpaper");
+    }
+
+    assert_eq!(setter.history().len(), 4);
+    for stats in engine.filter_stats() {
+        println!("{}: {} rejected", stats.name, stats.rejected);
+    }
+}
@@ -0,0 +1,1034 @@
+//! Module for the on-disk Unsplash image cache. The cache may be shared by several machines over
+//! a network filesystem, so refreshes are serialized with a lock file and downloaded into a
+//! private staging directory. A finished staging directory becomes the live batch by atomically
+//! repointing a symlink at it, so other instances only ever see whole batches.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use libc;
+
+use verify;
+
+/// Name of the lock file held while refreshing the cache.
+const LOCK_FILE: &'static str = ".lock";
+
+/// Name of the file exclusively created by whoever breaks a stale lock, so that only one instance
+/// takes it over.
+const LOCK_BREAK_FILE: &'static str = ".lock.break";
+
+/// Name of the symlink pointing at the directory of the live batch of images.
+const CURRENT_LINK: &'static str = ".current";
+
+/// Name of the file in a batch directory recording when it was downloaded and how many images
+/// it holds.
+const MARKER_FILE: &'static str = ".refreshed";
+
+/// Prefix of the directories that refreshes download into.
+const STAGING_PREFIX: &'static str = ".staging-";
+
+/// File extensions of the image formats that are cached.
+const IMAGE_EXTENSIONS: &'static [&'static str] = &["jpg", "png", "webp"];
+
+/// Name of the file created to check whether the cache can be written again.
+const PROBE_FILE: &'static str = ".probe";
+
+/// Seconds after which a lock or staging directory is considered abandoned.
+const STALE_SECS: u64 = 10 * 60;
+
+/// Count of unique names handed out, to tell apart names made in the same nanosecond.
+static UNIQUE: AtomicUsize = AtomicUsize::new(0);
+
+/// Get the Unsplash image cache directory for the given home directory.
+pub fn cache_path(home: &Path) -> PathBuf {
+    let mut p = home.to_path_buf();
+    p.push(".config");
+    p.push("wallsplash");
+    p.push("cache");
+    p
+}
+
+/// Get the path of a cached image in the format with the given extension.
+pub fn image_path(dir: &Path, idx: usize, ext: &str) -> PathBuf {
+    dir.join(format!("{}.{}", idx, ext))
+}
+
+/// Get the directory holding the live batch of images, if a refresh has completed.
+pub fn live_dir(dir: &Path) -> Option<PathBuf> {
+    fs::read_link(dir.join(CURRENT_LINK))
+        .ok()
+        .map(|target| dir.join(target))
+}
+
+/// Find the image at the given position in a batch or staging directory, whatever its format.
+pub fn find_in(batch: &Path, idx: usize) -> Option<PathBuf> {
+    IMAGE_EXTENSIONS
+        .iter()
+        .map(|ext| image_path(batch, idx, ext))
+        .find(|path| path.is_file())
+}
+
+/// Whether an error means nothing in the cache can be written, because the filesystem is mounted
+/// read-only or permissions forbid it, as opposed to a single file having gone missing.
+pub fn is_unwritable(err: &(Error + 'static)) -> bool {
+    let err = match err.downcast_ref::<io::Error>() {
+        Some(e) => e,
+        None => return false,
+    };
+    if err.kind() == io::ErrorKind::PermissionDenied {
+        return true;
+    }
+    match err.raw_os_error() {
+        Some(code) => code == libc::EROFS || code == libc::EACCES || code == libc::EPERM,
+        None => false,
+    }
+}
+
+/// Check that the cache directory can be written, creating it if needed.
+pub fn probe_writable(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(PROBE_FILE);
+    fs::File::create(&path)?;
+    fs::remove_file(&path)
+}
+
+/// Check of whether the cache directory can be written, so that tests can make it fail. Probes
+/// are `Send` so that a fetcher holding one can start up on another thread.
+pub trait Probe: fmt::Debug + Send {
+    /// Try writing to the directory.
+    fn writable(&self, dir: &Path) -> io::Result<()>;
+}
+
+/// Probe writing a file into the cache directory on disk.
+#[derive(Debug)]
+pub struct DiskProbe;
+
+impl Probe for DiskProbe {
+    fn writable(&self, dir: &Path) -> io::Result<()> {
+        probe_writable(dir)
+    }
+}
+
+/// Count the cached images that are usable, without changing anything. Only images numbered
+/// from zero up to the first gap are counted, which is all of them after a repair.
+pub fn count_images(dir: &Path) -> usize {
+    let live = match live_dir(dir) {
+        Some(live) => live,
+        None => return 0,
+    };
+    let mut total = 0;
+    while find_in(&live, total).is_some() {
+        total += 1;
+    }
+    match read_marker_raw(&live) {
+        Some((_, count)) => total.min(count),
+        None => total,
+    }
+}
+
+/// Lock held while refreshing the cache. Released when dropped.
+#[derive(Debug)]
+pub struct CacheLock {
+    path: PathBuf,
+    /// Contents of the lock file that identify this holder, so that a lock broken and taken over
+    /// by another instance is never renewed or released by this one.
+    token: String,
+}
+
+impl CacheLock {
+    /// Try to take the refresh lock, taking it over if its holder appears to have died. Returns
+    /// `None` when another instance is currently refreshing.
+    pub fn acquire(dir: &Path) -> Result<Option<CacheLock>, Box<Error>> {
+        let path = dir.join(LOCK_FILE);
+        let token = unique_name();
+
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => {
+                file.write_all(token.as_bytes())?;
+                return Ok(Some(CacheLock {
+                    path: path,
+                    token: token,
+                }));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(Box::new(e)),
+        }
+
+        if !is_stale(&path) {
+            return Ok(None);
+        }
+        CacheLock::take_over(dir, path, token)
+    }
+
+    /// Replace a stale lock with a new one. Instances take turns breaking it by exclusively
+    /// creating a separate file, and the new lock is renamed over the stale one, so there is
+    /// never a moment without a lock for a third instance to take.
+    fn take_over(
+        dir: &Path,
+        path: PathBuf,
+        token: String,
+    ) -> Result<Option<CacheLock>, Box<Error>> {
+        let breaker = dir.join(LOCK_BREAK_FILE);
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&breaker)
+        {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                // Left behind by an instance that died while breaking the lock.
+                if is_stale(&breaker) {
+                    let _ = fs::remove_file(&breaker);
+                }
+                return Ok(None);
+            }
+            Err(e) => return Err(Box::new(e)),
+        }
+
+        // Another instance may have broken and retaken the lock since it was checked.
+        let taken = if is_stale(&path) {
+            warn!("breaking stale cache lock {}", path.display());
+            let tmp = dir.join(format!("{}.{}", LOCK_FILE, token));
+            let replaced = fs::File::create(&tmp)
+                .and_then(|mut file| file.write_all(token.as_bytes()))
+                .and_then(|_| fs::rename(&tmp, &path));
+            if replaced.is_err() {
+                let _ = fs::remove_file(&tmp);
+            }
+            replaced.map(|_| {
+                Some(CacheLock {
+                    path: path,
+                    token: token,
+                })
+            })
+        } else {
+            Ok(None)
+        };
+
+        if let Err(e) = fs::remove_file(&breaker) {
+            warn!("failed to remove {}: {}", breaker.display(), e);
+        }
+        Ok(taken?)
+    }
+
+    /// Show that the refresh holding the lock is still making progress, so that other instances
+    /// do not break it while a long refresh is running. Fails if it was broken anyway.
+    pub fn touch(&self) -> io::Result<()> {
+        if !self.held() {
+            return Err(io::Error::other("cache lock was taken over by another instance"));
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        file.write_all(self.token.as_bytes())
+    }
+
+    /// Whether the lock file still belongs to this holder.
+    fn held(&self) -> bool {
+        let mut content = String::new();
+        fs::File::open(&self.path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .map(|_| content == self.token)
+            .unwrap_or(false)
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        if !self.held() {
+            debug!("cache lock {} was taken over, leaving it", self.path.display());
+            return;
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("failed to release cache lock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Create a uniquely named staging directory to download a refresh into.
+pub fn create_staging(dir: &Path) -> io::Result<PathBuf> {
+    let staging = dir.join(format!("{}{}", STAGING_PREFIX, unique_name()));
+    fs::create_dir(&staging)?;
+    Ok(staging)
+}
+
+/// Make a staging directory holding a completed refresh of `count` images the live batch. Its
+/// marker is written first and the live symlink is then swapped over to it with a rename, so
+/// other instances see either the old batch or the new one and never a mix. The batch being
+/// replaced is kept until the next refresh for anyone still showing one of its images, and any
+/// older ones are removed.
+pub fn complete(staging: &Path, dir: &Path, count: usize) -> Result<(), Box<Error>> {
+    write_marker(staging, count)?;
+    publish(staging, dir)
+}
+
+/// Make a staging directory whose marker is already written the live batch, keeping the batch
+/// it replaces and removing any older ones.
+fn publish(staging: &Path, dir: &Path) -> Result<(), Box<Error>> {
+    let previous = live_dir(dir);
+    swap_live(dir, staging)?;
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let keep = path == staging || previous.as_ref().is_some_and(|p| *p == path);
+        if !keep && path.is_dir() && is_staging(&path) {
+            debug!("removing old unsplash batch {}", path.display());
+            fs::remove_dir_all(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Point the live symlink at the batch directory, replacing the old link in one rename.
+fn swap_live(dir: &Path, batch: &Path) -> io::Result<()> {
+    let target = match batch.file_name() {
+        Some(name) => name,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no batch name")),
+    };
+    let tmp = dir.join(format!("{}.{}", CURRENT_LINK, unique_name()));
+    symlink(target, &tmp)?;
+    fs::rename(&tmp, dir.join(CURRENT_LINK)).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// Read the last refresh of the cache, as the time since it happened and the number of images
+/// it left behind. The timestamp is written by whichever instance refreshed, so it is trusted
+/// over any local notion of when the last refresh was.
+pub fn read_marker(dir: &Path) -> Option<(Duration, usize)> {
+    let (when, count) = read_marker_raw(&live_dir(dir)?)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let age = Duration::from_secs(now.saturating_sub(when));
+    Some((age, count))
+}
+
+/// Read the marker of a batch directory as the refresh time in seconds since the epoch and the
+/// number of images.
+fn read_marker_raw(batch: &Path) -> Option<(u64, usize)> {
+    let mut content = String::new();
+    fs::File::open(batch.join(MARKER_FILE))
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .ok()?;
+
+    let mut parts = content.split_whitespace();
+    let when = parts.next()?.parse::<u64>().ok()?;
+    let count = parts.next()?.parse::<usize>().ok()?;
+    Some((when, count))
+}
+
+fn write_marker(batch: &Path, count: usize) -> Result<(), Box<Error>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    write_marker_at(batch, now, count)
+}
+
+/// Replace the marker of a batch directory through a rename, so readers never see it half
+/// written.
+fn write_marker_at(batch: &Path, when: u64, count: usize) -> Result<(), Box<Error>> {
+    let tmp = batch.join(format!("{}.{}", MARKER_FILE, unique_name()));
+    fs::File::create(&tmp)?.write_all(format!("{} {}\n", when, count).as_bytes())?;
+    fs::rename(&tmp, batch.join(MARKER_FILE))?;
+    Ok(())
+}
+
+/// Remove the live batch of images. Returns the number of images removed.
+pub fn evict(dir: &Path) -> Result<usize, Box<Error>> {
+    let live = match live_dir(dir) {
+        Some(live) => live,
+        None => return Ok(0),
+    };
+    let count = count_images(dir);
+
+    match fs::remove_file(dir.join(CURRENT_LINK)) {
+        Ok(_) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(Box::new(e)),
+    }
+    match fs::remove_dir_all(&live) {
+        Ok(_) => Ok(count),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(count),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+/// Remove the cached images downloaded longer than `max_age` ago, except `keep`, which may be
+/// on screen. The survivors are linked into a new batch, numbered from zero in their old order,
+/// and the original refresh time is kept. Returns the old positions of the survivors, or `None`
+/// when nothing expired or another instance is refreshing the cache.
+pub fn evict_older(
+    dir: &Path,
+    max_age: Duration,
+    keep: Option<&Path>,
+) -> Result<Option<Vec<usize>>, Box<Error>> {
+    let live = match live_dir(dir) {
+        Some(live) => live,
+        None => return Ok(None),
+    };
+
+    let mut kept = Vec::new();
+    let mut expired = Vec::new();
+    for idx in 0..count_images(dir) {
+        let path = match find_in(&live, idx) {
+            Some(path) => path,
+            None => break,
+        };
+        let age = path.metadata()?.modified()?.elapsed().unwrap_or_default();
+        if age > max_age && keep.is_none_or(|k| k != path) {
+            expired.push(path);
+        } else {
+            kept.push((idx, path));
+        }
+    }
+    if expired.is_empty() {
+        return Ok(None);
+    }
+
+    let _lock = match CacheLock::acquire(dir)? {
+        Some(lock) => lock,
+        None => {
+            debug!("cache is being refreshed elsewhere, skipping eviction");
+            return Ok(None);
+        }
+    };
+    if live_dir(dir).as_ref() != Some(&live) {
+        // Refreshed by another instance since it was checked.
+        return Ok(None);
+    }
+    if kept.is_empty() {
+        evict(dir)?;
+        return Ok(Some(Vec::new()));
+    }
+
+    let batch = create_staging(dir)?;
+    for (new, (_, path)) in kept.iter().enumerate() {
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("jpg");
+        fs::hard_link(path, image_path(&batch, new, ext))?;
+    }
+    let when = read_marker_raw(&live).map_or(0, |(when, _)| when);
+    write_marker_at(&batch, when, kept.len())?;
+    publish(&batch, dir)?;
+
+    // The replaced batch is kept for anyone still showing one of the survivors, but expired
+    // images are of no more use to anyone.
+    for path in &expired {
+        fs::remove_file(path)?;
+    }
+    info!(
+        "removed {} unsplash images older than {}s",
+        expired.len(),
+        max_age.as_secs()
+    );
+    Ok(Some(kept.into_iter().map(|(idx, _)| idx).collect()))
+}
+
+/// What a repair pass of the cache found and fixed.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Repair {
+    /// Number of usable images left, numbered from zero without gaps.
+    pub total: usize,
+    /// Images counted by the marker that were missing or failed validation.
+    pub dropped: usize,
+    /// Valid images beyond the marker's count that were kept.
+    pub adopted: usize,
+    /// Partial downloads, abandoned staging directories, and unrecorded bad or duplicate images
+    /// that were deleted.
+    pub removed: usize,
+}
+
+/// Cleans up the cache directory after an unclean shutdown. Partial downloads and abandoned
+/// staging directories are deleted, images of the live batch that fail validation are dropped,
+/// valid images its marker does not count are adopted, and the survivors are renumbered from
+/// zero before the marker is rewritten. A cache from before batches were kept in their own
+/// directories is moved into one first. Skipped when another instance holds the refresh lock.
+pub fn repair(dir: &Path) -> Result<Repair, Box<Error>> {
+    let _lock = match CacheLock::acquire(dir)? {
+        Some(lock) => lock,
+        None => {
+            debug!("cache is being refreshed elsewhere, skipping repair");
+            return Ok(Repair {
+                total: count_images(dir),
+                ..Repair::default()
+            });
+        }
+    };
+
+    let mut repair = Repair::default();
+    let live = match live_dir(dir) {
+        Some(live) => Some(live),
+        None => migrate_flat(dir)?,
+    };
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            let abandoned = is_staging(&path) && is_stale(&path);
+            if abandoned && live.as_ref().is_none_or(|l| *l != path) {
+                fs::remove_dir_all(&path)?;
+                repair.removed += 1;
+            }
+        } else if is_partial(&path) {
+            fs::remove_file(&path)?;
+            repair.removed += 1;
+        }
+    }
+
+    match live {
+        Some(ref live) if live.is_dir() => repair_batch(live, &mut repair)?,
+        _ => {}
+    }
+    if repair.dropped > 0 || repair.adopted > 0 || repair.removed > 0 {
+        info!(
+            "repaired cache: dropped {}, adopted {}, removed {} partials",
+            repair.dropped, repair.adopted, repair.removed
+        );
+    }
+    Ok(repair)
+}
+
+/// Repair the images of the live batch directory, tallying what was done.
+fn repair_batch(live: &Path, repair: &mut Repair) -> Result<(), Box<Error>> {
+    let marker = read_marker_raw(live);
+    let recorded = marker.map_or(0, |(_, count)| count);
+
+    let mut images = Vec::new();
+    for entry in fs::read_dir(live)? {
+        let path = entry?.path();
+        if is_partial(&path) {
+            fs::remove_file(&path)?;
+            repair.removed += 1;
+        } else if let Some(idx) = image_index(&path) {
+            images.push((idx, path));
+        }
+    }
+    images.sort();
+
+    let mut kept: Vec<(usize, PathBuf)> = Vec::new();
+    for (idx, path) in images {
+        let duplicate = kept.last().is_some_and(|&(last, _)| last == idx);
+        if !duplicate && verify::check_image(&path).is_ok() {
+            kept.push((idx, path));
+            continue;
+        }
+        debug!("removing bad cached image {}", path.display());
+        fs::remove_file(&path)?;
+        if duplicate || idx >= recorded {
+            repair.removed += 1;
+        }
+    }
+
+    let recorded_kept = kept.iter().filter(|&&(idx, _)| idx < recorded).count();
+    repair.dropped = recorded - recorded_kept;
+    repair.adopted = kept.len() - recorded_kept;
+    repair.total = kept.len();
+
+    // Survivors are in ascending order, so each one moves down into a slot already vacated.
+    let mut renamed = false;
+    for (new, &(old, ref path)) in kept.iter().enumerate() {
+        if new != old {
+            let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("jpg");
+            fs::rename(path, image_path(live, new, ext))?;
+            renamed = true;
+        }
+    }
+
+    let changed = repair.dropped > 0 || repair.adopted > 0 || renamed;
+    if changed || marker.is_some_and(|(_, count)| count != repair.total) {
+        // Keep the original refresh time so that the repair does not make the cache look fresh.
+        write_marker_at(live, marker.map_or(0, |(when, _)| when), repair.total)?;
+    }
+    Ok(())
+}
+
+/// Move the images and marker of a cache kept directly in the cache directory into a batch
+/// directory and make it live. Returns the new live batch, if there was anything to move.
+fn migrate_flat(dir: &Path) -> Result<Option<PathBuf>, Box<Error>> {
+    let mut moving = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let marker = path.file_name().is_some_and(|n| n == MARKER_FILE);
+        if marker || (path.is_file() && image_index(&path).is_some()) {
+            moving.push(path);
+        }
+    }
+    if moving.is_empty() {
+        return Ok(None);
+    }
+
+    let batch = create_staging(dir)?;
+    for path in moving {
+        if let Some(name) = path.file_name() {
+            fs::rename(&path, batch.join(name))?;
+        }
+    }
+    swap_live(dir, &batch)?;
+    info!("moved unsplash cache into {}", batch.display());
+    Ok(Some(batch))
+}
+
+/// Whether the path names a staging or batch directory.
+fn is_staging(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with(STAGING_PREFIX))
+}
+
+/// Whether the path names a partial download.
+fn is_partial(path: &Path) -> bool {
+    path.is_file() && path.extension().is_some_and(|ext| ext == "part")
+}
+
+/// Name that no other instance, on this machine or another sharing the cache, is using.
+fn unique_name() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let seq = UNIQUE.fetch_add(1, Ordering::SeqCst);
+    format!("{}-{}-{}", process::id(), nanos, seq)
+}
+
+/// Position of a cached image from its file name, if it is one.
+fn image_index(path: &Path) -> Option<usize> {
+    let ext = path.extension()?.to_str()?;
+    if !IMAGE_EXTENSIONS.contains(&ext) {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Whether a file or directory has not been modified for a long time.
+fn is_stale(path: &Path) -> bool {
+    path.metadata()
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.elapsed().ok())
+        .is_some_and(|age| age.as_secs() >= STALE_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use testing;
+
+    /// Empty directory to build a cache in, unique to the test.
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("wallsplash-cache-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Make a new, empty batch directory the live one.
+    fn live_batch(dir: &Path) -> PathBuf {
+        let batch = create_staging(dir).unwrap();
+        swap_live(dir, &batch).unwrap();
+        batch
+    }
+
+    fn image(dir: &Path, idx: usize, ext: &str) {
+        let path = image_path(dir, idx, ext);
+        match ext {
+            "png" => testing::write_png(&path, 64, 48).unwrap(),
+            _ => testing::write_jpeg(&path, 64, 48).unwrap(),
+        }
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Pretend the file was last modified long enough ago to count as abandoned.
+    fn make_stale(path: &Path) {
+        age(path, STALE_SECS + 60);
+    }
+
+    /// Pretend the file was downloaded the given number of seconds ago.
+    fn age(path: &Path, secs: u64) {
+        let then = SystemTime::now() - Duration::from_secs(secs);
+        fs::File::open(path).unwrap().set_modified(then).unwrap();
+    }
+
+    #[test]
+    fn evict_older_keeps_fresh_and_shown_images() {
+        let dir = cache_dir("evict-some");
+        let old = live_batch(&dir);
+        for idx in 0..5 {
+            image(&old, idx, "jpg");
+        }
+        write_marker_at(&old, 100, 5).unwrap();
+        for &idx in &[0, 2, 3] {
+            age(&image_path(&old, idx, "jpg"), 2 * 60 * 60);
+        }
+        let shown = image_path(&old, 2, "jpg");
+
+        let kept = evict_older(&dir, Duration::from_secs(60 * 60), Some(&shown)).unwrap();
+        assert_eq!(kept, Some(vec![1, 2, 4]));
+
+        let live = live_dir(&dir).unwrap();
+        assert!(live != old);
+        assert_eq!(names(&live), vec![".refreshed", "0.jpg", "1.jpg", "2.jpg"]);
+        assert_eq!(read_marker_raw(&live), Some((100, 3)));
+        // The shown image is still there for the setter, but the other expired ones are gone.
+        assert!(shown.exists());
+        assert!(!image_path(&old, 0, "jpg").exists());
+        assert!(!image_path(&old, 3, "jpg").exists());
+    }
+
+    #[test]
+    fn evict_older_leaves_a_fresh_cache_alone() {
+        let dir = cache_dir("evict-none");
+        let live = live_batch(&dir);
+        image(&live, 0, "jpg");
+        image(&live, 1, "png");
+        write_marker_at(&live, 100, 2).unwrap();
+
+        assert_eq!(evict_older(&dir, Duration::from_secs(60), None).unwrap(), None);
+        assert_eq!(live_dir(&dir), Some(live));
+    }
+
+    #[test]
+    fn evict_older_removes_the_batch_when_everything_expired() {
+        let dir = cache_dir("evict-all");
+        let live = live_batch(&dir);
+        image(&live, 0, "jpg");
+        write_marker_at(&live, 100, 1).unwrap();
+        age(&image_path(&live, 0, "jpg"), 120);
+
+        let kept = evict_older(&dir, Duration::from_secs(60), None).unwrap();
+        assert_eq!(kept, Some(vec![]));
+        assert_eq!(live_dir(&dir), None);
+        assert_eq!(count_images(&dir), 0);
+    }
+
+    #[test]
+    fn repair_leaves_a_clean_cache_alone() {
+        let dir = cache_dir("clean");
+        let live = live_batch(&dir);
+        for idx in 0..3 {
+            image(&live, idx, "jpg");
+        }
+        write_marker_at(&live, 100, 3).unwrap();
+
+        let repair = repair(&dir).unwrap();
+        assert_eq!(repair, Repair { total: 3, ..Repair::default() });
+        assert_eq!(read_marker_raw(&live), Some((100, 3)));
+        assert_eq!(names(&live), vec![".refreshed", "0.jpg", "1.jpg", "2.jpg"]);
+    }
+
+    #[test]
+    fn repair_drops_adopts_and_renumbers() {
+        let dir = cache_dir("mixed");
+        let live = live_batch(&dir);
+        image(&live, 0, "jpg");
+        fs::write(image_path(&live, 1, "jpg"), b"").unwrap();
+        image(&live, 3, "png");
+        image(&live, 4, "jpg");
+        fs::write(live.join("5.jpg.part"), b"\xFF\xD8").unwrap();
+        write_marker_at(&live, 100, 4).unwrap();
+
+        let repair = repair(&dir).unwrap();
+        assert_eq!(
+            repair,
+            Repair {
+                total: 3,
+                dropped: 2,
+                adopted: 1,
+                removed: 1,
+            }
+        );
+        assert_eq!(read_marker_raw(&live), Some((100, 3)));
+        assert_eq!(names(&live), vec![".refreshed", "0.jpg", "1.png", "2.jpg"]);
+        assert_eq!(count_images(&dir), 3);
+    }
+
+    #[test]
+    fn repair_drops_images_that_fail_validation() {
+        let dir = cache_dir("invalid");
+        let live = live_batch(&dir);
+        image(&live, 0, "jpg");
+        fs::write(image_path(&live, 1, "jpg"), b"<html>rate limited</html>").unwrap();
+        image(&live, 2, "jpg");
+        fs::write(image_path(&live, 3, "png"), b"not a png").unwrap();
+        write_marker_at(&live, 100, 3).unwrap();
+
+        let repair = repair(&dir).unwrap();
+        assert_eq!(
+            repair,
+            Repair {
+                total: 2,
+                dropped: 1,
+                adopted: 0,
+                removed: 1,
+            }
+        );
+        assert_eq!(names(&live), vec![".refreshed", "0.jpg", "1.jpg"]);
+    }
+
+    #[test]
+    fn repair_keeps_one_image_per_position() {
+        let dir = cache_dir("duplicate");
+        let live = live_batch(&dir);
+        image(&live, 0, "jpg");
+        image(&live, 0, "png");
+        write_marker_at(&live, 100, 1).unwrap();
+
+        let repair = repair(&dir).unwrap();
+        assert_eq!(repair.total, 1);
+        assert_eq!(repair.removed, 1);
+        assert_eq!(names(&live), vec![".refreshed", "0.jpg"]);
+    }
+
+    #[test]
+    fn repair_adopts_images_without_a_marker_as_stale() {
+        let dir = cache_dir("unmarked");
+        let live = live_batch(&dir);
+        image(&live, 0, "jpg");
+        image(&live, 1, "png");
+
+        let repair = repair(&dir).unwrap();
+        assert_eq!(repair.total, 2);
+        assert_eq!(repair.adopted, 2);
+        assert_eq!(read_marker_raw(&live), Some((0, 2)));
+    }
+
+    #[test]
+    fn repair_moves_a_flat_cache_into_a_batch() {
+        let dir = cache_dir("flat");
+        image(&dir, 0, "jpg");
+        image(&dir, 1, "png");
+        write_marker_at(&dir, 100, 2).unwrap();
+        fs::write(dir.join("2.jpg.part"), b"\xFF\xD8").unwrap();
+
+        let repair = repair(&dir).unwrap();
+        assert_eq!(repair, Repair { total: 2, removed: 1, ..Repair::default() });
+
+        let live = live_dir(&dir).unwrap();
+        assert_eq!(names(&live), vec![".refreshed", "0.jpg", "1.png"]);
+        assert_eq!(read_marker_raw(&live), Some((100, 2)));
+        assert_eq!(find_in(&live, 1), Some(image_path(&live, 1, "png")));
+    }
+
+    #[test]
+    fn repair_removes_abandoned_staging_only() {
+        let dir = cache_dir("staging");
+        let live = live_batch(&dir);
+        image(&live, 0, "jpg");
+        write_marker_at(&live, 100, 1).unwrap();
+        make_stale(&live);
+        let abandoned = create_staging(&dir).unwrap();
+        make_stale(&abandoned);
+        let running = create_staging(&dir).unwrap();
+        fs::write(dir.join("notes.txt"), b"hello").unwrap();
+
+        let repair = repair(&dir).unwrap();
+        assert_eq!(repair, Repair { total: 1, removed: 1, ..Repair::default() });
+        assert!(live.is_dir());
+        assert!(!abandoned.exists());
+        assert!(running.is_dir());
+        assert!(dir.join("notes.txt").is_file());
+    }
+
+    #[test]
+    fn repair_waits_for_a_refresh_in_progress() {
+        let dir = cache_dir("locked");
+        let live = live_batch(&dir);
+        image(&live, 0, "jpg");
+        fs::write(live.join("1.jpg.part"), b"\xFF\xD8").unwrap();
+        let _lock = CacheLock::acquire(&dir).unwrap().unwrap();
+
+        let repair = repair(&dir).unwrap();
+        assert_eq!(repair, Repair { total: 1, ..Repair::default() });
+        assert!(live.join("1.jpg.part").is_file());
+    }
+
+    #[test]
+    fn complete_swaps_whole_batches() {
+        let dir = cache_dir("swap");
+        let first = create_staging(&dir).unwrap();
+        image(&first, 0, "jpg");
+        image(&first, 1, "jpg");
+        complete(&first, &dir, 2).unwrap();
+        assert_eq!(live_dir(&dir), Some(first.clone()));
+        assert_eq!(count_images(&dir), 2);
+
+        // A reader still holding a path from the replaced batch can keep using it.
+        let shown = find_in(&first, 1).unwrap();
+        let second = create_staging(&dir).unwrap();
+        image(&second, 0, "png");
+        assert_eq!(count_images(&dir), 2);
+        complete(&second, &dir, 1).unwrap();
+        assert_eq!(live_dir(&dir), Some(second.clone()));
+        assert_eq!(count_images(&dir), 1);
+        let live = live_dir(&dir).unwrap();
+        assert_eq!(find_in(&live, 0), Some(image_path(&second, 0, "png")));
+        assert!(shown.is_file());
+
+        let third = create_staging(&dir).unwrap();
+        image(&third, 0, "jpg");
+        complete(&third, &dir, 1).unwrap();
+        assert!(!first.exists());
+        assert!(second.is_dir());
+        assert_eq!(names(&dir).iter().filter(|n| n.starts_with(".current")).count(), 1);
+    }
+
+    #[test]
+    fn evict_removes_the_live_batch() {
+        let dir = cache_dir("evict");
+        let batch = create_staging(&dir).unwrap();
+        image(&batch, 0, "jpg");
+        image(&batch, 1, "jpg");
+        complete(&batch, &dir, 2).unwrap();
+
+        assert_eq!(evict(&dir).unwrap(), 2);
+        assert_eq!(live_dir(&dir), None);
+        assert!(!batch.exists());
+        assert_eq!(read_marker(&dir), None);
+        assert_eq!(evict(&dir).unwrap(), 0);
+    }
+
+    #[test]
+    fn lock_excludes_other_instances() {
+        let dir = cache_dir("lock");
+        let lock = CacheLock::acquire(&dir).unwrap().unwrap();
+        assert!(CacheLock::acquire(&dir).unwrap().is_none());
+        lock.touch().unwrap();
+        drop(lock);
+        assert!(CacheLock::acquire(&dir).unwrap().is_some());
+    }
+
+    #[test]
+    fn stale_lock_is_taken_over_once() {
+        let dir = cache_dir("stale");
+        let old = CacheLock::acquire(&dir).unwrap().unwrap();
+        make_stale(&dir.join(LOCK_FILE));
+
+        let start = Arc::new(Barrier::new(8));
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let dir = dir.clone();
+                let start = start.clone();
+                thread::spawn(move || {
+                    start.wait();
+                    CacheLock::acquire(&dir).unwrap()
+                })
+            })
+            .collect();
+        let mut taken: Vec<CacheLock> = workers
+            .into_iter()
+            .filter_map(|w| w.join().unwrap())
+            .collect();
+        assert_eq!(taken.len(), 1);
+        assert!(!dir.join(LOCK_BREAK_FILE).exists());
+
+        // The holder that was presumed dead neither renews nor releases the new lock.
+        assert!(old.touch().is_err());
+        drop(old);
+        assert!(CacheLock::acquire(&dir).unwrap().is_none());
+        taken.pop().unwrap().touch().unwrap();
+    }
+
+    #[test]
+    fn instances_refreshing_one_cache_take_turns() {
+        let dir = cache_dir("hammer");
+        let holders = Arc::new(AtomicUsize::new(0));
+        let refreshes = Arc::new(AtomicUsize::new(0));
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let dir = dir.clone();
+                let holders = holders.clone();
+                let refreshes = refreshes.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let lock = match CacheLock::acquire(&dir).unwrap() {
+                            Some(lock) => lock,
+                            None => continue,
+                        };
+                        assert_eq!(holders.fetch_add(1, Ordering::SeqCst), 0);
+
+                        let staging = create_staging(&dir).unwrap();
+                        image(&staging, 0, "jpg");
+                        lock.touch().unwrap();
+                        complete(&staging, &dir, 1).unwrap();
+                        refreshes.fetch_add(1, Ordering::SeqCst);
+
+                        holders.fetch_sub(1, Ordering::SeqCst);
+                        drop(lock);
+                    }
+                })
+            })
+            .collect();
+
+        // Readers only ever see whole batches, whatever the writers are doing.
+        for _ in 0..200 {
+            if let Some((_, count)) = read_marker(&dir) {
+                assert_eq!(count, 1);
+            }
+        }
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert!(refreshes.load(Ordering::SeqCst) > 0);
+        assert_eq!(count_images(&dir), 1);
+        let batches = names(&dir).iter().filter(|n| n.starts_with(STAGING_PREFIX)).count();
+        assert!(batches <= 2);
+    }
+
+    #[test]
+    fn unwritable_errors() {
+        let unwritable = [
+            io::Error::new(io::ErrorKind::PermissionDenied, "denied"),
+            io::Error::from_raw_os_error(libc::EROFS),
+            io::Error::from_raw_os_error(libc::EACCES),
+            io::Error::from_raw_os_error(libc::EPERM),
+        ];
+        for err in &unwritable {
+            assert!(is_unwritable(err), "{}", err);
+        }
+
+        let other = [
+            io::Error::new(io::ErrorKind::NotFound, "missing"),
+            io::Error::from_raw_os_error(libc::ENOENT),
+            io::Error::from_raw_os_error(libc::ENOSPC),
+            io::Error::from_raw_os_error(libc::EIO),
+        ];
+        for err in &other {
+            assert!(!is_unwritable(err), "{}", err);
+        }
+        assert!(!is_unwritable(&::std::fmt::Error));
+    }
+
+    #[test]
+    fn unwritable_through_a_box() {
+        let err: Box<Error> = Box::new(io::Error::from_raw_os_error(libc::EROFS));
+        assert!(is_unwritable(&*err));
+        let err: Box<Error> = From::from("cache is read-only");
+        assert!(!is_unwritable(&*err));
+    }
+}
@@ -0,0 +1,660 @@
+//! Module for the rotation engine that decides which image to display next.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use errors::WallsplashError;
+use fetchers::{Fetch, LocalFetcher, UnsplashFetcher, Warming};
+use filters::{Blacklist, Filter, FilterCtx, FilterStats, MinResolution, ValidImage, Verdict};
+use Context;
+
+/// Names of the sources known to the engine.
+pub const SOURCES: &'static [&'static str] = &["local", "unsplash"];
+
+/// Most images to try in one rotation when the filters keep rejecting them.
+const CANDIDATE_ATTEMPTS: usize = 3;
+
+/// Number of recently shown images passed on to the filters.
+const RECENT_IMAGES: usize = 10;
+
+/// Strategy for choosing which source provides the next image.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Order {
+    /// Take turns between the sources.
+    Alternate,
+    /// Use the first source, by name, that yields an image.
+    Priority(Vec<String>),
+}
+
+/// Information about an image chosen by the engine.
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    /// File path of the image to display.
+    pub path: PathBuf,
+    /// Name of the source that provided the image.
+    pub source: String,
+}
+
+/// Engine that rotates between the image sources. Each call to `next_image` advances the
+/// rotation, refreshing source caches as needed, but never sets the wallpaper or sleeps.
+pub struct Engine {
+    /// Sources in rotation or priority order.
+    sources: Vec<Box<Fetch>>,
+    /// Whether to try sources by priority instead of alternating.
+    priority: bool,
+    /// Index of the source to use next when alternating.
+    next: usize,
+    /// Filters every chosen image must pass, in the order they are applied.
+    filters: Vec<Box<Filter>>,
+    /// Verdict counts of each filter, in the same order as the filters.
+    stats: Vec<FilterStats>,
+    /// Images returned most recently, the latest last.
+    recent: Vec<PathBuf>,
+}
+
+/// Outcome of running one candidate through the filter chain.
+enum Outcome {
+    /// Every filter let the image through, with the product of their preference weights.
+    Passed(f64),
+    /// A filter rejected the image.
+    Rejected { filter: String, reason: String },
+}
+
+impl Engine {
+    /// Build the engine over the local directories and Unsplash. Unsplash finishes starting up
+    /// in the background, and the local images fill in until it is ready.
+    pub fn new(ctx: &Context) -> Result<Engine, Box<Error>> {
+        let mut unsplash = UnsplashFetcher::new(ctx)?;
+        let unsplash = Warming::start("unsplash", move || -> Box<Fetch + Send> {
+            unsplash.start_up();
+            Box::new(unsplash)
+        });
+        let local = LocalFetcher::new(
+            &ctx.dirs,
+            ctx.max_files,
+            &ctx.extensions,
+            ctx.hidden,
+            ctx.recursive,
+            ctx.rescan,
+            ctx.local_order,
+        );
+
+        let mut engine =
+            Engine::with_sources(vec![Box::new(local), Box::new(unsplash)], &ctx.order)?;
+        if !ctx.blacklist.is_empty() {
+            engine.add_filter(Box::new(Blacklist::new(ctx.blacklist.clone())));
+        }
+        if let Some(min) = ctx.min_resolution {
+            engine.add_filter(Box::new(MinResolution::new(min)));
+        }
+        Ok(engine)
+    }
+
+    /// Build an engine over the given sources instead of the local directory and Unsplash. When
+    /// ordering by priority, every named source must be among them, and sources not named are
+    /// left out. Fails when no source is left to rotate between. Chosen images must pass the
+    /// `ValidImage` filter.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use std::fs;
+    /// use std::path::PathBuf;
+    ///
+    /// use wallsplash::{Engine, Fetch, Order};
+    ///
+    /// /// Source that always offers the same image.
+    /// struct Single(PathBuf);
+    ///
+    /// impl Fetch for Single {
+    ///     fn name(&self) -> &str {
+    ///         "single"
+    ///     }
+    ///
+    ///     fn next_image_path(&mut self) -> Result<PathBuf, Box<Error>> {
+    ///         Ok(self.0.clone())
+    ///     }
+    /// }
+    ///
+    /// let name = format!("wallsplash-doc-engine-{}.png", std::process::id());
+    /// let path = std::env::temp_dir().join(name);
+    /// // Just the header of a 1x1 PNG, which is all the `ValidImage` filter reads.
+    /// fs::write(&path, b"\x89PNG\r\n\x1A\n\0\0\0\x0DIHDR\0\0\0\x01\0\0\0\x01").unwrap();
+    ///
+    /// let sources: Vec<Box<Fetch>> = vec![Box::new(Single(path.clone()))];
+    /// let mut engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+    /// for _ in 0..3 {
+    ///     let info = engine.next_image().unwrap();
+    ///     assert_eq!(info.path, path);
+    ///     assert_eq!(info.source, "single");
+    /// }
+    /// ```
+    pub fn with_sources(mut sources: Vec<Box<Fetch>>, order: &Order) -> Result<Engine, Box<Error>> {
+        let priority = match *order {
+            Order::Alternate => false,
+            Order::Priority(ref names) => {
+                let mut ordered = Vec::new();
+                for name in names {
+                    match sources.iter().position(|s| s.name() == name) {
+                        Some(pos) => ordered.push(sources.remove(pos)),
+                        None => return Err(Box::new(WallsplashError::UnknownSource)),
+                    }
+                }
+                sources = ordered;
+                true
+            }
+        };
+        if sources.is_empty() {
+            return Err(Box::new(WallsplashError::NoSource));
+        }
+
+        let mut engine = Engine {
+            sources: sources,
+            priority: priority,
+            next: 0,
+            filters: Vec::new(),
+            stats: Vec::new(),
+            recent: Vec::new(),
+        };
+        engine.add_filter(Box::new(ValidImage));
+        Ok(engine)
+    }
+
+    /// Add a filter to the end of the chain applied to every chosen image.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use wallsplash::{Filter, FilterCtx, ImageInfo, Verdict};
+    ///
+    /// /// Filter that only lets PNG images through.
+    /// struct PngOnly;
+    ///
+    /// impl Filter for PngOnly {
+    ///     fn name(&self) -> &str {
+    ///         "png-only"
+    ///     }
+    ///
+    ///     fn accept(&self, info: &ImageInfo, _: &FilterCtx) -> Verdict {
+    ///         match info.path.extension() {
+    ///             Some(ext) if ext == "png" => Verdict::Accept,
+    ///             _ => Verdict::Reject {
+    ///                 reason: "not a PNG".to_owned(),
+    ///             },
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// # fn register(engine: &mut wallsplash::Engine) {
+    /// engine.add_filter(Box::new(PngOnly));
+    /// # }
+    /// ```
+    pub fn add_filter(&mut self, filter: Box<Filter>) {
+        self.stats.push(FilterStats {
+            name: filter.name().to_owned(),
+            ..Default::default()
+        });
+        self.filters.push(filter);
+    }
+
+    /// Whether every filter lets the image through, without counting the verdicts.
+    pub fn accepts(&self, info: &ImageInfo) -> bool {
+        let ctx = FilterCtx {
+            recent: &self.recent,
+            attempt: 0,
+        };
+        self.filters.iter().all(|f| match f.accept(info, &ctx) {
+            Verdict::Reject { reason } => {
+                debug!("{} rejected {}: {}", f.name(), info.path.display(), reason);
+                false
+            }
+            _ => true,
+        })
+    }
+
+    /// Take note of an image put on screen without the engine, such as the wallpaper set while
+    /// starting up, so that the sources carry on after it.
+    pub fn shown(&mut self, path: &Path) {
+        if self.recent.len() == RECENT_IMAGES {
+            self.recent.remove(0);
+        }
+        self.recent.push(path.to_path_buf());
+        for source in &mut self.sources {
+            source.shown(path);
+        }
+    }
+
+    /// Verdict counts of each filter since the engine was built, in chain order.
+    pub fn filter_stats(&self) -> &[FilterStats] {
+        &self.stats
+    }
+
+    /// Advance the rotation and return the next image to display that passes every filter. A
+    /// rejected image is replaced by the next one in the rotation, a few times at most. An image
+    /// the filters prefer less is only returned when none of the other tries is preferred more.
+    pub fn next_image(&mut self) -> Result<ImageInfo, Box<Error>> {
+        let mut best: Option<(ImageInfo, f64)> = None;
+        let mut rejections: BTreeMap<String, usize> = BTreeMap::new();
+        for attempt in 0..CANDIDATE_ATTEMPTS {
+            let info = match self.next_candidate() {
+                Ok(info) => info,
+                Err(_) if best.is_some() => break,
+                Err(e) => return Err(e),
+            };
+            match self.check(&info, attempt) {
+                Outcome::Passed(weight) if weight >= 1.0 => {
+                    best = Some((info, weight));
+                    break;
+                }
+                Outcome::Passed(weight) => {
+                    debug!(
+                        "filters weigh {} image {} at {}",
+                        info.source,
+                        info.path.display(),
+                        weight
+                    );
+                    if best.as_ref().is_none_or(|&(_, w)| weight > w) {
+                        best = Some((info, weight));
+                    }
+                }
+                Outcome::Rejected { filter, reason } => {
+                    debug!(
+                        "{} rejected {} image {}: {}",
+                        filter,
+                        info.source,
+                        info.path.display(),
+                        reason
+                    );
+                    *rejections.entry(filter).or_insert(0) += 1;
+                }
+            }
+        }
+
+        if !rejections.is_empty() {
+            let summary: Vec<String> = rejections
+                .iter()
+                .map(|(filter, count)| format!("{} {}", filter, count))
+                .collect();
+            debug!("filters rejected this rotation: {}", summary.join(", "));
+        }
+        match best {
+            Some((info, _)) => {
+                for (filter, count) in rejections {
+                    if let Some(stats) = self.stats.iter_mut().find(|s| s.name == filter) {
+                        stats.recovered += count as u64;
+                    }
+                }
+                if self.recent.len() == RECENT_IMAGES {
+                    self.recent.remove(0);
+                }
+                self.recent.push(info.path.clone());
+                Ok(info)
+            }
+            None => Err(Box::new(WallsplashError::NoAcceptedImage)),
+        }
+    }
+
+    /// Check once more that an image from `next_image` is still valid, right before it is set,
+    /// since it may have been deleted or evicted in the meantime. A gone image is replaced by the
+    /// next one, a few times at most, and each replacement counts as a recovery by the
+    /// `ValidImage` filter.
+    pub fn recheck(&mut self, info: ImageInfo) -> Result<ImageInfo, Box<Error>> {
+        let mut info = info;
+        for _ in 0..CANDIDATE_ATTEMPTS {
+            let ctx = FilterCtx {
+                recent: &[],
+                attempt: 0,
+            };
+            let reason = match ValidImage.accept(&info, &ctx) {
+                Verdict::Reject { reason } => reason,
+                _ => return Ok(info),
+            };
+
+            warn!(
+                "{} image {} went away before it was set ({}), choosing another",
+                info.source,
+                info.path.display(),
+                reason
+            );
+            // The valid-image filter is always the first in the chain.
+            self.stats[0].rejected += 1;
+            self.recent.pop();
+            info = self.next_image()?;
+            self.stats[0].recovered += 1;
+        }
+        Err(Box::new(WallsplashError::NoAcceptedImage))
+    }
+
+    /// Run the image through the filters in order, stopping at the first that rejects it, and
+    /// count each verdict.
+    fn check(&mut self, info: &ImageInfo, attempt: usize) -> Outcome {
+        let ctx = FilterCtx {
+            recent: &self.recent,
+            attempt: attempt,
+        };
+        let mut weight = 1.0;
+        for (filter, stats) in self.filters.iter().zip(self.stats.iter_mut()) {
+            match filter.accept(info, &ctx) {
+                Verdict::Accept => stats.accepted += 1,
+                Verdict::Prefer { weight: w } => {
+                    stats.preferred += 1;
+                    weight *= w;
+                }
+                Verdict::Reject { reason } => {
+                    stats.rejected += 1;
+                    return Outcome::Rejected {
+                        filter: filter.name().to_owned(),
+                        reason: reason,
+                    };
+                }
+            }
+        }
+        Outcome::Passed(weight)
+    }
+
+    /// Advance the rotation and return the next image chosen by the sources. When alternating,
+    /// the rotation moves on to the following source even when the current one fails.
+    fn next_candidate(&mut self) -> Result<ImageInfo, Box<Error>> {
+        if self.priority {
+            return self.next_priority_image();
+        }
+
+        let idx = self.next;
+        self.next = (self.next + 1) % self.sources.len();
+
+        let source = &mut self.sources[idx];
+        let path = source.next_image_path()?;
+        Ok(ImageInfo {
+            path: path,
+            source: source.name().to_owned(),
+        })
+    }
+
+    /// Return the image from the first source in priority order that has one. Fails with the
+    /// last source's error when none of them do.
+    fn next_priority_image(&mut self) -> Result<ImageInfo, Box<Error>> {
+        let mut last_err = None;
+        for source in &mut self.sources {
+            match source.next_image_path() {
+                Ok(path) => {
+                    return Ok(ImageInfo {
+                        path: path,
+                        source: source.name().to_owned(),
+                    })
+                }
+                Err(e) => {
+                    debug!("{} has no image: {}", source.name(), e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Box::new(WallsplashError::UnknownSource)))
+    }
+
+    /// Borrow the engine as an iterator of images. The iterator shares the rotation state with
+    /// the engine, so it can be dropped and resumed at any time.
+    pub fn images(&mut self) -> Images<'_> {
+        Images { engine: self }
+    }
+}
+
+impl Iterator for Engine {
+    type Item = Result<ImageInfo, Box<Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_image())
+    }
+}
+
+/// Borrowing iterator over the images chosen by an engine. Never ends.
+pub struct Images<'a> {
+    engine: &'a mut Engine,
+}
+
+impl<'a> Iterator for Images<'a> {
+    type Item = Result<ImageInfo, Box<Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.engine.next_image())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::rc::Rc;
+
+    use super::*;
+    use testing::{self, MockFetcher};
+
+    /// Filter that records when it is consulted and decides by the image's file name.
+    struct Probe {
+        name: &'static str,
+        calls: Rc<RefCell<Vec<&'static str>>>,
+        decide: fn(&str) -> Verdict,
+    }
+
+    impl Filter for Probe {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn accept(&self, info: &ImageInfo, _: &FilterCtx) -> Verdict {
+            self.calls.borrow_mut().push(self.name);
+            let stem = info.path.file_stem().unwrap().to_string_lossy().into_owned();
+            (self.decide)(&stem)
+        }
+    }
+
+    fn probe(
+        name: &'static str,
+        calls: &Rc<RefCell<Vec<&'static str>>>,
+        decide: fn(&str) -> Verdict,
+    ) -> Box<Filter> {
+        Box::new(Probe {
+            name: name,
+            calls: calls.clone(),
+            decide: decide,
+        })
+    }
+
+    fn reject_if(stem: &str, word: &str) -> Verdict {
+        if stem.contains(word) {
+            Verdict::Reject {
+                reason: format!("named {}", word),
+            }
+        } else {
+            Verdict::Accept
+        }
+    }
+
+    fn stems(engine: &mut Engine, count: usize) -> Vec<String> {
+        engine
+            .images()
+            .take(count)
+            .map(|i| {
+                let stem = i.unwrap().path.file_stem().unwrap().to_string_lossy().into_owned();
+                let prefix = format!("wallsplash-engine-{}-", process::id());
+                stem.trim_start_matches(&prefix).to_owned()
+            })
+            .collect()
+    }
+
+    /// Write a small JPEG the `ValidImage` filter accepts and return its path.
+    fn image(name: &str) -> PathBuf {
+        let name = format!("wallsplash-engine-{}-{}.jpg", process::id(), name);
+        let path = env::temp_dir().join(name);
+        testing::write_jpeg(&path, 640, 480).unwrap();
+        path
+    }
+
+    fn source(name: &str, images: &[&str]) -> Box<Fetch> {
+        Box::new(MockFetcher::new(name, images.iter().map(|i| image(i)).collect()))
+    }
+
+    fn names(engine: &mut Engine, count: usize) -> Vec<String> {
+        engine.images().take(count).map(|i| i.unwrap().source).collect()
+    }
+
+    #[test]
+    fn no_sources_is_an_error() {
+        assert!(Engine::with_sources(vec![], &Order::Alternate).is_err());
+        assert!(Engine::with_sources(vec![], &Order::Priority(vec![])).is_err());
+
+        let sources = vec![source("a", &["a1"])];
+        assert!(Engine::with_sources(sources, &Order::Priority(vec![])).is_err());
+    }
+
+    #[test]
+    fn alternate_takes_turns() {
+        let sources = vec![source("a", &["a1", "a2"]), source("b", &["b1"])];
+        let mut engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+        assert_eq!(names(&mut engine, 5), vec!["a", "b", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn alternate_moves_on_after_a_failing_source() {
+        let sources = vec![source("empty", &[]), source("b", &["b1"])];
+        let mut engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+        assert!(engine.next_image().is_err());
+        assert_eq!(engine.next_image().unwrap().source, "b");
+        assert!(engine.next_image().is_err());
+    }
+
+    #[test]
+    fn priority_prefers_the_first_named_source() {
+        let sources = vec![source("a", &["a1"]), source("b", &["b1"])];
+        let order = Order::Priority(vec!["b".to_owned(), "a".to_owned()]);
+        let mut engine = Engine::with_sources(sources, &order).unwrap();
+        assert_eq!(names(&mut engine, 3), vec!["b", "b", "b"]);
+    }
+
+    #[test]
+    fn priority_falls_back_when_a_source_is_empty() {
+        let sources = vec![source("a", &["a1"]), source("empty", &[])];
+        let order = Order::Priority(vec!["empty".to_owned(), "a".to_owned()]);
+        let mut engine = Engine::with_sources(sources, &order).unwrap();
+        assert_eq!(names(&mut engine, 2), vec!["a", "a"]);
+    }
+
+    #[test]
+    fn filters_run_in_order_and_stop_at_the_first_rejection() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let sources = vec![source("a", &["chain-ok", "chain-bad"])];
+        let mut engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+        engine.add_filter(probe("first", &calls, |s| reject_if(s, "bad")));
+        engine.add_filter(probe("second", &calls, |_| Verdict::Accept));
+
+        assert_eq!(stems(&mut engine, 2), vec!["chain-ok", "chain-ok"]);
+        // ok, then bad stops at the first filter, then ok again.
+        assert_eq!(*calls.borrow(), vec!["first", "second", "first", "first", "second"]);
+    }
+
+    #[test]
+    fn rejections_are_counted_per_filter() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let images = &["count-ok", "count-red", "count-blue"];
+        let sources = vec![source("a", images)];
+        let mut engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+        engine.add_filter(probe("red", &calls, |s| reject_if(s, "red")));
+        engine.add_filter(probe("blue", &calls, |s| reject_if(s, "blue")));
+
+        assert_eq!(stems(&mut engine, 2), vec!["count-ok", "count-ok"]);
+        let counts: Vec<(String, u64, u64)> = engine
+            .filter_stats()
+            .iter()
+            .map(|s| (s.name.clone(), s.accepted, s.rejected))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("valid-image".to_owned(), 4, 0),
+                ("red".to_owned(), 3, 1),
+                ("blue".to_owned(), 2, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn retries_are_bounded_when_every_image_is_rejected() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let sources = vec![source("a", &["bounded-1", "bounded-2"])];
+        let mut engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+        engine.add_filter(probe("none", &calls, |_| Verdict::Reject {
+            reason: "no".to_owned(),
+        }));
+
+        assert!(engine.next_image().is_err());
+        assert_eq!(calls.borrow().len(), CANDIDATE_ATTEMPTS);
+        assert_eq!(engine.filter_stats()[1].rejected, CANDIDATE_ATTEMPTS as u64);
+    }
+
+    #[test]
+    fn preferred_images_wait_for_a_better_one() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let images = &["prefer-low", "prefer-mid", "prefer-top"];
+        let sources = vec![source("a", images)];
+        let mut engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+        engine.add_filter(probe("weigh", &calls, |s| {
+            if s.ends_with("low") {
+                Verdict::Prefer { weight: 0.2 }
+            } else if s.ends_with("mid") {
+                Verdict::Prefer { weight: 0.5 }
+            } else {
+                Verdict::Accept
+            }
+        }));
+
+        // The first rotation reaches an image that is fully accepted within its attempts.
+        assert_eq!(stems(&mut engine, 1), vec!["prefer-top"]);
+        // With only weighted images left to try, the best of them wins.
+        engine.add_filter(probe("no-top", &calls, |s| reject_if(s, "top")));
+        assert_eq!(stems(&mut engine, 1), vec!["prefer-mid"]);
+        assert_eq!(engine.filter_stats()[1].preferred, 4);
+    }
+
+    #[test]
+    fn gone_images_are_replaced_and_counted_as_recovered() {
+        let gone = image("recover-gone");
+        fs::remove_file(&gone).unwrap();
+        let sources: Vec<Box<Fetch>> = vec![Box::new(MockFetcher::new(
+            "a",
+            vec![gone, image("recover-kept")],
+        ))];
+        let mut engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+
+        assert_eq!(stems(&mut engine, 1), vec!["recover-kept"]);
+        let stats = &engine.filter_stats()[0];
+        assert_eq!((stats.rejected, stats.recovered), (1, 1));
+    }
+
+    #[test]
+    fn recheck_replaces_an_image_deleted_before_it_is_set() {
+        let sources = vec![source("a", &["recheck-1", "recheck-2"])];
+        let mut engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+
+        let first = engine.next_image().unwrap();
+        let kept = engine.recheck(first.clone()).unwrap();
+        assert_eq!(kept.path, first.path);
+
+        let second = engine.next_image().unwrap();
+        fs::remove_file(&second.path).unwrap();
+        let replaced = engine.recheck(second).unwrap();
+        assert_eq!(replaced.path, first.path);
+        let stats = &engine.filter_stats()[0];
+        assert_eq!((stats.accepted, stats.rejected, stats.recovered), (3, 1, 1));
+    }
+
+    #[test]
+    fn priority_rejects_an_unknown_source() {
+        let sources = vec![source("a", &["a1"])];
+        let order = Order::Priority(vec!["missing".to_owned()]);
+        assert!(Engine::with_sources(sources, &order).is_err());
+    }
+}
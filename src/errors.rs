@@ -2,26 +2,101 @@
 
 use std::error::Error;
 use std::fmt;
+use std::io;
+
+/// Broad cause of a failure, for deciding whether retrying could help.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorClass {
+    /// The configuration asks for something impossible; retrying will not help.
+    Config,
+    /// The system is missing something, such as a writable cache directory or a setter program.
+    Environment,
+    /// Something went wrong while running, which may not happen again.
+    Runtime,
+}
+
+/// Classify an error returned by `run`. I/O errors only escape from setting up the cache or
+/// dropping privileges, so they count as environment errors.
+pub fn classify(err: &(Error + 'static)) -> ErrorClass {
+    if err.is::<io::Error>() {
+        return ErrorClass::Environment;
+    }
+    match err.downcast_ref::<WallsplashError>() {
+        Some(&WallsplashError::InvalidLimit)
+        | Some(&WallsplashError::NoLocalDir)
+        | Some(&WallsplashError::NoSource)
+        | Some(&WallsplashError::UnsplashCollection(_))
+        | Some(&WallsplashError::UnknownGroup)
+        | Some(&WallsplashError::UnknownSource)
+        | Some(&WallsplashError::UnknownUser) => ErrorClass::Config,
+        Some(&WallsplashError::PrivilegeDropFailed) => ErrorClass::Environment,
+        _ => ErrorClass::Runtime,
+    }
+}
 
 #[derive(Debug)]
 pub enum WallsplashError {
+    HoldProbeTimeout,
+    InvalidLimit,
     LocalNoImage,
+    LockStateUnknown,
+    NoAcceptedImage,
+    NoDisplay,
+    NoLocalDir,
+    NoSource,
+    PrivilegeDropFailed,
+    SetterCommandFailed,
+    SourceStarting,
+    UnknownGroup,
+    UnknownSource,
+    UnknownUser,
     UnsplashAPIFail,
+    UnsplashCollection(u64),
     UnsplashNoImage,
+    UnsplashUnavailable,
 }
 
 impl fmt::Display for WallsplashError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(self.description())
+        match *self {
+            WallsplashError::UnsplashCollection(id) => {
+                write!(f, "{} {}", self.message(), id)
+            }
+            _ => f.write_str(self.message()),
+        }
     }
 }
 
-impl Error for WallsplashError {
-    fn description(&self) -> &str {
+impl WallsplashError {
+    /// Message describing the error, without any details it carries.
+    fn message(&self) -> &'static str {
         match *self {
+            WallsplashError::HoldProbeTimeout => "The hold_while command timed out",
+            WallsplashError::InvalidLimit => "The Unsplash limit must be between 1 and 30",
             WallsplashError::LocalNoImage => "No local images found",
+            WallsplashError::LockStateUnknown => "Could not determine session lock state",
+            WallsplashError::NoAcceptedImage => "No image passed the filters",
+            WallsplashError::NoDisplay => "No display to set the wallpaper on",
+            WallsplashError::NoLocalDir => "No local image directory given",
+            WallsplashError::NoSource => "No image source to rotate between",
+            WallsplashError::PrivilegeDropFailed => "Failed to permanently drop root privileges",
+            WallsplashError::SetterCommandFailed => "Wallpaper setter command failed",
+            WallsplashError::SourceStarting => "The image source is still starting up",
+            WallsplashError::UnknownGroup => "Unknown group in run_as",
+            WallsplashError::UnknownSource => "Unknown image source",
+            WallsplashError::UnknownUser => "Unknown user in run_as",
             WallsplashError::UnsplashAPIFail => "Unsplash /photos api failed",
+            WallsplashError::UnsplashCollection(_) => {
+                "Unknown or private Unsplash collection"
+            }
             WallsplashError::UnsplashNoImage => "No images found from Unsplash",
+            WallsplashError::UnsplashUnavailable => "Unsplash is temporarily unavailable",
         }
     }
 }
+
+impl Error for WallsplashError {
+    fn description(&self) -> &str {
+        self.message()
+    }
+}
@@ -1,59 +1,212 @@
 //! Module for image fetchers.
 
+#![allow(non_local_definitions)]
+
+use std::collections::{HashSet, VecDeque};
 use std::env;
 use std::error::Error;
 use std::fs;
-use std::io;
+use std::io::{self, Read, Write};
+use std::fmt;
 use std::ops::Deref;
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::{self, FromStr};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use reqwest;
-use reqwest::header::{Authorization, ContentType};
+use reqwest::header::{Authorization, ContentType, Headers, HttpDate};
 use reqwest::mime::{Mime, SubLevel, TopLevel};
+use reqwest::{StatusCode, Url};
+use serde_json::{self, Value};
 
+use cache::{self, CacheLock};
 use errors::WallsplashError;
+use hold;
+use rotation::{Rotation, ShuffleMode};
+use setters;
+use usage::{self, Usage};
+use Context;
 
+/// Source of images for the engine to rotate through. See `Engine::with_sources` for an example.
 pub trait Fetch {
+    /// Returns the name of this image source.
+    fn name(&self) -> &str;
+
     /// Returns the file path for the next image to display.
     fn next_image_path(&mut self) -> Result<PathBuf, Box<Error>>;
+
+    /// Takes note of an image put on screen before this source was asked for one, such as the
+    /// wallpaper set while starting up, so that the rotation can carry on after it.
+    fn shown(&mut self, _path: &Path) {}
 }
 
+/// File extensions, in lowercase, that local images are recognized by.
+pub const IMAGE_EXTENSIONS: &'static [&'static str] = &["jpg", "jpeg", "png", "webp", "bmp", "gif"];
+
 /// Fetcher for local images.
 #[derive(Debug)]
 pub struct LocalFetcher {
-    /// Local directory to search for images.
-    dir: String,
-    /// Index of next image to use.
-    next: usize,
+    /// Local directories to search for images, merged into one rotation.
+    dirs: Vec<PathBuf>,
+    /// Most files to consider from all directories together.
+    max_files: usize,
+    /// File extensions, in lowercase, of the files to consider.
+    extensions: Vec<String>,
+    /// Whether to consider hidden files.
+    hidden: bool,
+    /// Whether to also search the subdirectories.
+    recursive: bool,
+    /// Longest time to reuse a listing while the directories appear unchanged.
+    rescan: Duration,
+    /// Images found by the last scan, if any.
+    listing: Option<Listing>,
+    /// Whether the last scan stopped at `max_files`.
+    truncated: bool,
+    /// Directories that could not be listed in the last scan.
+    unreadable: Vec<PathBuf>,
+    /// Image shown last, for keeping the rotation's place when the listing changes.
+    last: Option<PathBuf>,
+    /// Position in the rotation of images.
+    rotation: Rotation,
+}
+
+/// Images found by a scan of the local directories.
+#[derive(Debug)]
+struct Listing {
+    /// Images found, in rotation order.
+    images: Vec<PathBuf>,
+    /// Modification time of each directory when it was scanned. Only the top level is watched,
+    /// so changes deeper down are picked up by the periodic rescan.
+    modified: Vec<Option<SystemTime>>,
+    /// Time of the scan.
+    scanned: Instant,
 }
 
 impl LocalFetcher {
-    pub fn new(dir: &str) -> Self {
+    /// Creates a fetcher for the images in `dirs`, recognized by the built-in extensions and any
+    /// `extensions` given on top of those.
+    pub fn new(
+        dirs: &[PathBuf],
+        max_files: usize,
+        extensions: &[String],
+        hidden: bool,
+        recursive: bool,
+        rescan: Duration,
+        order: ShuffleMode,
+    ) -> Self {
         LocalFetcher {
-            dir: dir.to_owned(),
-            next: 0,
+            dirs: dirs.to_vec(),
+            max_files: max_files,
+            extensions: image_extensions(extensions),
+            hidden: hidden,
+            recursive: recursive,
+            rescan: rescan,
+            listing: None,
+            truncated: false,
+            unreadable: Vec::new(),
+            last: None,
+            rotation: Rotation::new(order),
         }
     }
-}
 
-impl Fetch for LocalFetcher {
-    fn next_image_path(&mut self) -> Result<PathBuf, Box<Error>> {
+    /// Modification time of each directory, where it can be read.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.dirs
+            .iter()
+            .map(|d| d.metadata().and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Whether the last listing can still be used.
+    fn is_fresh(&self) -> bool {
+        match self.listing {
+            Some(ref l) => l.scanned.elapsed() < self.rescan && l.modified == self.modified(),
+            None => false,
+        }
+    }
+
+    /// Scan the directories again, keeping the rotation's place after the image shown last
+    /// when it is still there.
+    fn rescan(&mut self) {
+        let modified = self.modified();
+        let (images, truncated, failed) = self.scan();
+        if truncated && !self.truncated {
+            warn!(
+                "local directories have more than {} images, only using the first {}",
+                self.max_files, self.max_files
+            );
+        }
+        self.truncated = truncated;
+
+        for (dir, err) in &failed {
+            if !self.unreadable.contains(dir) {
+                warn!("skipping local directory {}: {}", dir.display(), err);
+            }
+        }
+        self.unreadable = failed.into_iter().map(|(dir, _)| dir).collect();
+
+        let changed = self.listing.as_ref().is_none_or(|l| l.images != images);
+        if changed {
+            let pos = self.last
+                .as_ref()
+                .and_then(|last| images.iter().position(|p| p == last));
+            if let Some(pos) = pos {
+                self.rotation.restart_at(pos + 1);
+            }
+        }
+
+        debug!("scanned local directories, found {} images", images.len());
+        self.listing = Some(Listing {
+            images: images,
+            modified: modified,
+            scanned: Instant::now(),
+        });
+    }
+
+    /// Lists the images of every directory, up to `max_files` in total. Returns the images,
+    /// whether there were more, and the directories that could not be listed.
+    fn scan(&self) -> (Vec<PathBuf>, bool, Vec<(PathBuf, io::Error)>) {
         let mut images = Vec::new();
+        let mut failed = Vec::new();
 
-        for entry in fs::read_dir(&self.dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                images.push(path);
+        for dir in &self.dirs {
+            let max = self.max_files - images.len();
+            let accept = |p: &Path, is_dir| is_candidate(p, is_dir, &self.extensions, self.hidden);
+            match scan_files(dir, max, self.recursive, &accept) {
+                Ok((files, truncated)) => {
+                    images.extend(files);
+                    if truncated {
+                        return (images, true, failed);
+                    }
+                }
+                Err(e) => failed.push((dir.clone(), e)),
             }
         }
+        (images, false, failed)
+    }
+}
+
+impl Fetch for LocalFetcher {
+    fn name(&self) -> &str {
+        "local"
+    }
 
-        if images.len() > 0 {
-            self.next = self.next % images.len();
+    fn next_image_path(&mut self) -> Result<PathBuf, Box<Error>> {
+        if !self.is_fresh() {
+            self.rescan();
+        }
 
-            let path = images[self.next].clone();
-            self.next += 1;
+        let images = match self.listing {
+            Some(ref l) => &l.images,
+            None => return Err(Box::new(WallsplashError::LocalNoImage)),
+        };
+        if let Some(idx) = self.rotation.next(images.len()) {
+            let path = images[idx].clone();
+            self.last = Some(path.clone());
 
             debug!("local: {:?}", path);
             return Ok(path);
@@ -61,15 +214,173 @@ impl Fetch for LocalFetcher {
 
         Err(Box::new(WallsplashError::LocalNoImage))
     }
+
+    fn shown(&mut self, path: &Path) {
+        // The first scan picks up after it.
+        if self.listing.is_none() {
+            self.last = Some(path.to_path_buf());
+        }
+    }
+}
+
+/// Lists the images in a local image directory the same way rotation finds them, by the
+/// built-in extensions and any `extensions` given on top of those.
+pub fn list_images(
+    dir: &Path,
+    extensions: &[String],
+    hidden: bool,
+    recursive: bool,
+) -> io::Result<Vec<PathBuf>> {
+    let extensions = image_extensions(extensions);
+    let accept = |p: &Path, is_dir| is_candidate(p, is_dir, &extensions, hidden);
+    scan_files(dir, usize::MAX, recursive, &accept).map(|(files, _)| files)
+}
+
+/// The built-in image extensions followed by the extra ones, all in lowercase.
+fn image_extensions(extra: &[String]) -> Vec<String> {
+    IMAGE_EXTENSIONS
+        .iter()
+        .map(|e| e.to_string())
+        .chain(extra.iter().map(|e| e.trim_start_matches('.').to_lowercase()))
+        .collect()
+}
+
+/// Whether a file should be considered an image, or a subdirectory should be searched. Names
+/// need not be valid UTF-8.
+fn is_candidate(path: &Path, is_dir: bool, extensions: &[String], hidden: bool) -> bool {
+    let name = match path.file_name() {
+        Some(n) => n,
+        None => return false,
+    };
+    if !hidden && name.as_bytes().starts_with(b".") {
+        return false;
+    }
+    if is_dir {
+        return true;
+    }
+
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| extensions.contains(&e))
+}
+
+/// Lists up to `max` accepted files in a local image directory, and whether there were more.
+/// With `recursive`, accepted subdirectories are searched too, after the files next to them.
+/// `accept` is given each path and whether it is a directory.
+fn scan_files(
+    dir: &Path,
+    max: usize,
+    recursive: bool,
+    accept: &Fn(&Path, bool) -> bool,
+) -> io::Result<(Vec<PathBuf>, bool)> {
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    let truncated = scan_dir(dir, max, recursive, accept, &mut visited, &mut files)?;
+    Ok((files, truncated))
+}
+
+/// Adds the accepted files of one directory, in name order so that the rotation stays put
+/// between scans, and then those of its subdirectories. Directories reached again through a
+/// symlink are skipped, which keeps symlink loops from being followed forever. Only symlinks
+/// need an extra lookup to tell what they point at. Returns whether `max` files were exceeded.
+fn scan_dir(
+    dir: &Path,
+    max: usize,
+    recursive: bool,
+    accept: &Fn(&Path, bool) -> bool,
+    visited: &mut HashSet<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) -> io::Result<bool> {
+    if recursive && !visited.insert(dir.canonicalize()?) {
+        debug!("skipping {}, already searched", dir.display());
+        return Ok(false);
+    }
+
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        entries.push((entry.path(), entry.file_type()?));
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut subdirs = Vec::new();
+    for (path, kind) in entries {
+        let is_symlink = kind.is_symlink();
+        if recursive && (kind.is_dir() || (is_symlink && path.is_dir())) {
+            if accept(&path, true) {
+                subdirs.push(path);
+            }
+        } else if (kind.is_file() || (is_symlink && path.is_file())) && accept(&path, false) {
+            if files.len() == max {
+                return Ok(true);
+            }
+            files.push(path);
+        }
+    }
+
+    for subdir in subdirs {
+        match scan_dir(&subdir, max, recursive, accept, visited, files) {
+            Ok(true) => return Ok(true),
+            Ok(false) => {}
+            Err(e) => warn!("skipping local directory {}: {}", subdir.display(), e),
+        }
+    }
+    Ok(false)
 }
 
 const UNSPLASH_API: &'static str = "https://api.unsplash.com";
 const PHOTOS_ENDPOINT: &'static str = "/photos";
+const SEARCH_ENDPOINT: &'static str = "/search/photos";
+const COLLECTIONS_ENDPOINT: &'static str = "/collections";
+const RANDOM_ENDPOINT: &'static str = "/photos/random";
+
+/// Number of recent refreshes considered when adapting the refresh interval.
+const REFRESH_HISTORY: usize = 5;
+
+/// Prefix of the name of the file keeping the Unsplash rotation across restarts.
+const ROTATION_FILE: &'static str = "rotation-unsplash";
+
+/// Seconds between checks whether the cache can be written again.
+const READ_ONLY_PROBE_SECS: u64 = 5 * 60;
+
+/// Seconds to hold off after Unsplash reports it is unavailable without saying for how long.
+const UNAVAILABLE_BACKOFF_SECS: u64 = 10 * 60;
 
 #[derive(Deserialize, Debug)]
 struct Photo {
     id: String,
     links: Links,
+    urls: Option<Urls>,
+}
+
+impl Photo {
+    /// URL to download the photo from. With a resolution, Unsplash resizes the photo to fit
+    /// within it, otherwise the full-resolution original is downloaded.
+    fn download_url(&self, size: Option<Resolution>) -> String {
+        let (size, raw) = match (size, self.urls.as_ref()) {
+            (Some(size), Some(urls)) => (size, &urls.raw),
+            _ => return self.links.download.clone(),
+        };
+
+        match Url::parse(raw) {
+            Ok(mut url) => {
+                url.query_pairs_mut()
+                    .append_pair("w", &size.width.to_string())
+                    .append_pair("h", &size.height.to_string())
+                    .append_pair("fit", "max");
+                url.as_str().to_owned()
+            }
+            Err(e) => {
+                debug!("unusable raw url for unsplash photo {}: {}", self.id, e);
+                self.links.download.clone()
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SearchResults {
+    results: Vec<Value>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -77,6 +388,251 @@ struct Links {
     download: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct Urls {
+    raw: String,
+}
+
+/// Progress reported by a refresh downloading in the background.
+#[derive(Debug)]
+enum Progress {
+    /// The next image, with the given photo ID and size in bytes, is in the staging directory.
+    Image(String, u64),
+    /// Bytes were downloaded that did not bring in an image, such as a listing.
+    Bytes(u64),
+    /// Unsplash reported it is unavailable, asking to retry after the given delay if any.
+    Unavailable(Option<Duration>),
+    /// The batch is complete.
+    Done,
+    /// The refresh failed. Images reported before are only kept when there is nothing else.
+    Failed(Box<Error + Send + Sync>),
+}
+
+/// A refresh downloading in the background. Its images only replace the cached ones once the
+/// whole batch is in, but are served straight from staging while the cache is empty.
+#[derive(Debug)]
+struct PendingRefresh {
+    /// Progress reported by the download thread.
+    progress: Receiver<Progress>,
+    /// Directory the download thread writes images into.
+    staging: PathBuf,
+    /// Lock on the cache, held until the refresh ends.
+    lock: CacheLock,
+    /// Photo IDs of the images downloaded so far, in cache order.
+    ids: Vec<String>,
+}
+
+/// An ongoing period where Unsplash reports it is unavailable.
+#[derive(Debug)]
+struct Outage {
+    /// Time when the outage was first noticed.
+    start: Instant,
+    /// Number of refresh attempts skipped while waiting for the service to come back.
+    suppressed: u32,
+}
+
+/// Whether Unsplash may be contacted, following the outages it reports with 503.
+#[derive(Debug, Default)]
+struct Availability {
+    /// Earliest time to contact Unsplash again.
+    retry_after: Option<Instant>,
+    /// Current outage, if any.
+    outage: Option<Outage>,
+}
+
+impl Availability {
+    /// Record that Unsplash responded with 503, honoring the delay it asked for or backing off
+    /// for a while when it did not ask for one. Only the first failure of an outage is logged as
+    /// a warning.
+    fn mark_unavailable(&mut self, delay: Option<Duration>, now: Instant) {
+        let delay = delay.unwrap_or_else(|| Duration::from_secs(UNAVAILABLE_BACKOFF_SECS));
+        self.retry_after = Some(now + delay);
+
+        if self.outage.is_some() {
+            debug!("unsplash still unavailable, retrying in {}s", delay.as_secs());
+        } else {
+            warn!("Unsplash is unavailable, retrying in {}s", delay.as_secs());
+            self.outage = Some(Outage {
+                start: now,
+                suppressed: 0,
+            });
+        }
+    }
+
+    /// Record that Unsplash responded normally, summarizing the outage if there was one. Returns
+    /// how long the outage lasted and how many attempts it suppressed.
+    fn mark_available(&mut self, now: Instant) -> Option<(Duration, u32)> {
+        self.retry_after = None;
+        self.outage.take().map(|outage| {
+            let lasted = now.duration_since(outage.start);
+            info!(
+                "Unsplash is available again after {}s, {} attempts suppressed",
+                lasted.as_secs(),
+                outage.suppressed
+            );
+            (lasted, outage.suppressed)
+        })
+    }
+
+    /// Whether a refresh due now has to wait for Unsplash to come back, in which case it counts
+    /// as suppressed.
+    fn suppressed(&mut self, now: Instant) -> bool {
+        match self.retry_after {
+            Some(when) if now < when => {
+                if let Some(ref mut outage) = self.outage {
+                    outage.suppressed += 1;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether Unsplash is in the middle of an outage.
+    fn in_outage(&self) -> bool {
+        self.outage.is_some()
+    }
+}
+
+/// Which photos Unsplash is asked for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnsplashMode {
+    /// The most recently published photos.
+    Latest,
+    /// A different random selection on every refresh.
+    Random,
+}
+
+impl FromStr for UnsplashMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UnsplashMode, String> {
+        match s {
+            "latest" => Ok(UnsplashMode::Latest),
+            "random" => Ok(UnsplashMode::Random),
+            _ => Err(format!("invalid mode '{}', expected latest or random", s)),
+        }
+    }
+}
+
+/// Shape of the Unsplash photos to ask for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orientation {
+    Landscape,
+    Portrait,
+    Squarish,
+}
+
+impl Orientation {
+    /// Name of the orientation as Unsplash expects it.
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Orientation::Landscape => "landscape",
+            Orientation::Portrait => "portrait",
+            Orientation::Squarish => "squarish",
+        }
+    }
+}
+
+impl FromStr for Orientation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Orientation, String> {
+        match s {
+            "landscape" => Ok(Orientation::Landscape),
+            "portrait" => Ok(Orientation::Portrait),
+            "squarish" => Ok(Orientation::Squarish),
+            _ => Err(format!(
+                "invalid orientation '{}', expected landscape, portrait or squarish",
+                s
+            )),
+        }
+    }
+}
+
+/// Size of a screen in pixels, which downloaded photos need not exceed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Resolution {
+    /// Find the resolution of the largest connected screen with xrandr, if it can be run. It
+    /// runs with the same scrubbed environment as the setters, and is killed if it hangs.
+    pub fn detect() -> Option<Resolution> {
+        let mut xrandr = setters::sanitized_command("xrandr", &[]);
+        xrandr.arg("--current").stdout(Stdio::piped());
+        let output = match hold::run_bounded(&mut xrandr) {
+            Ok(Some(o)) => o,
+            Ok(None) => {
+                debug!("xrandr timed out detecting the screen resolution");
+                return None;
+            }
+            Err(e) => {
+                debug!("cannot run xrandr to detect the screen resolution: {}", e);
+                return None;
+            }
+        };
+        if !output.status.success() {
+            debug!("xrandr failed to detect the screen resolution");
+            return None;
+        }
+
+        // The active mode of each output is marked with an asterisk, like `1920x1080 60.00*+`.
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| line.contains('*'))
+            .filter_map(|line| line.split_whitespace().next())
+            .filter_map(|mode| mode.parse::<Resolution>().ok())
+            .max_by_key(|r| (r.width as u64) * (r.height as u64))
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Resolution, String> {
+        let invalid = || format!("invalid resolution '{}', expected WIDTHxHEIGHT", s);
+        let mut parts = s.splitn(2, 'x');
+        let width = parts.next().and_then(|w| w.trim().parse::<u32>().ok());
+        let height = parts.next().and_then(|h| h.trim().parse::<u32>().ok());
+        match (width, height) {
+            (Some(w), Some(h)) if w > 0 && h > 0 => Ok(Resolution {
+                width: w,
+                height: h,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Which Unsplash photos a refresh downloads.
+#[derive(Debug, Clone)]
+struct Selection {
+    /// Whether to take the latest photos or random ones.
+    mode: UnsplashMode,
+    /// Search query to pick photos by.
+    query: Option<String>,
+    /// Collections to get photos from, instead of all of Unsplash.
+    collections: Vec<u64>,
+    /// Shape of the photos, if any in particular.
+    orientation: Option<Orientation>,
+}
+
+/// An ongoing period where the cache cannot be written, so refreshes and evictions are suspended.
+#[derive(Debug)]
+struct ReadOnly {
+    /// Time when writing to the cache was last attempted.
+    checked: Instant,
+}
+
 /// Fetcher for images provided by Unsplash.
 #[derive(Debug)]
 pub struct UnsplashFetcher {
@@ -84,126 +640,1549 @@ pub struct UnsplashFetcher {
     token: String,
     /// Max number of images to get from Unsplash.
     limit: u32,
+    /// Which photos to download on each refresh.
+    selection: Selection,
+    /// Resolution to resize downloaded photos to fit within, or `None` for the originals.
+    resolution: Option<Resolution>,
+    /// Whether to detect the screen resolution while starting up, when none was given.
+    detect_resolution: bool,
     /// Directory for caching images.
     dir: PathBuf,
-    /// Index of next image to use.
-    next: usize,
+    /// Position in the rotation of cached images.
+    rotation: Rotation,
     /// Total number of images cached.
     total: usize,
     /// Whether caching is complete.
     cached: bool,
-    /// Time until next refresh of image cache.
+    /// Time until next refresh of image cache, as configured.
     refresh: Duration,
+    /// Longest time the refresh interval may be stretched to.
+    max_refresh: Duration,
+    /// Current refresh interval, adapted to how often new images appear.
+    interval: Duration,
+    /// Age after which cached images are removed even when they cannot be refreshed.
+    max_age: Option<Duration>,
+    /// Photo IDs from the most recent refresh, in cache order.
+    ids: Vec<String>,
+    /// Photo ID of the image displayed last, if known.
+    last_id: Option<String>,
+    /// Path of the image served last, which may still be on screen.
+    shown: Option<PathBuf>,
+    /// Whether each recent refresh found new photos, oldest first.
+    history: VecDeque<bool>,
     /// Time when successful cache is completed.
     timestamp: Instant,
+    /// Whether Unsplash may be contacted, or is in an outage.
+    availability: Availability,
+    /// Current period where the cache cannot be written, if any.
+    read_only: Option<ReadOnly>,
+    /// Check of whether the cache can be written again.
+    probe: Box<cache::Probe>,
+    /// Most bytes all sources may download in a calendar month, if limited.
+    budget: Option<u64>,
+    /// Bytes downloaded this month.
+    usage: Usage,
+    /// Whether downloads are suspended because the budget is used up.
+    suspended: bool,
+    /// Refresh currently downloading in the background, if any.
+    pending: Option<PendingRefresh>,
 }
 
 impl UnsplashFetcher {
-    pub fn new(token: &str, limit: u32, refresh: Duration) -> Result<Self, Box<Error>> {
-        let mut cache = env::home_dir().unwrap();
-        cache.push(".config");
-        cache.push("wallsplash");
-        cache.push("cache");
+    /// Creates a fetcher with the Unsplash settings of the context. Only the cache directory is
+    /// set up here; call `start_up` before asking for images.
+    pub fn new(ctx: &Context) -> Result<Self, Box<Error>> {
+        let home = env::home_dir().unwrap();
+        UnsplashFetcher::in_home(ctx, &home, Box::new(cache::DiskProbe))
+    }
 
+    /// Creates a fetcher keeping its cache and state under the given home directory, and
+    /// checking whether the cache is writable with the given probe.
+    fn in_home(ctx: &Context, home: &Path, probe: Box<cache::Probe>) -> Result<Self, Box<Error>> {
+        let cache = cache::cache_path(home);
+
+        let mut read_only = None;
         if !cache.is_dir() {
             debug!("creating cache directory {:?}", cache);
-            fs::create_dir_all(&cache)?;
+            match fs::create_dir_all(&cache) {
+                Ok(_) => {}
+                Err(ref e) if cache::is_unwritable(e) => {
+                    warn!(
+                        "cannot write to unsplash cache {}: {}, serving existing images until it \
+                         is writable again",
+                        cache.display(),
+                        e
+                    );
+                    read_only = Some(ReadOnly {
+                        checked: Instant::now(),
+                    });
+                }
+                Err(e) => return Err(Box::new(e)),
+            }
         }
+        let state = usage::state_path(home);
+        let usage = Usage::load(&state);
+        let rotation = Rotation::load(
+            ctx.unsplash_order,
+            &state.join(usage::state_file(ROTATION_FILE)),
+        );
 
         Ok(UnsplashFetcher {
-            token: token.to_owned(),
-            limit: limit,
+            token: ctx.token.clone(),
+            limit: ctx.limit,
+            selection: Selection {
+                mode: ctx.unsplash_mode,
+                query: regional_query(ctx.query.clone(), ctx.region.clone()),
+                collections: ctx.collections.clone(),
+                orientation: ctx.orientation,
+            },
+            resolution: ctx.resolution,
+            detect_resolution: ctx.detect_resolution,
             dir: cache,
-            next: 0,
+            rotation: rotation,
             total: 0,
             cached: false,
-            refresh: refresh,
+            refresh: ctx.refresh,
+            max_refresh: ctx.max_refresh,
+            interval: ctx.refresh,
+            max_age: ctx.max_image_age,
+            ids: Vec::new(),
+            last_id: None,
+            shown: None,
+            history: VecDeque::new(),
             timestamp: Instant::now(),
+            availability: Availability::default(),
+            read_only: read_only,
+            probe: probe,
+            budget: ctx.budget,
+            usage: usage,
+            suspended: false,
+            pending: None,
         })
     }
 
-    /// Calls Unsplash API to download and cache images.
-    fn download_images(&mut self) -> Result<usize, Box<Error>> {
-        let photos_uri = format!(
-            "{}{}?per_page={}&order_by=latest",
-            UNSPLASH_API, PHOTOS_ENDPOINT, self.limit
+    /// Do the slow part of starting up: repair the cache left by the last run, detect the
+    /// screen resolution when asked to, and start the first refresh if one is due. Failures are
+    /// logged, and the fetcher serves whatever is in the cache.
+    pub fn start_up(&mut self) {
+        if self.read_only.is_none() {
+            match cache::repair(&self.dir) {
+                Ok(repair) => self.total = repair.total,
+                Err(e) => {
+                    if cache::is_unwritable(&*e) {
+                        self.mark_read_only(&*e);
+                    } else {
+                        warn!("failed to repair unsplash cache: {}", e);
+                    }
+                    self.total = cache::count_images(&self.dir);
+                }
+            }
+        } else {
+            self.total = cache::count_images(&self.dir);
+        }
+
+        if self.detect_resolution && self.resolution.is_none() {
+            self.resolution = Resolution::detect();
+            match self.resolution {
+                Some(r) => debug!("detected screen resolution {}", r),
+                None => info!("cannot detect the screen resolution, downloading full-size photos"),
+            }
+        }
+
+        if let Err(e) = self.refresh_if_due() {
+            warn!("{}", e);
+        }
+    }
+
+    /// Time until the next refresh is due. Never longer than the maximum image age, so that
+    /// expired images are replaced before they are removed.
+    fn refresh_due(&self) -> Duration {
+        match self.max_age {
+            Some(age) if age < self.interval => age,
+            _ => self.interval,
+        }
+    }
+
+    /// Remove the cached images downloaded longer ago than the maximum image age, which happens
+    /// when refreshes keep failing or are suspended. The image served last is kept, since it
+    /// may be on screen.
+    fn evict_expired(&mut self) -> Result<(), Box<Error>> {
+        let max_age = match self.max_age {
+            // A refresh in progress is already replacing them.
+            Some(_) if self.pending.is_some() => return Ok(()),
+            // Nothing can be removed until the cache is writable again.
+            Some(_) if self.read_only.is_some() => return Ok(()),
+            Some(age) => age,
+            None => return Ok(()),
+        };
+
+        let shown = self.shown.as_deref();
+        let kept = match cache::evict_older(&self.dir, max_age, shown)? {
+            Some(kept) => kept,
+            None => return Ok(()),
+        };
+        self.ids = kept.iter().filter_map(|&i| self.ids.get(i).cloned()).collect();
+        self.total = kept.len();
+        if self.total == 0 {
+            self.cached = false;
+        }
+        Ok(())
+    }
+
+    /// Whether this month's downloads have used up the budget. Only the start of a suspension
+    /// is logged as a warning.
+    fn over_budget(&mut self) -> bool {
+        let budget = match self.budget {
+            Some(b) => b,
+            None => return false,
+        };
+
+        let over = self.usage.total() >= budget;
+        if over && !self.suspended {
+            warn!(
+                "monthly download budget of {} MB used up, downloads suspended until next month",
+                budget / (1024 * 1024)
+            );
+        } else if !over && self.suspended {
+            info!("monthly download budget available again, resuming downloads");
+        }
+        self.suspended = over;
+        over
+    }
+
+    /// The last refresh of the cache by another instance sharing it, when it is recent enough
+    /// to use instead of refreshing again.
+    fn shared_refresh(&self) -> Option<(Duration, usize)> {
+        match cache::read_marker(&self.dir) {
+            Some((age, total)) if age < self.refresh_due() && total > 0 => Some((age, total)),
+            _ => None,
+        }
+    }
+
+    /// Suspend refreshes after a write to the cache failed because the filesystem is mounted
+    /// read-only or permissions forbid it. Only the start of the suspension is logged as a
+    /// warning.
+    fn mark_read_only(&mut self, err: &Error) {
+        if self.read_only.is_none() {
+            warn!(
+                "cannot write to unsplash cache {}: {}, serving existing images until it is \
+                 writable again",
+                self.dir.display(),
+                err
+            );
+        }
+        self.read_only = Some(ReadOnly {
+            checked: Instant::now(),
+        });
+    }
+
+    /// Whether the cache can be written. While it could not, this is only checked again every
+    /// few minutes.
+    fn writable(&mut self) -> bool {
+        let checked = match self.read_only {
+            Some(ref r) => r.checked,
+            None => return true,
+        };
+        if checked.elapsed() < Duration::from_secs(READ_ONLY_PROBE_SECS) {
+            return false;
+        }
+
+        match self.probe.writable(&self.dir) {
+            Ok(_) => {
+                info!(
+                    "unsplash cache {} is writable again, resuming refreshes",
+                    self.dir.display()
+                );
+                self.read_only = None;
+                true
+            }
+            Err(e) => {
+                debug!("unsplash cache still not writable: {}", e);
+                self.read_only = Some(ReadOnly {
+                    checked: Instant::now(),
+                });
+                false
+            }
+        }
+    }
+
+    /// Decide whether a failed refresh can be ridden out by serving the existing cache, which is
+    /// the case during an outage or while the cache cannot be written.
+    fn ride_out(&mut self, err: Box<Error>) -> Result<(), Box<Error>> {
+        self.cached = false;
+        if cache::is_unwritable(&*err) {
+            self.mark_read_only(&*err);
+        } else if !self.availability.in_outage() {
+            return Err(err);
+        }
+
+        if self.total == 0 {
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Continue the rotation in a freshly downloaded batch.
+    fn resume_after_refresh(&mut self, ids: &[String]) {
+        if let Some(idx) = resume_rotation(&mut self.rotation, ids, self.last_id.as_deref()) {
+            debug!("unsplash rotation resuming at {}", idx);
+        }
+    }
+
+    /// Remember whether a refresh brought in new photos and adapt the refresh interval.
+    fn record_refresh(&mut self, ids: Vec<String>) {
+        if !self.ids.is_empty() {
+            let fresh = ids.iter().any(|id| !self.ids.contains(id));
+            if self.history.len() == REFRESH_HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back(fresh);
+
+            let interval = adaptive_interval(self.refresh, self.max_refresh, &self.history);
+            if interval != self.interval {
+                info!(
+                    "unsplash refresh interval now {}s, {} of the last {} refreshes had new photos",
+                    interval.as_secs(),
+                    self.history.iter().filter(|&&f| f).count(),
+                    self.history.len()
+                );
+                self.interval = interval;
+            }
+        }
+        self.ids = ids;
+    }
+
+    /// Start a refresh when one is due and none is running, unless Unsplash is unavailable,
+    /// the budget is used up, another instance has just refreshed the shared cache, or the
+    /// cache cannot be written.
+    fn refresh_if_due(&mut self) -> Result<(), Box<Error>> {
+        let due = !self.cached || self.timestamp.elapsed() >= self.refresh_due();
+        if !due || self.pending.is_some() {
+            return Ok(());
+        }
+
+        if self.availability.suppressed(Instant::now()) {
+            debug!("unsplash unavailable, serving existing cache");
+        } else if self.over_budget() {
+            debug!("download budget used up, serving existing cache");
+        } else if let Some((age, total)) = self.shared_refresh() {
+            debug!("adopting unsplash cache refreshed {}s ago", age.as_secs());
+            self.cached = true;
+            self.total = total;
+            self.timestamp = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+        } else if !self.writable() {
+            debug!("unsplash cache not writable, serving existing cache");
+        } else if let Err(err) = self.start_refresh() {
+            self.ride_out(err)?;
+        }
+        Ok(())
+    }
+
+    /// Start refreshing the cache in the background, unless another instance sharing it is
+    /// already doing so.
+    fn start_refresh(&mut self) -> Result<(), Box<Error>> {
+        let lock = match CacheLock::acquire(&self.dir)? {
+            Some(lock) => lock,
+            None => {
+                debug!("unsplash cache is being refreshed elsewhere");
+                return Ok(());
+            }
+        };
+
+        let staging = cache::create_staging(&self.dir)?;
+        let meter = Meter {
+            left: self.budget.map(|b| b.saturating_sub(self.usage.total())),
+        };
+        let (tx, rx) = mpsc::channel();
+        {
+            let token = self.token.clone();
+            let limit = self.limit;
+            let selection = self.selection.clone();
+            let resolution = self.resolution;
+            let staging = staging.clone();
+            thread::spawn(move || {
+                let done = match download_batch(
+                    &token,
+                    limit,
+                    &selection,
+                    resolution,
+                    &staging,
+                    meter,
+                    &tx,
+                ) {
+                    Ok(_) => Progress::Done,
+                    Err(e) => Progress::Failed(e),
+                };
+                let _ = tx.send(done);
+            });
+        }
+
+        debug!("unsplash refresh started");
+        self.pending = Some(PendingRefresh {
+            progress: rx,
+            staging: staging,
+            lock: lock,
+            ids: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Take note of the images the background refresh has downloaded so far, and swap the whole
+    /// batch into the cache once it is done. Never waits for the refresh, so that other sources
+    /// can fill in while there is nothing to serve yet.
+    fn poll_refresh(&mut self) -> Result<(), Box<Error>> {
+        let mut pending = match self.pending.take() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        loop {
+            match pending.progress.try_recv() {
+                Ok(Progress::Image(id, bytes)) => {
+                    if let Err(e) = self.usage.add("unsplash", bytes) {
+                        warn!("failed to record download usage: {}", e);
+                    }
+                    if let Err(e) = pending.lock.touch() {
+                        warn!("failed to renew cache lock: {}", e);
+                    }
+                    pending.ids.push(id);
+                }
+                Ok(Progress::Bytes(bytes)) => {
+                    if let Err(e) = self.usage.add("unsplash", bytes) {
+                        warn!("failed to record download usage: {}", e);
+                    }
+                }
+                Ok(Progress::Unavailable(delay)) => {
+                    self.availability.mark_unavailable(delay, Instant::now());
+                }
+                Ok(Progress::Done) => {
+                    self.availability.mark_available(Instant::now());
+                    self.cached = true;
+                    self.timestamp = Instant::now();
+                    if pending.ids.is_empty() {
+                        fs::remove_dir_all(&pending.staging)?;
+                        return Ok(());
+                    }
+                    if let Err(e) = pending.lock.touch() {
+                        // Another instance took over the refresh, so its batch wins.
+                        warn!("discarding unsplash refresh: {}", e);
+                        fs::remove_dir_all(&pending.staging)?;
+                        return Ok(());
+                    }
+
+                    cache::complete(&pending.staging, &self.dir, pending.ids.len())?;
+                    self.total = pending.ids.len();
+                    self.resume_after_refresh(&pending.ids);
+                    self.record_refresh(pending.ids);
+                    return Ok(());
+                }
+                Ok(Progress::Failed(err)) => {
+                    self.abandon_refresh(pending)?;
+                    return Err(err);
+                }
+                Err(TryRecvError::Empty) => {
+                    self.pending = Some(pending);
+                    return Ok(());
+                }
+                Err(TryRecvError::Disconnected) => {
+                    self.abandon_refresh(pending)?;
+                    return Err(Box::new(WallsplashError::UnsplashAPIFail));
+                }
+            }
+        }
+    }
+
+    /// Clean up after a failed refresh. The images it did download are kept when the cache has
+    /// nothing else to show, though the refresh does not count as done.
+    fn abandon_refresh(&mut self, pending: PendingRefresh) -> Result<(), Box<Error>> {
+        if self.total > 0 || pending.ids.is_empty() {
+            fs::remove_dir_all(&pending.staging)?;
+            return Ok(());
+        }
+
+        cache::complete(&pending.staging, &self.dir, pending.ids.len())?;
+        self.total = pending.ids.len();
+        self.ids = pending.ids;
+        Ok(())
+    }
+
+    /// The directory to serve images from and how many it has. While the cache is empty, the
+    /// images a refresh has downloaded so far are served from its staging directory.
+    fn serving(&self) -> (PathBuf, usize) {
+        match self.pending {
+            Some(ref p) if self.total == 0 => (p.staging.clone(), p.ids.len()),
+            _ => (cache::live_dir(&self.dir).unwrap_or_else(|| self.dir.clone()), self.total),
+        }
+    }
+}
+
+/// Pick where the rotation continues in a batch of photos: right after the photo displayed last
+/// if it is still there, otherwise at a random photo so the start of each batch does not get
+/// more screen time than the rest. Returns the index it continues at, if the batch has any.
+fn resume_rotation(
+    rotation: &mut Rotation,
+    ids: &[String],
+    last_id: Option<&str>,
+) -> Option<usize> {
+    if ids.is_empty() {
+        return None;
+    }
+
+    let pos = last_id.and_then(|last| ids.iter().position(|id| id == last));
+    let idx = match pos {
+        Some(p) => (p + 1) % ids.len(),
+        None => rotation.random_index(ids.len()),
+    };
+    rotation.restart_at(idx);
+    Some(idx)
+}
+
+/// Calls Unsplash API to download a batch of images into the staging directory, reporting each
+/// image as soon as it is written. Stops early once `allowance` bytes have been downloaded.
+fn download_batch(
+    token: &str,
+    limit: u32,
+    selection: &Selection,
+    resolution: Option<Resolution>,
+    dir: &Path,
+    mut meter: Meter,
+    progress: &Sender<Progress>,
+) -> Result<(), Box<Error + Send + Sync>> {
+    let request = reqwest::Client::new()?;
+    let per_page = limit.to_string();
+    let collections: Vec<String> = selection.collections.iter().map(|id| id.to_string()).collect();
+
+    let mut params = Vec::new();
+    if let Some(o) = selection.orientation {
+        params.push(("orientation", o.as_str().to_owned()));
+    }
+
+    let items: Vec<Value> = match (selection.mode, selection.query.as_ref()) {
+        (UnsplashMode::Random, query) => {
+            params.push(("count", per_page));
+            if let Some(q) = query {
+                params.push(("query", q.clone()));
+            }
+            if !collections.is_empty() {
+                params.push(("collections", collections.join(",")));
+            }
+            let uri = Url::parse_with_params(
+                &format!("{}{}", UNSPLASH_API, RANDOM_ENDPOINT),
+                &params,
+            )?;
+            let mut resp = get_listing(&request, token, uri, progress)?;
+            if *resp.status() == StatusCode::NotFound {
+                info!("no random unsplash photos match the query or collections");
+                return Err(Box::new(WallsplashError::UnsplashNoImage));
+            }
+            if !resp.status().is_success() {
+                return Err(Box::new(WallsplashError::UnsplashAPIFail));
+            }
+            match read_listing(&mut resp, &mut meter, progress)? {
+                Some(listing) => serde_json::from_value(listing)?,
+                None => return Ok(()),
+            }
+        }
+        (UnsplashMode::Latest, Some(q)) => {
+            params.push(("query", q.clone()));
+            params.push(("per_page", per_page));
+            if !collections.is_empty() {
+                params.push(("collections", collections.join(",")));
+            }
+            let uri = Url::parse_with_params(
+                &format!("{}{}", UNSPLASH_API, SEARCH_ENDPOINT),
+                &params,
+            )?;
+            let mut resp = get_listing(&request, token, uri, progress)?;
+            if !resp.status().is_success() {
+                return Err(Box::new(WallsplashError::UnsplashAPIFail));
+            }
+            let found: SearchResults = match read_listing(&mut resp, &mut meter, progress)? {
+                Some(listing) => serde_json::from_value(listing)?,
+                None => return Ok(()),
+            };
+            if found.results.is_empty() {
+                info!("no unsplash photos found for '{}'", q);
+                return Err(Box::new(WallsplashError::UnsplashNoImage));
+            }
+            found.results
+        }
+        (UnsplashMode::Latest, None) if !selection.collections.is_empty() => {
+            params.push(("per_page", per_page));
+            let mut lists = Vec::new();
+            for &id in &selection.collections {
+                let uri = Url::parse_with_params(
+                    &format!("{}{}/{}{}", UNSPLASH_API, COLLECTIONS_ENDPOINT, id, PHOTOS_ENDPOINT),
+                    &params,
+                )?;
+                let mut resp = get_listing(&request, token, uri, progress)?;
+                match *resp.status() {
+                    StatusCode::NotFound | StatusCode::Forbidden | StatusCode::Unauthorized => {
+                        return Err(Box::new(WallsplashError::UnsplashCollection(id)));
+                    }
+                    status if !status.is_success() => {
+                        return Err(Box::new(WallsplashError::UnsplashAPIFail));
+                    }
+                    _ => {}
+                }
+                match read_listing(&mut resp, &mut meter, progress)? {
+                    Some(listing) => lists.push(serde_json::from_value(listing)?),
+                    None => return Ok(()),
+                }
+            }
+            merge_listings(lists, limit as usize)
+        }
+        (UnsplashMode::Latest, None) => {
+            params.push(("per_page", per_page));
+            params.push(("order_by", "latest".to_owned()));
+            let uri = Url::parse_with_params(
+                &format!("{}{}", UNSPLASH_API, PHOTOS_ENDPOINT),
+                &params,
+            )?;
+            let mut resp = get_listing(&request, token, uri, progress)?;
+            if !resp.status().is_success() {
+                return Err(Box::new(WallsplashError::UnsplashAPIFail));
+            }
+            match read_listing(&mut resp, &mut meter, progress)? {
+                Some(listing) => serde_json::from_value(listing)?,
+                None => return Ok(()),
+            }
+        }
+    };
+    let listed = items.len();
+    let photos = parse_photos(items);
+    debug!("json: {:?}", photos);
+
+    if photos.len() < listed {
+        info!(
+            "skipped {} of {} unsplash photos that could not be parsed",
+            listed - photos.len(),
+            listed
         );
-        debug!("url: {}\n", photos_uri);
+    }
+    if photos.is_empty() && listed > 0 {
+        return Err(Box::new(WallsplashError::UnsplashAPIFail));
+    }
+
+    let mut idx = 0;
+    for photo in &photos {
+        if meter.exhausted() {
+            info!("download budget used up, ending the refresh early");
+            break;
+        }
 
-        let request = reqwest::Client::new()?;
-        let mut resp = request
-            .get(&photos_uri)
-            .header(Authorization(format!("Client-ID {}", self.token)))
-            .send()?;
+        let img_url = photo.download_url(resolution);
+        debug!("downloading: {}", img_url);
+
+        let mut resp = request.get(img_url.as_str()).send()?;
 
         debug!("response: {}", resp.url());
         debug!("status:   {}", resp.status());
         debug!("headers:\n\n{}", resp.headers());
 
-        if !resp.status().is_success() {
-            return Err(Box::new(WallsplashError::UnsplashAPIFail));
+        if *resp.status() == StatusCode::ServiceUnavailable {
+            let _ = progress.send(Progress::Unavailable(retry_delay(resp.headers())));
+            return Err(Box::new(WallsplashError::UnsplashUnavailable));
         }
 
-        let photos: Vec<Photo> = resp.json()?;
-        debug!("json: {:?}", photos);
+        let ext = match resp.headers().get::<ContentType>() {
+            Some(mime) => match *mime.deref() {
+                Mime(TopLevel::Image, SubLevel::Jpeg, _) => "jpg",
+                Mime(TopLevel::Image, SubLevel::Png, _) => "png",
+                Mime(TopLevel::Image, SubLevel::Ext(ref s), _) if s == "webp" => "webp",
+                _ => {
+                    debug!("skipping unsupported image type {}", mime.deref());
+                    continue;
+                }
+            },
+            None => continue,
+        };
 
-        let mut idx = 0;
-        for photo in &photos {
-            let img_url = &photo.links.download;
-            debug!("downloading: {}", img_url);
+        let path = cache::image_path(dir, idx, ext);
+        let mut img_file = fs::File::create(&path)?;
+        debug!("writing image: {:?}\n", img_file);
+        let (copied, whole) = meter.copy(&mut resp, &mut img_file)?;
+        if !whole {
+            info!("download budget used up partway through an image, ending the refresh early");
+            drop(img_file);
+            fs::remove_file(&path)?;
+            let _ = progress.send(Progress::Bytes(copied));
+            return Ok(());
+        }
+        idx += 1;
 
-            let mut resp = request.get(img_url.as_str()).send()?;
+        // The fetcher is gone, so nobody is waiting for the rest of the batch.
+        if progress.send(Progress::Image(photo.id.clone(), copied)).is_err() {
+            return Ok(());
+        }
+    }
 
-            debug!("response: {}", resp.url());
-            debug!("status:   {}", resp.status());
-            debug!("headers:\n\n{}", resp.headers());
+    Ok(())
+}
 
-            let mut img_file = match resp.headers().get::<ContentType>() {
-                Some(mime) => match *mime.deref() {
-                    Mime(TopLevel::Image, SubLevel::Jpeg, _) => {
-                        let path = self.dir.join(format!("{}.jpg", idx));
-                        fs::File::create(path)?
-                    }
-                    _ => continue,
-                },
-                None => continue,
-            };
+/// Counts the bytes a refresh downloads, listings included, and holds them to what is left of the
+/// monthly budget.
+#[derive(Debug)]
+struct Meter {
+    /// Bytes that may still be downloaded, if limited.
+    left: Option<u64>,
+}
+
+impl Meter {
+    /// Copy a response body into the writer, reading no more than is left of the budget and one
+    /// byte to tell whether there was more. Returns the bytes read and whether that was the whole
+    /// body.
+    fn copy<R: Read, W: Write>(&mut self, body: &mut R, out: &mut W) -> io::Result<(u64, bool)> {
+        let left = match self.left {
+            Some(left) => left,
+            None => return io::copy(body, out).map(|n| (n, true)),
+        };
+
+        let read = io::copy(&mut body.take(left.saturating_add(1)), out)?;
+        if read > left {
+            self.left = Some(0);
+            Ok((read, false))
+        } else {
+            self.left = Some(left - read);
+            Ok((read, true))
+        }
+    }
+
+    /// Whether the budget is used up.
+    fn exhausted(&self) -> bool {
+        self.left == Some(0)
+    }
+}
+
+/// Reads a listing through the meter, reporting its size. Returns `None` when the budget ran out
+/// before the end of it.
+fn read_listing(
+    resp: &mut reqwest::Response,
+    meter: &mut Meter,
+    progress: &Sender<Progress>,
+) -> Result<Option<Value>, Box<Error + Send + Sync>> {
+    let mut body = Vec::new();
+    let (read, whole) = meter.copy(resp, &mut body)?;
+    let _ = progress.send(Progress::Bytes(read));
+    if !whole {
+        info!("download budget used up while listing photos, ending the refresh early");
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// Requests a photo listing from Unsplash, leaving the caller to judge the status. Reports the
+/// outage and fails when the service is unavailable.
+fn get_listing(
+    request: &reqwest::Client,
+    token: &str,
+    uri: Url,
+    progress: &Sender<Progress>,
+) -> Result<reqwest::Response, Box<Error + Send + Sync>> {
+    debug!("url: {}\n", uri);
+    let resp = request
+        .get(uri)
+        .header(Authorization(format!("Client-ID {}", token)))
+        .send()?;
+
+    debug!("response: {}", resp.url());
+    debug!("status:   {}", resp.status());
+    debug!("headers:\n\n{}", resp.headers());
+
+    if *resp.status() == StatusCode::ServiceUnavailable {
+        let _ = progress.send(Progress::Unavailable(retry_delay(resp.headers())));
+        return Err(Box::new(WallsplashError::UnsplashUnavailable));
+    }
+    Ok(resp)
+}
+
+/// Merges the listings of several collections by taking turns between them, so that each gets a
+/// fair share of the limit. Photos found in more than one collection are only kept once.
+fn merge_listings(lists: Vec<Vec<Value>>, limit: usize) -> Vec<Value> {
+    let mut iters: Vec<_> = lists.into_iter().map(|l| l.into_iter()).collect();
+    let mut seen = HashSet::new();
+    let mut merged = Vec::new();
+
+    while merged.len() < limit && !iters.is_empty() {
+        iters.retain(|i| i.len() > 0);
+        for iter in &mut iters {
+            if merged.len() >= limit {
+                break;
+            }
+            if let Some(item) = iter.next() {
+                let id = item.get("id").and_then(|id| id.as_str()).map(|id| id.to_owned());
+                if id.is_none_or(|id| seen.insert(id)) {
+                    merged.push(item);
+                }
+            }
+        }
+    }
+    merged
+}
 
-            debug!("writing image: {:?}\n", img_file);
-            io::copy(&mut resp, &mut img_file)?;
-            idx += 1;
+/// Converts the items of a photo listing one by one, skipping any that are malformed instead of
+/// throwing away the whole listing.
+fn parse_photos(items: Vec<Value>) -> Vec<Photo> {
+    let mut photos = Vec::new();
+    for item in items {
+        let id = item.get("id")
+            .and_then(|id| id.as_str())
+            .unwrap_or("unknown")
+            .to_owned();
+        match serde_json::from_value::<Photo>(item) {
+            Ok(photo) => photos.push(photo),
+            Err(e) => warn!("skipping malformed unsplash photo {}: {}", id, e),
         }
+    }
+    photos
+}
+
+/// Computes the refresh interval from whether each recent refresh, oldest first, found new
+/// photos. Once fewer than half of the recent refreshes found any, the interval doubles for
+/// every trailing refresh that came back without new photos. The result always stays between
+/// `base` and `max`.
+fn adaptive_interval(base: Duration, max: Duration, history: &VecDeque<bool>) -> Duration {
+    let fresh = history.iter().filter(|&&f| f).count();
+    if fresh * 2 >= history.len() || max <= base {
+        return base;
+    }
 
-        Ok(idx)
+    let stale = history.iter().rev().take_while(|&&f| !f).count() as u32;
+    let interval = 1u32.checked_shl(stale)
+        .and_then(|factor| base.checked_mul(factor))
+        .unwrap_or(max);
+    if interval > max {
+        max
+    } else {
+        interval
+    }
+}
+
+/// Gets the delay asked for by the `Retry-After` header of a response, if any.
+fn retry_delay(headers: &Headers) -> Option<Duration> {
+    headers
+        .get_raw("Retry-After")
+        .and_then(|raw| raw.first())
+        .and_then(|value| str::from_utf8(value).ok())
+        .and_then(|value| parse_retry_after(value, SystemTime::now()))
+}
+
+/// Parses a `Retry-After` header value, which is either a number of seconds or an HTTP-date,
+/// into the delay from `now`. Dates in the past result in no delay.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = value.parse::<HttpDate>().ok()?;
+    let when = date.0.to_timespec().sec;
+    let now = match now.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => return None,
+    };
+    if when > now {
+        Some(Duration::from_secs((when - now) as u64))
+    } else {
+        Some(Duration::from_secs(0))
     }
 }
 
 impl Fetch for UnsplashFetcher {
+    fn name(&self) -> &str {
+        "unsplash"
+    }
+
     fn next_image_path(&mut self) -> Result<PathBuf, Box<Error>> {
-        if !self.cached || self.timestamp.elapsed() >= self.refresh {
-            match self.download_images() {
-                Ok(len) => {
-                    self.cached = true;
-                    self.total = len;
-                }
-                Err(err) => {
-                    self.cached = false;
-                    return Err(err);
-                }
+        self.refresh_if_due()?;
+        if let Err(err) = self.poll_refresh() {
+            self.ride_out(err)?;
+        }
+
+        if let Err(err) = self.evict_expired() {
+            if !cache::is_unwritable(&*err) {
+                return Err(err);
             }
-            self.timestamp = Instant::now();
+            self.mark_read_only(&*err);
         }
+        let (mut dir, total) = self.serving();
+        let mut idx = self.rotation.next(total);
 
-        if self.total > 0 {
-            self.next = self.next % self.total;
+        // Another instance sharing the cache may have swapped in a smaller batch.
+        if idx.is_some_and(|i| cache::find_in(&dir, i).is_none()) {
+            self.total = cache::read_marker(&self.dir).map_or(0, |(_, count)| count);
+            let (live, total) = self.serving();
+            dir = live;
+            idx = self.rotation.next(total);
+        }
 
-            let path = self.dir.join(format!("{}.jpg", self.next));
-            self.next += 1;
+        let found = idx.and_then(|i| cache::find_in(&dir, i).map(|p| (i, p)));
+        if let Some((idx, path)) = found {
+            self.last_id = match self.pending {
+                Some(ref p) if self.total == 0 => p.ids.get(idx).cloned(),
+                _ => self.ids.get(idx).cloned(),
+            };
 
             debug!("unsplash: {:?}", path);
+            self.shown = Some(path.clone());
             return Ok(path);
         }
 
         Err(Box::new(WallsplashError::UnsplashNoImage))
     }
 }
+
+/// Source that starts up on a thread of its own, so that the other sources can serve images in
+/// the meantime. Until it is ready, asking it for an image fails with `SourceStarting`.
+pub struct Warming {
+    /// Name of the source being started.
+    name: String,
+    /// Time when the source began starting up.
+    started: Instant,
+    /// Channel the source arrives on once it is ready, while it is still starting.
+    starting: Option<Receiver<Box<Fetch + Send>>>,
+    /// The source, once it is ready.
+    ready: Option<Box<Fetch + Send>>,
+}
+
+impl Warming {
+    /// Start up the source named `name` by calling `start` on another thread.
+    pub fn start<F>(name: &str, start: F) -> Warming
+    where
+        F: FnOnce() -> Box<Fetch + Send> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(start());
+        });
+        Warming {
+            name: name.to_owned(),
+            started: Instant::now(),
+            starting: Some(rx),
+            ready: None,
+        }
+    }
+
+    /// Take the source once it has finished starting up.
+    fn poll(&mut self) {
+        let arrived = match self.starting {
+            Some(ref rx) => rx.try_recv(),
+            None => return,
+        };
+        match arrived {
+            Ok(source) => {
+                info!("startup: {} ready after {}ms", self.name, ::millis(self.started));
+                self.ready = Some(source);
+            }
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => error!("{} failed while starting up", self.name),
+        }
+        self.starting = None;
+    }
+}
+
+impl fmt::Debug for Warming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Warming")
+            .field("name", &self.name)
+            .field("ready", &self.ready.is_some())
+            .finish()
+    }
+}
+
+impl Fetch for Warming {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn next_image_path(&mut self) -> Result<PathBuf, Box<Error>> {
+        self.poll();
+        match self.ready {
+            Some(ref mut source) => source.next_image_path(),
+            None if self.starting.is_some() => Err(Box::new(WallsplashError::SourceStarting)),
+            None => Err(Box::new(WallsplashError::NoSource)),
+        }
+    }
+
+    fn shown(&mut self, path: &Path) {
+        self.poll();
+        if let Some(ref mut source) = self.ready {
+            source.shown(path);
+        }
+    }
+}
+
+/// Appends the region to the search query so that photos of places nearby come up. Without a
+/// query the region is left out, since searching for it alone would replace the latest photos
+/// or the chosen collections.
+fn regional_query(query: Option<String>, region: Option<String>) -> Option<String> {
+    match (query, region) {
+        (Some(q), Some(r)) => Some(format!("{} {}", q, r)),
+        (None, Some(r)) => {
+            warn!("ignoring region '{}' since there is no unsplash query to add it to", r);
+            None
+        }
+        (q, None) => q,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::fs::symlink;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use libc;
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    use super::*;
+    use engine::{Engine, Order};
+    use testing;
+    use ContextBuilder;
+
+    const SEED: [u32; 4] = [11, 22, 33, 44];
+
+    fn batch(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    fn seeded(order: ShuffleMode) -> Rotation {
+        Rotation::with_rng(order, XorShiftRng::from_seed(SEED))
+    }
+
+    /// Seconds since the epoch of "Sun, 06 Nov 1994 08:49:37 GMT".
+    const DATE_SECS: u64 = 784_111_777;
+
+    #[test]
+    fn resume_after_the_last_photo_when_it_survived() {
+        let ids = batch(&["a", "b", "c", "d"]);
+        let mut rotation = seeded(ShuffleMode::Sequential);
+        assert_eq!(resume_rotation(&mut rotation, &ids, Some("b")), Some(2));
+        assert_eq!(rotation.next(ids.len()), Some(2));
+        assert_eq!(resume_rotation(&mut rotation, &ids, Some("d")), Some(0));
+        assert_eq!(rotation.next(ids.len()), Some(0));
+        resume_rotation(&mut rotation, &ids, Some("c"));
+        let shown: Vec<usize> = (0..4).map(|_| rotation.next(ids.len()).unwrap()).collect();
+        assert_eq!(shown, vec![3, 0, 1, 2]);
+    }
+
+    #[test]
+    fn resume_at_a_random_photo_when_the_last_was_evicted() {
+        let ids = batch(&["e", "f", "g", "h", "i", "j", "k", "l"]);
+        let expected = XorShiftRng::from_seed(SEED).gen_range(0, ids.len());
+
+        let mut rotation = seeded(ShuffleMode::Sequential);
+        assert_eq!(resume_rotation(&mut rotation, &ids, Some("a")), Some(expected));
+        assert_eq!(rotation.next(ids.len()), Some(expected));
+
+        // Over many batches, the start is spread out rather than stuck at the first photo.
+        let starts: HashSet<usize> = (0..50)
+            .filter_map(|seed| {
+                let rng = XorShiftRng::from_seed([seed, 1, 2, 3]);
+                let mut rotation = Rotation::with_rng(ShuffleMode::Sequential, rng);
+                resume_rotation(&mut rotation, &ids, Some("a"))
+            })
+            .collect();
+        assert!(starts.len() > 4, "starts {:?}", starts);
+    }
+
+    #[test]
+    fn resume_in_a_fresh_cache() {
+        let ids = batch(&["a", "b", "c"]);
+        let expected = XorShiftRng::from_seed(SEED).gen_range(0, ids.len());
+        let mut rotation = seeded(ShuffleMode::Sequential);
+        assert_eq!(resume_rotation(&mut rotation, &ids, None), Some(expected));
+        assert_eq!(rotation.next(ids.len()), Some(expected));
+
+        let mut rotation = seeded(ShuffleMode::Sequential);
+        assert_eq!(resume_rotation(&mut rotation, &[], Some("a")), None);
+        assert_eq!(rotation.next(3), Some(0));
+    }
+
+    #[test]
+    fn meter_holds_downloads_to_the_budget() {
+        let mut meter = Meter { left: Some(10) };
+        let mut out = Vec::new();
+        assert_eq!(meter.copy(&mut &b"abcd"[..], &mut out).unwrap(), (4, true));
+        assert_eq!(meter.copy(&mut &b"efghij"[..], &mut out).unwrap(), (6, true));
+        assert_eq!(out, b"abcdefghij");
+        assert!(meter.exhausted());
+
+        let mut meter = Meter { left: Some(5) };
+        let mut out = Vec::new();
+        assert_eq!(meter.copy(&mut &b"abcdefgh"[..], &mut out).unwrap(), (6, false));
+        assert_eq!(out.len(), 6);
+        assert!(meter.exhausted());
+        assert_eq!(meter.copy(&mut &b"more"[..], &mut Vec::new()).unwrap(), (1, false));
+
+        let mut meter = Meter { left: None };
+        let body = vec![7; 100_000];
+        assert_eq!(meter.copy(&mut &body[..], &mut Vec::new()).unwrap(), (100_000, true));
+        assert!(!meter.exhausted());
+    }
+
+    #[test]
+    fn outage_honors_the_requested_delay() {
+        let start = Instant::now();
+        let mut availability = Availability::default();
+        assert!(!availability.suppressed(start));
+
+        availability.mark_unavailable(Some(Duration::from_secs(120)), start);
+        assert!(availability.in_outage());
+        assert!(availability.suppressed(start + Duration::from_secs(60)));
+        assert!(availability.suppressed(start + Duration::from_secs(119)));
+        assert!(!availability.suppressed(start + Duration::from_secs(120)));
+
+        // Still down when tried again, so the outage carries on with the new delay.
+        let retried = start + Duration::from_secs(120);
+        availability.mark_unavailable(Some(Duration::from_secs(30)), retried);
+        assert!(availability.suppressed(retried + Duration::from_secs(10)));
+        assert!(!availability.suppressed(retried + Duration::from_secs(30)));
+
+        let back = start + Duration::from_secs(200);
+        assert_eq!(
+            availability.mark_available(back),
+            Some((Duration::from_secs(200), 3))
+        );
+        assert!(!availability.in_outage());
+        assert!(!availability.suppressed(back));
+        assert_eq!(availability.mark_available(back), None);
+    }
+
+    #[test]
+    fn outage_without_retry_after_backs_off() {
+        let start = Instant::now();
+        let backoff = Duration::from_secs(UNAVAILABLE_BACKOFF_SECS);
+        let mut availability = Availability::default();
+
+        availability.mark_unavailable(None, start);
+        assert!(availability.suppressed(start + Duration::from_secs(1)));
+        assert!(availability.suppressed(start + backoff - Duration::from_secs(1)));
+        assert!(!availability.suppressed(start + backoff));
+
+        assert_eq!(
+            availability.mark_available(start + backoff),
+            Some((backoff, 2))
+        );
+    }
+
+    #[test]
+    fn latin1_names_are_candidates() {
+        let extensions = image_extensions(&[]);
+        let candidate = |name: &[u8], hidden| {
+            let path = Path::new("/walls").join(OsStr::from_bytes(name));
+            is_candidate(&path, false, &extensions, hidden)
+        };
+        assert!(candidate(b"caf\xe9.jpg", false));
+        assert!(candidate(b"\xe9t\xe9.PNG", false));
+        assert!(!candidate(b".caf\xe9.jpg", false));
+        assert!(candidate(b".caf\xe9.jpg", true));
+        assert!(!candidate(b"caf\xe9.txt", false));
+        assert!(!candidate(b"caf\xe9.jp\xe9", false));
+    }
+
+    /// Scratch directory for the local fetcher tests, emptied first.
+    fn local_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("wallsplash-local-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Local fetcher in sequential order that rescans only when a directory changes, or every
+    /// time with `always_rescan`.
+    fn local_fetcher(dirs: &[PathBuf], max_files: usize, recursive: bool) -> LocalFetcher {
+        let rescan = Duration::from_secs(60 * 60);
+        let order = ShuffleMode::Sequential;
+        LocalFetcher::new(dirs, max_files, &[], false, recursive, rescan, order)
+    }
+
+    fn always_rescan(mut fetcher: LocalFetcher) -> LocalFetcher {
+        fetcher.rescan = Duration::from_secs(0);
+        fetcher
+    }
+
+    /// Create empty files with the given names under `dir`, and return their paths.
+    fn touch(dir: &Path, names: &[&str]) -> Vec<PathBuf> {
+        names
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                fs::write(&path, b"").unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn next_paths(fetcher: &mut LocalFetcher, count: usize) -> Vec<PathBuf> {
+        (0..count).map(|_| fetcher.next_image_path().unwrap()).collect()
+    }
+
+    #[test]
+    fn local_listing_is_reused_while_unchanged() {
+        let dir = local_dir("unchanged");
+        let images = touch(&dir, &["a.jpg", "b.jpg"]);
+        let mut fetcher = local_fetcher(std::slice::from_ref(&dir), 10, false);
+
+        assert_eq!(next_paths(&mut fetcher, 2), images);
+        let scanned = fetcher.listing.as_ref().unwrap().scanned;
+        assert_eq!(next_paths(&mut fetcher, 2), images);
+        assert_eq!(fetcher.listing.as_ref().unwrap().scanned, scanned);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn local_file_added_between_calls_joins_the_rotation() {
+        let dir = local_dir("added");
+        let images = touch(&dir, &["a.jpg", "c.jpg"]);
+        let mut fetcher = local_fetcher(std::slice::from_ref(&dir), 10, false);
+        assert_eq!(fetcher.next_image_path().unwrap(), images[0]);
+
+        let added = touch(&dir, &["b.jpg"]);
+        let expected = vec![added[0].clone(), images[1].clone(), images[0].clone()];
+        assert_eq!(next_paths(&mut fetcher, 3), expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn local_rotation_survives_a_changing_directory() {
+        let dir = local_dir("mutating");
+        let mut fetcher = always_rescan(local_fetcher(std::slice::from_ref(&dir), 10, false));
+        assert!(fetcher.next_image_path().is_err());
+
+        for round in 0..20 {
+            let name = format!("{:02}.png", round);
+            touch(&dir, &[&name]);
+            if round % 3 == 2 {
+                let gone = dir.join(format!("{:02}.png", round - 2));
+                fs::remove_file(&gone).unwrap();
+            }
+            let path = fetcher.next_image_path().unwrap();
+            assert!(path.starts_with(&dir), "{:?}", path);
+            assert!(path.exists(), "{:?} was removed", path);
+        }
+
+        for entry in fs::read_dir(&dir).unwrap() {
+            fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+        assert!(fetcher.next_image_path().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn local_recursive_scan_follows_symlinks_once() {
+        let dir = local_dir("nested");
+        let deep = dir.join("2017").join("summer");
+        fs::create_dir_all(&deep).unwrap();
+        let outside = local_dir("nested-outside");
+        let mut images = touch(&dir, &["a.jpg"]);
+        images.extend(touch(&dir.join("2017"), &["b.jpg"]));
+        images.extend(touch(&deep, &["c.jpg"]));
+        let linked = touch(&outside, &["d.jpg"]);
+        symlink(&outside, dir.join("linked")).unwrap();
+        // A loop back to the top, and a second way into a directory already searched.
+        symlink(&dir, deep.join("loop")).unwrap();
+        symlink(&deep, dir.join("shortcut")).unwrap();
+
+        let flat = local_fetcher(std::slice::from_ref(&dir), 10, false).scan().0;
+        assert_eq!(flat, &images[..1]);
+
+        let found = local_fetcher(std::slice::from_ref(&dir), 10, true).scan().0;
+        assert_eq!(found.len(), 4, "{:?}", found);
+        assert_eq!(&found[..3], &images[..]);
+        assert_eq!(found[3], dir.join("linked").join("d.jpg"));
+        assert!(found[3].ends_with(linked[0].file_name().unwrap()));
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn local_missing_dir_is_skipped() {
+        let first = local_dir("several-a");
+        let second = local_dir("several-b");
+        let missing = local_dir("several-missing");
+        fs::remove_dir(&missing).unwrap();
+        let mut images = touch(&first, &["a.jpg"]);
+        images.extend(touch(&second, &["b.jpg"]));
+
+        let dirs = vec![first.clone(), missing.clone(), second.clone()];
+        let mut fetcher = local_fetcher(&dirs, 10, false);
+        assert_eq!(next_paths(&mut fetcher, 2), images);
+        assert_eq!(fetcher.unreadable, vec![missing]);
+        fs::remove_dir_all(&first).unwrap();
+        fs::remove_dir_all(&second).unwrap();
+    }
+
+    #[test]
+    fn local_listing_stops_at_max_files() {
+        let first = local_dir("max-a");
+        let second = local_dir("max-b");
+        let images = touch(&first, &["a.jpg", "b.jpg", "c.jpg"]);
+        touch(&second, &["d.jpg"]);
+
+        let mut fetcher = local_fetcher(&[first.clone(), second.clone()], 2, false);
+        let expected = vec![images[0].clone(), images[1].clone(), images[0].clone()];
+        assert_eq!(next_paths(&mut fetcher, 3), expected);
+        assert!(fetcher.truncated);
+
+        let mut fetcher = local_fetcher(&[first.clone(), second.clone()], 4, false);
+        assert_eq!(fetcher.next_image_path().unwrap(), images[0]);
+        assert!(!fetcher.truncated);
+        assert_eq!(fetcher.listing.as_ref().unwrap().images.len(), 4);
+        fs::remove_dir_all(&first).unwrap();
+        fs::remove_dir_all(&second).unwrap();
+    }
+
+    #[test]
+    fn latin1_names_rotate_and_keep_their_place() {
+        let dir = local_dir("latin1");
+        let names: [&[u8]; 3] = [b"b-caf\xe9.png", b"c-na\xefve.jpg", b"d-cr\xe8me.png"];
+        let images: Vec<PathBuf> = names.iter().map(|n| dir.join(OsStr::from_bytes(n))).collect();
+        for image in &images {
+            testing::write_png(image, 1, 1).unwrap();
+        }
+
+        let local = local_fetcher(std::slice::from_ref(&dir), 10, false);
+        let sources: Vec<Box<Fetch>> = vec![Box::new(local)];
+        let mut engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+        for image in &images[..2] {
+            assert_eq!(&engine.next_image().unwrap().path, image);
+        }
+
+        // A new image sorting first does not move the rotation back.
+        testing::write_png(&dir.join("a-new.png"), 1, 1).unwrap();
+        assert_eq!(engine.next_image().unwrap().path, images[2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn region_only_narrows_a_search() {
+        let q = |s: &str| Some(s.to_owned());
+        assert_eq!(regional_query(q("beach"), q("Lisbon")), q("beach Lisbon"));
+        assert_eq!(regional_query(q("beach"), None), q("beach"));
+        assert_eq!(regional_query(None, q("Lisbon")), None);
+        assert_eq!(regional_query(None, None), None);
+    }
+
+    #[test]
+    fn retry_after_delta_seconds() {
+        let now = UNIX_EPOCH + Duration::from_secs(DATE_SECS);
+        assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::from_secs(0)));
+        assert_eq!(parse_retry_after("-5", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("", now), None);
+    }
+
+    #[test]
+    fn retry_after_http_date() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let before = UNIX_EPOCH + Duration::from_secs(DATE_SECS - 90);
+        assert_eq!(parse_retry_after(date, before), Some(Duration::from_secs(90)));
+
+        let at = UNIX_EPOCH + Duration::from_secs(DATE_SECS);
+        assert_eq!(parse_retry_after(date, at), Some(Duration::from_secs(0)));
+
+        let after = UNIX_EPOCH + Duration::from_secs(DATE_SECS + 3600);
+        assert_eq!(parse_retry_after(date, after), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn adaptive_interval_table() {
+        let base = Duration::from_secs(60);
+        let max = Duration::from_secs(300);
+        // (history oldest first, expected interval in seconds)
+        let cases: &[(&[bool], u64)] = &[
+            (&[], 60),
+            (&[true, true, true], 60),
+            (&[true, false], 60),
+            (&[false, false, true, true], 60),
+            (&[false], 120),
+            (&[true, false, false], 240),
+            (&[false, false, false], 300),
+            (&[false, false, false, false, false, false, false, false], 300),
+            (&[false, false, false, true], 60),
+        ];
+        for &(history, expected) in cases {
+            let history: VecDeque<bool> = history.iter().cloned().collect();
+            let interval = adaptive_interval(base, max, &history);
+            assert_eq!(interval, Duration::from_secs(expected), "history {:?}", history);
+        }
+    }
+
+    #[test]
+    fn adaptive_interval_never_below_base() {
+        let base = Duration::from_secs(600);
+        let history: VecDeque<bool> = vec![false; 4].into_iter().collect();
+        assert_eq!(adaptive_interval(base, Duration::from_secs(60), &history), base);
+        assert_eq!(adaptive_interval(base, base, &history), base);
+    }
+
+    #[test]
+    fn adaptive_interval_survives_overflow() {
+        let base = Duration::from_secs(u64::MAX / 2);
+        let max = Duration::from_secs(u64::MAX);
+        let history: VecDeque<bool> = vec![false; 40].into_iter().collect();
+        assert_eq!(adaptive_interval(base, max, &history), max);
+    }
+
+    fn listing(json: &str) -> Vec<Photo> {
+        match serde_json::from_str::<Value>(json).unwrap() {
+            Value::Array(items) => parse_photos(items),
+            _ => panic!("fixture is not a listing"),
+        }
+    }
+
+    #[test]
+    fn parse_photos_keeps_well_formed_items() {
+        let photos = listing(r#"[
+            {"id": "a", "links": {"download": "https://unsplash.com/photos/a/download"}},
+            {"id": "b", "links": {"download": "https://unsplash.com/photos/b/download"},
+             "urls": {"raw": "https://images.unsplash.com/b"}}
+        ]"#);
+        assert_eq!(photos.len(), 2);
+        assert_eq!(photos[0].id, "a");
+        assert_eq!(photos[0].links.download, "https://unsplash.com/photos/a/download");
+        assert!(photos[0].urls.is_none());
+        assert_eq!(photos[1].urls.as_ref().unwrap().raw, "https://images.unsplash.com/b");
+    }
+
+    #[test]
+    fn parse_photos_skips_missing_links() {
+        let photos = listing(r#"[
+            {"id": "a"},
+            {"id": "b", "links": {}},
+            {"id": "c", "links": null},
+            {"id": "d", "links": {"download": "https://unsplash.com/photos/d/download"}}
+        ]"#);
+        let ids: Vec<&str> = photos.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(ids, vec!["d"]);
+    }
+
+    #[test]
+    fn parse_photos_ignores_null_users_and_extra_fields() {
+        let photos = listing(r##"[
+            {"id": "a", "user": null, "likes": 3, "color": "#60544D",
+             "links": {"download": "https://unsplash.com/photos/a/download", "html": "x"},
+             "urls": {"raw": "https://images.unsplash.com/a", "full": "y"},
+             "sponsorship": {"sponsor": null}}
+        ]"##);
+        assert_eq!(photos.len(), 1);
+        assert_eq!(photos[0].id, "a");
+    }
+
+    #[test]
+    fn parse_photos_skips_items_that_are_not_photos() {
+        let photos = listing(r#"[
+            null, 7, "a", [],
+            {"id": 5, "links": {"download": "https://unsplash.com/photos/5/download"}},
+            {"id": "b", "links": {"download": "https://unsplash.com/photos/b/download"}}
+        ]"#);
+        let ids: Vec<&str> = photos.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(ids, vec!["b"]);
+    }
+
+    /// Cache filesystem that fails writes with the error number the test sets, and counts the
+    /// probes made.
+    #[derive(Debug, Clone, Default)]
+    struct FakeProbe {
+        errno: Arc<Mutex<Option<i32>>>,
+        probes: Arc<Mutex<usize>>,
+    }
+
+    impl FakeProbe {
+        fn fail_with(&self, errno: Option<i32>) {
+            *self.errno.lock().unwrap() = errno;
+        }
+
+        fn probes(&self) -> usize {
+            *self.probes.lock().unwrap()
+        }
+    }
+
+    impl cache::Probe for FakeProbe {
+        fn writable(&self, _dir: &Path) -> io::Result<()> {
+            *self.probes.lock().unwrap() += 1;
+            match *self.errno.lock().unwrap() {
+                Some(errno) => Err(io::Error::from_raw_os_error(errno)),
+                None => Ok(()),
+            }
+        }
+    }
+
+    /// Unsplash fetcher keeping its cache under the given scratch home directory.
+    fn unsplash_fetcher(home: &Path, probe: &FakeProbe) -> UnsplashFetcher {
+        let ctx = ContextBuilder::new().dir(home).build().unwrap();
+        UnsplashFetcher::in_home(&ctx, home, Box::new(probe.clone())).unwrap()
+    }
+
+    /// Pretend the last write to the cache was attempted long enough ago to try again.
+    fn probe_due(fetcher: &mut UnsplashFetcher) {
+        let due = Duration::from_secs(READ_ONLY_PROBE_SECS);
+        fetcher.read_only = Some(ReadOnly {
+            checked: Instant::now().checked_sub(due).unwrap(),
+        });
+    }
+
+    fn read_only_error() -> Box<Error> {
+        Box::new(io::Error::from_raw_os_error(libc::EROFS))
+    }
+
+    #[test]
+    fn read_only_cache_is_served_until_writable() {
+        let probe = FakeProbe::default();
+        let home = local_dir("unsplash-read-only");
+        let mut fetcher = unsplash_fetcher(&home, &probe);
+        fetcher.total = 3;
+        assert!(fetcher.writable());
+
+        probe.fail_with(Some(libc::EROFS));
+        fetcher.ride_out(read_only_error()).unwrap();
+        assert!(fetcher.read_only.is_some());
+        assert!(!fetcher.cached);
+
+        // Not checked again until a few minutes have passed.
+        assert!(!fetcher.writable());
+        assert_eq!(probe.probes(), 0);
+
+        probe_due(&mut fetcher);
+        assert!(!fetcher.writable());
+        assert_eq!(probe.probes(), 1);
+        assert!(!fetcher.writable());
+        assert_eq!(probe.probes(), 1);
+
+        probe.fail_with(None);
+        probe_due(&mut fetcher);
+        assert!(fetcher.writable());
+        assert!(fetcher.read_only.is_none());
+        assert!(fetcher.writable());
+        assert_eq!(probe.probes(), 2);
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn read_only_cache_without_images_is_an_error() {
+        let probe = FakeProbe::default();
+        let home = local_dir("unsplash-read-only-empty");
+        let mut fetcher = unsplash_fetcher(&home, &probe);
+        assert!(fetcher.ride_out(read_only_error()).is_err());
+        assert!(fetcher.read_only.is_some());
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn other_write_errors_leave_the_cache_writable() {
+        let probe = FakeProbe::default();
+        let home = local_dir("unsplash-full");
+        let mut fetcher = unsplash_fetcher(&home, &probe);
+        fetcher.total = 3;
+        let full: Box<Error> = Box::new(io::Error::from_raw_os_error(libc::ENOSPC));
+        assert!(fetcher.ride_out(full).is_err());
+        assert!(fetcher.read_only.is_none());
+        assert!(fetcher.writable());
+        assert_eq!(probe.probes(), 0);
+        fs::remove_dir_all(&home).unwrap();
+    }
+}
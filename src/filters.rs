@@ -0,0 +1,195 @@
+//! Module for filters that decide whether a chosen image should be shown.
+
+use std::path::PathBuf;
+
+use engine::ImageInfo;
+use fetchers::Resolution;
+use verify;
+
+/// Decision of a filter about an image.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    /// The image may be shown.
+    Accept,
+    /// The image must not be shown, for the given reason.
+    Reject { reason: String },
+    /// The image may be shown, but another one is preferred when the weight is below 1. The
+    /// weights of every filter in the chain are multiplied together.
+    Prefer { weight: f64 },
+}
+
+/// What the engine knows about the current rotation when it asks a filter about an image.
+#[derive(Debug, Clone, Copy)]
+pub struct FilterCtx<'a> {
+    /// Images shown most recently, the latest last.
+    pub recent: &'a [PathBuf],
+    /// Number of candidates already tried in this rotation, starting at 0.
+    pub attempt: usize,
+}
+
+/// Predicate applied by the engine to every image chosen from any source.
+pub trait Filter {
+    /// Returns the name of this filter, for logging and stats.
+    fn name(&self) -> &str;
+
+    /// Decides whether the image may be shown.
+    fn accept(&self, info: &ImageInfo, ctx: &FilterCtx) -> Verdict;
+}
+
+/// How often a filter gave each verdict since the engine started.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilterStats {
+    /// Name of the filter.
+    pub name: String,
+    /// Images the filter accepted outright.
+    pub accepted: u64,
+    /// Images the filter rejected.
+    pub rejected: u64,
+    /// Images the filter accepted with a preference weight.
+    pub preferred: u64,
+    /// Images the filter rejected that another image replaced within the same rotation.
+    pub recovered: u64,
+}
+
+/// Filter that rejects files that are gone, unreadable, or not a supported image.
+#[derive(Debug)]
+pub struct ValidImage;
+
+impl Filter for ValidImage {
+    fn name(&self) -> &str {
+        "valid-image"
+    }
+
+    fn accept(&self, info: &ImageInfo, _: &FilterCtx) -> Verdict {
+        match verify::check_image(&info.path) {
+            Ok(_) => Verdict::Accept,
+            Err(problem) => Verdict::Reject {
+                reason: problem.to_string(),
+            },
+        }
+    }
+}
+
+/// Filter that rejects listed images, and every image inside a listed directory.
+#[derive(Debug)]
+pub struct Blacklist {
+    paths: Vec<PathBuf>,
+}
+
+impl Blacklist {
+    pub fn new(paths: Vec<PathBuf>) -> Blacklist {
+        Blacklist { paths: paths }
+    }
+}
+
+impl Filter for Blacklist {
+    fn name(&self) -> &str {
+        "blacklist"
+    }
+
+    fn accept(&self, info: &ImageInfo, _: &FilterCtx) -> Verdict {
+        match self.paths.iter().find(|p| info.path.starts_with(p)) {
+            Some(p) => Verdict::Reject {
+                reason: format!("blacklisted by {}", p.display()),
+            },
+            None => Verdict::Accept,
+        }
+    }
+}
+
+/// Filter that rejects images narrower or shorter than a resolution.
+#[derive(Debug)]
+pub struct MinResolution {
+    min: Resolution,
+}
+
+impl MinResolution {
+    pub fn new(min: Resolution) -> MinResolution {
+        MinResolution { min: min }
+    }
+}
+
+impl Filter for MinResolution {
+    fn name(&self) -> &str {
+        "min-resolution"
+    }
+
+    fn accept(&self, info: &ImageInfo, _: &FilterCtx) -> Verdict {
+        match verify::image_size(&info.path) {
+            Ok((w, h)) if w >= self.min.width && h >= self.min.height => Verdict::Accept,
+            Ok((w, h)) => Verdict::Reject {
+                reason: format!("{}x{} is smaller than {}", w, h, self.min),
+            },
+            Err(problem) => Verdict::Reject {
+                reason: problem.to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    use super::*;
+    use testing;
+
+    fn info(path: &Path) -> ImageInfo {
+        ImageInfo {
+            path: path.to_path_buf(),
+            source: "test".to_owned(),
+        }
+    }
+
+    fn verdict(filter: &Filter, path: &Path) -> Verdict {
+        let ctx = FilterCtx {
+            recent: &[],
+            attempt: 0,
+        };
+        filter.accept(&info(path), &ctx)
+    }
+
+    fn rejected(v: Verdict) -> bool {
+        matches!(v, Verdict::Reject { .. })
+    }
+
+    #[test]
+    fn blacklist_matches_files_and_directories() {
+        let filter = Blacklist::new(vec![
+            PathBuf::from("/pics/ugly.jpg"),
+            PathBuf::from("/pics/old"),
+        ]);
+        assert!(rejected(verdict(&filter, Path::new("/pics/ugly.jpg"))));
+        assert!(rejected(verdict(&filter, Path::new("/pics/old/a.jpg"))));
+        assert!(rejected(verdict(&filter, Path::new("/pics/old/deep/b.png"))));
+        assert_eq!(verdict(&filter, Path::new("/pics/older/a.jpg")), Verdict::Accept);
+        assert_eq!(verdict(&filter, Path::new("/pics/ugly.jpg.png")), Verdict::Accept);
+    }
+
+    #[test]
+    fn min_resolution_checks_both_dimensions() {
+        let dir = env::temp_dir().join(format!("wallsplash-filters-{}", process::id()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        let filter = MinResolution::new(Resolution {
+            width: 1920,
+            height: 1080,
+        });
+
+        let cases: &[(u16, u16, bool)] = &[
+            (1920, 1080, true),
+            (3840, 2160, true),
+            (1919, 1080, false),
+            (1920, 1079, false),
+            (640, 480, false),
+        ];
+        for &(w, h, ok) in cases {
+            let path = dir.join(format!("{}x{}.jpg", w, h));
+            testing::write_jpeg(&path, w, h).unwrap();
+            assert_eq!(verdict(&filter, &path) == Verdict::Accept, ok, "{}x{}", w, h);
+        }
+        assert!(rejected(verdict(&filter, &dir.join("missing.jpg"))));
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
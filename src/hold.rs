@@ -0,0 +1,149 @@
+//! Module for holding rotations while the user does not want to be disturbed.
+
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use errors::WallsplashError;
+use setters;
+
+/// Seconds a hold command may run before it is killed.
+const COMMAND_TIMEOUT_SECS: u64 = 5;
+
+/// Milliseconds between checks on whether a hold command has finished.
+const COMMAND_POLL_MILLIS: u64 = 50;
+
+pub trait HoldProbe {
+    /// Returns whether rotations should currently be held.
+    fn is_held(&self) -> Result<bool, Box<Error>>;
+}
+
+/// Condition that holds rotations while it is true.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hold {
+    /// Hold while the file exists.
+    File(PathBuf),
+    /// Hold while the shell command exits successfully.
+    Command(String),
+}
+
+impl FromStr for Hold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Hold, String> {
+        if s.starts_with("file:") && s.len() > 5 {
+            Ok(Hold::File(PathBuf::from(&s[5..])))
+        } else if s.starts_with("cmd:") && s.len() > 4 {
+            Ok(Hold::Command(s[4..].to_owned()))
+        } else {
+            Err(format!(
+                "invalid hold_while '{}', expected file:<path> or cmd:<command>",
+                s
+            ))
+        }
+    }
+}
+
+impl HoldProbe for Hold {
+    fn is_held(&self) -> Result<bool, Box<Error>> {
+        match *self {
+            Hold::File(ref path) => Ok(path.exists()),
+            Hold::Command(ref cmd) => run_probe(cmd, Duration::from_secs(COMMAND_TIMEOUT_SECS)),
+        }
+    }
+}
+
+/// Run the shell command with the setter's sanitized environment, killing it after `timeout`.
+/// Returns whether it exited successfully.
+fn run_probe(cmd: &str, timeout: Duration) -> Result<bool, Box<Error>> {
+    let mut sh = setters::sanitized_command("sh", &[]);
+    sh.arg("-c").arg(cmd).stdout(Stdio::null());
+    match run_for(&mut sh, timeout)? {
+        Some(output) => Ok(output.status.success()),
+        None => Err(Box::new(WallsplashError::HoldProbeTimeout)),
+    }
+}
+
+/// Run a command that checks on the session, killing it when it takes too long. Returns what it
+/// printed to stdout, if piped, or `None` when it was killed. Its stderr is discarded.
+pub fn run_bounded(cmd: &mut Command) -> Result<Option<Output>, Box<Error>> {
+    run_for(cmd, Duration::from_secs(COMMAND_TIMEOUT_SECS))
+}
+
+/// Run a command like `run_bounded`, killing it after `timeout`.
+fn run_for(cmd: &mut Command, timeout: Duration) -> Result<Option<Output>, Box<Error>> {
+    let mut child = cmd.stderr(Stdio::null()).spawn()?;
+    let start = Instant::now();
+
+    loop {
+        if child.try_wait()?.is_some() {
+            return Ok(Some(child.wait_with_output()?));
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(COMMAND_POLL_MILLIS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn parse_file_and_command_holds() {
+        assert_eq!(
+            "file:/run/user/1000/dnd".parse::<Hold>(),
+            Ok(Hold::File(PathBuf::from("/run/user/1000/dnd")))
+        );
+        assert_eq!(
+            "cmd:makoctl mode | grep -q dnd".parse::<Hold>(),
+            Ok(Hold::Command("makoctl mode | grep -q dnd".to_owned()))
+        );
+        for bad in &["", "file:", "cmd:", "/run/user/1000/dnd", "command:true", "FILE:/tmp/x"] {
+            assert!(bad.parse::<Hold>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn file_holds_while_it_exists() {
+        let path = env::temp_dir().join(format!("wallsplash-hold-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let hold = Hold::File(path.clone());
+        assert!(!hold.is_held().unwrap());
+
+        fs::write(&path, b"").unwrap();
+        assert!(hold.is_held().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(!hold.is_held().unwrap());
+    }
+
+    #[test]
+    fn command_holds_while_it_succeeds() {
+        assert!(Hold::Command("true".to_owned()).is_held().unwrap());
+        assert!(!Hold::Command("exit 3".to_owned()).is_held().unwrap());
+        assert!(!Hold::Command("no-such-command-here".to_owned()).is_held().unwrap());
+    }
+
+    #[test]
+    fn hung_command_times_out() {
+        let start = Instant::now();
+        match run_probe("sleep 60", Duration::from_millis(200)) {
+            Err(ref e) => match e.downcast_ref::<WallsplashError>() {
+                Some(&WallsplashError::HoldProbeTimeout) => {}
+                _ => panic!("{}", e),
+            },
+            Ok(held) => panic!("finished with {}", held),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5), "took {:?}", start.elapsed());
+    }
+}
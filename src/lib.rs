@@ -1,74 +1,1017 @@
 //! Library for rotating desktop wallpapers using local and Unsplash images.
 
+#![allow(bare_trait_objects)]
+#![allow(clippy::redundant_field_names, clippy::redundant_static_lifetimes)]
+
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate serde_derive;
+extern crate libc;
+extern crate rand;
 extern crate reqwest;
+extern crate serde_json;
 
+use std::cmp;
 use std::error::Error;
-use std::process::Command;
+use std::path::{Path, PathBuf};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+mod cache;
+mod engine;
 mod errors;
 mod fetchers;
+mod filters;
+mod hold;
+mod privileges;
+mod region;
+mod rotation;
+mod session;
+mod setters;
+mod slideshow;
+mod status;
+mod usage;
+mod verify;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use engine::{Engine, ImageInfo, Images, Order, SOURCES};
+pub use errors::{classify, ErrorClass, WallsplashError};
+pub use fetchers::{Fetch, Orientation, Resolution, UnsplashMode, Warming};
+pub use filters::{Blacklist, Filter, FilterCtx, FilterStats, MinResolution, ValidImage, Verdict};
+pub use hold::Hold;
+pub use rotation::ShuffleMode;
+pub use region::from_timezone as timezone_region;
+pub use session::OnLocked;
+pub use setters::{setter_by_name, CommandSetter, FehSetter, GnomeSetter, Headless,
+                  NitrogenSetter, Scaling, SwaySetter, SwaybgSetter, WallpaperSetter, SETTERS};
+#[cfg(target_os = "macos")]
+pub use setters::MacosSetter;
+pub use slideshow::slideshow;
+pub use status::{status, Status};
+pub use verify::{check_image, image_size, verify_local, Problem, Report};
+
+use hold::HoldProbe;
+use session::{LockProbe, SessionLockProbe};
+
+/// Seconds between session lock checks while a rotation is deferred.
+const LOCK_POLL_SECS: u64 = 5;
+
+/// Seconds between attempts to set the wallpaper while there is no display.
+const DISPLAY_POLL_SECS: u64 = 30;
+
+/// Seconds between checks on whether a hold has been released.
+const HOLD_POLL_SECS: u64 = 10;
 
-use fetchers::{Fetch, LocalFetcher, UnsplashFetcher};
+/// Most images Unsplash returns in one page.
+const MAX_LIMIT: u32 = 30;
 
 /// Information needed by the engine to know what and how to run.
 #[derive(Debug)]
 pub struct Context {
-    /// Local directory path to find user wallpapers.
-    dir: String,
+    /// Local directory paths to find user wallpapers.
+    dirs: Vec<PathBuf>,
+    /// Most files to consider from the local directories.
+    max_files: usize,
+    /// Extra file extensions to recognize local images by.
+    extensions: Vec<String>,
+    /// Whether to consider hidden files in the local directories.
+    hidden: bool,
+    /// Whether to also search the subdirectories of the local directories.
+    recursive: bool,
+    /// Longest time to reuse the listing of the local directories while they appear unchanged.
+    rescan: Duration,
+    /// Order to show local images in.
+    local_order: ShuffleMode,
     /// Unsplash API Client token.
     token: String,
     /// Number of images to cache, max 30.
     limit: u32,
+    /// Region to prefer photos of, if any.
+    region: Option<String>,
+    /// Whether to cache the latest Unsplash photos or random ones.
+    unsplash_mode: UnsplashMode,
+    /// Search query to pick Unsplash photos by, instead of the latest ones.
+    query: Option<String>,
+    /// Unsplash collections to get photos from, instead of all the latest ones.
+    collections: Vec<u64>,
+    /// Shape of the Unsplash photos to cache, if any in particular.
+    orientation: Option<Orientation>,
+    /// Resolution to resize Unsplash photos to fit within, or `None` to download the originals.
+    resolution: Option<Resolution>,
+    /// Whether to detect the screen resolution while starting up, when none was given.
+    detect_resolution: bool,
     /// Seconds timeout before displaying next wallpaper.
     timeout: Duration,
     /// Seconds timeout before refreshing Unsplash images.
     refresh: Duration,
+    /// Longest the Unsplash refresh may be stretched to when no new images appear.
+    max_refresh: Duration,
+    /// Age after which cached Unsplash images are removed, if limited.
+    max_image_age: Option<Duration>,
+    /// Order to show cached Unsplash images in.
+    unsplash_order: ShuffleMode,
+    /// Strategy for choosing between the image sources.
+    order: Order,
+    /// User and group to switch to when started as root.
+    run_as: Option<String>,
+    /// What to do when a rotation comes due while the session is locked, if checking at all.
+    on_locked: Option<OnLocked>,
+    /// Most bytes to download per calendar month, if limited.
+    budget: Option<u64>,
+    /// Setter that puts the chosen images on the desktop.
+    setter: Box<WallpaperSetter>,
+    /// What to do when there is no display to set the wallpaper on.
+    headless: Headless,
+    /// Condition that holds rotations while true, if any.
+    hold_while: Option<Hold>,
+    /// Images and directories of images never to show.
+    blacklist: Vec<PathBuf>,
+    /// Smallest image to show, if limited.
+    min_resolution: Option<Resolution>,
 }
 
 impl Context {
-    pub fn new(dir: &str, token: &str, limit: u32, timeout: Duration, refresh: Duration) -> Context {
-        Context {
-            dir: dir.to_owned(),
-            token: token.to_owned(),
-            limit: limit,
-            timeout: timeout,
-            refresh: refresh,
+    /// Create a context for a single local directory, with every other setting left at its
+    /// default. Use `ContextBuilder` to change the rest.
+    pub fn new(
+        dir: &str,
+        token: &str,
+        limit: u32,
+        timeout: Duration,
+        refresh: Duration,
+    ) -> Result<Context, WallsplashError> {
+        ContextBuilder::new()
+            .dir(dir)
+            .token(token)
+            .limit(limit)
+            .timeout(timeout)
+            .refresh(refresh)
+            .build()
+    }
+}
+
+/// Builds a `Context` one named setting at a time. Settings that are not given keep the same
+/// defaults as the command line, except that at least one local directory must be given.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use wallsplash::ContextBuilder;
+///
+/// let ctx = ContextBuilder::new()
+///     .dir("/home/me/Pictures")
+///     .token("YOUR_TOKEN_HERE")
+///     .limit(20)
+///     .timeout(Duration::from_secs(15 * 60))
+///     .refresh(Duration::from_secs(12 * 60 * 60))
+///     .build()
+///     .unwrap();
+/// wallsplash::run(&ctx).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ContextBuilder {
+    ctx: Context,
+}
+
+impl Default for ContextBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContextBuilder {
+    pub fn new() -> ContextBuilder {
+        ContextBuilder {
+            ctx: Context {
+                dirs: Vec::new(),
+                max_files: 20_000,
+                extensions: Vec::new(),
+                hidden: false,
+                recursive: false,
+                rescan: Duration::from_secs(60 * 60),
+                local_order: ShuffleMode::Sequential,
+                token: String::new(),
+                limit: 10,
+                region: None,
+                unsplash_mode: UnsplashMode::Latest,
+                query: None,
+                collections: Vec::new(),
+                orientation: None,
+                resolution: None,
+                detect_resolution: false,
+                timeout: Duration::from_secs(30 * 60),
+                refresh: Duration::from_secs(24 * 60 * 60),
+                max_refresh: Duration::from_secs(7 * 24 * 60 * 60),
+                max_image_age: None,
+                unsplash_order: ShuffleMode::Sequential,
+                order: Order::Alternate,
+                run_as: None,
+                on_locked: None,
+                budget: None,
+                setter: Box::new(FehSetter::default()),
+                headless: Headless::Fail,
+                hold_while: None,
+                blacklist: Vec::new(),
+                min_resolution: None,
+            },
         }
     }
+
+    /// Add a local directory to find wallpapers in.
+    pub fn dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.ctx.dirs.push(dir.into());
+        self
+    }
+
+    /// Most files to consider from the local directories.
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.ctx.max_files = max_files;
+        self
+    }
+
+    /// Extra file extensions to recognize local images by.
+    pub fn extensions(mut self, extensions: Vec<String>) -> Self {
+        self.ctx.extensions = extensions;
+        self
+    }
+
+    /// Whether to consider hidden files in the local directories.
+    pub fn hidden(mut self, hidden: bool) -> Self {
+        self.ctx.hidden = hidden;
+        self
+    }
+
+    /// Whether to also search the subdirectories of the local directories.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.ctx.recursive = recursive;
+        self
+    }
+
+    /// Longest time to reuse the listing of the local directories while they appear unchanged.
+    pub fn rescan(mut self, rescan: Duration) -> Self {
+        self.ctx.rescan = rescan;
+        self
+    }
+
+    /// Order to show local images in.
+    pub fn local_order(mut self, order: ShuffleMode) -> Self {
+        self.ctx.local_order = order;
+        self
+    }
+
+    /// Unsplash API Client token.
+    pub fn token(mut self, token: &str) -> Self {
+        self.ctx.token = token.to_owned();
+        self
+    }
+
+    /// Number of images to cache, max 30.
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.ctx.limit = limit;
+        self
+    }
+
+    /// Region to prefer photos of, such as a country or an area like `Pacific Northwest`.
+    pub fn region(mut self, region: Option<String>) -> Self {
+        self.ctx.region = region;
+        self
+    }
+
+    /// Whether to cache the latest Unsplash photos or random ones.
+    pub fn unsplash_mode(mut self, mode: UnsplashMode) -> Self {
+        self.ctx.unsplash_mode = mode;
+        self
+    }
+
+    /// Search query to pick Unsplash photos by, instead of the latest ones.
+    pub fn query(mut self, query: Option<String>) -> Self {
+        self.ctx.query = query;
+        self
+    }
+
+    /// Unsplash collections to get photos from, instead of all the latest ones.
+    pub fn collections(mut self, collections: Vec<u64>) -> Self {
+        self.ctx.collections = collections;
+        self
+    }
+
+    /// Shape of the Unsplash photos to cache, if any in particular.
+    pub fn orientation(mut self, orientation: Option<Orientation>) -> Self {
+        self.ctx.orientation = orientation;
+        self
+    }
+
+    /// Resolution to resize Unsplash photos to fit within, or `None` to download the originals.
+    pub fn resolution(mut self, resolution: Option<Resolution>) -> Self {
+        self.ctx.resolution = resolution;
+        self
+    }
+
+    /// Whether to detect the screen resolution while starting up, when none was given. Done on
+    /// a background thread, so it never delays the first wallpaper.
+    pub fn detect_resolution(mut self, detect: bool) -> Self {
+        self.ctx.detect_resolution = detect;
+        self
+    }
+
+    /// Time before displaying the next wallpaper.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.ctx.timeout = timeout;
+        self
+    }
+
+    /// Time before refreshing Unsplash images.
+    pub fn refresh(mut self, refresh: Duration) -> Self {
+        self.ctx.refresh = refresh;
+        self
+    }
+
+    /// Longest the Unsplash refresh may be stretched to when no new images appear.
+    pub fn max_refresh(mut self, max_refresh: Duration) -> Self {
+        self.ctx.max_refresh = max_refresh;
+        self
+    }
+
+    /// Age after which cached Unsplash images are removed, if limited.
+    pub fn max_image_age(mut self, max_image_age: Option<Duration>) -> Self {
+        self.ctx.max_image_age = max_image_age;
+        self
+    }
+
+    /// Order to show cached Unsplash images in.
+    pub fn unsplash_order(mut self, order: ShuffleMode) -> Self {
+        self.ctx.unsplash_order = order;
+        self
+    }
+
+    /// Strategy for choosing between the image sources.
+    pub fn order(mut self, order: Order) -> Self {
+        self.ctx.order = order;
+        self
+    }
+
+    /// User and group to switch to when started as root.
+    pub fn run_as(mut self, run_as: Option<String>) -> Self {
+        self.ctx.run_as = run_as;
+        self
+    }
+
+    /// What to do when a rotation comes due while the session is locked, if checking at all.
+    pub fn on_locked(mut self, on_locked: Option<OnLocked>) -> Self {
+        self.ctx.on_locked = on_locked;
+        self
+    }
+
+    /// Most bytes to download per calendar month, if limited.
+    pub fn budget(mut self, budget: Option<u64>) -> Self {
+        self.ctx.budget = budget;
+        self
+    }
+
+    /// Setter that puts the chosen images on the desktop.
+    pub fn setter(mut self, setter: Box<WallpaperSetter>) -> Self {
+        self.ctx.setter = setter;
+        self
+    }
+
+    /// What to do when there is no display to set the wallpaper on.
+    pub fn headless(mut self, headless: Headless) -> Self {
+        self.ctx.headless = headless;
+        self
+    }
+
+    /// Condition that holds rotations while true, if any.
+    pub fn hold_while(mut self, hold: Option<Hold>) -> Self {
+        self.ctx.hold_while = hold;
+        self
+    }
+
+    /// Images and directories of images never to show.
+    pub fn blacklist(mut self, blacklist: Vec<PathBuf>) -> Self {
+        self.ctx.blacklist = blacklist;
+        self
+    }
+
+    /// Smallest image to show, from any source, if limited.
+    pub fn min_resolution(mut self, min: Option<Resolution>) -> Self {
+        self.ctx.min_resolution = min;
+        self
+    }
+
+    /// Check the settings and create the context.
+    ///
+    /// # Errors
+    ///
+    /// `InvalidLimit` when the limit is zero or over 30, and `NoLocalDir` when no local directory
+    /// was given or one of them is empty.
+    pub fn build(self) -> Result<Context, WallsplashError> {
+        if self.ctx.limit == 0 || self.ctx.limit > MAX_LIMIT {
+            return Err(WallsplashError::InvalidLimit);
+        }
+        if self.ctx.dirs.is_empty() || self.ctx.dirs.iter().any(|d| d.as_os_str().is_empty()) {
+            return Err(WallsplashError::NoLocalDir);
+        }
+        Ok(self.ctx)
+    }
 }
 
-/// Execute the never-ending engine!
+/// Switch to the `run_as` user of the context when running as root. Returns whether privileges
+/// were dropped. `run` does this itself, but calling it first lets files opened before `run`,
+/// such as a log file, belong to that user.
+pub fn drop_privileges(ctx: &Context) -> Result<bool, Box<Error>> {
+    match ctx.run_as {
+        Some(ref spec) if privileges::is_root() => privileges::drop_to(spec).map(|_| true),
+        Some(ref spec) => {
+            debug!("not running as root, ignoring run_as {}", spec);
+            Ok(false)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Execute the never-ending engine! Only returns with an error while starting up; failures to
+/// get or set a wallpaper are logged and the next rotation goes ahead.
 pub fn run(ctx: &Context) -> Result<(), Box<Error>> {
     debug!("{:?}\n", ctx);
+    let started = Instant::now();
 
-    let mut unsplash = UnsplashFetcher::new(ctx.token.as_str(), ctx.limit, ctx.refresh)?;
-    let mut local = LocalFetcher::new(ctx.dir.as_str());
+    if drop_privileges(ctx)? {
+        info!("startup: privileges dropped after {}ms", millis(started));
+    }
 
-    let mut do_local = true;
+    let engine = Engine::new(ctx)?;
+    info!("startup: engine ready after {}ms", millis(started));
+    let mut rotator = Rotator::new(ctx, engine, Box::new(SessionLockProbe), started);
 
+    // Without a wallpaper yet, the first rotation follows right away.
+    let warm = rotator.warm_start();
+    if warm == Rotated::Set || warm == Rotated::Held {
+        thread::sleep(pause_after(ctx, warm));
+    }
     loop {
-        let path = if do_local {
-            local.next_image_path()
-        } else {
-            unsplash.next_image_path()
+        let rotated = rotator.rotate();
+        thread::sleep(pause_after(ctx, rotated));
+    }
+}
+
+/// Time to wait before the next rotation. While held, the hold is checked again every so often
+/// so that rotations resume soon after it is released. A headless rotation has already waited.
+fn pause_after(ctx: &Context, rotated: Rotated) -> Duration {
+    match rotated {
+        Rotated::Held => cmp::min(ctx.timeout, Duration::from_secs(HOLD_POLL_SECS)),
+        Rotated::Headless => Duration::from_secs(0),
+        _ => ctx.timeout,
+    }
+}
+
+/// What became of a rotation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rotated {
+    /// The next image was set.
+    Set,
+    /// Rotations are held, so nothing was done.
+    Held,
+    /// The session is locked, so the rotation was dropped.
+    Skipped,
+    /// No image could be found or set.
+    Failed,
+    /// There was no display, and the rest of the rotation went by waiting for one.
+    Headless,
+}
+
+/// Performs the rotations of `run` one at a time, remembering what it has logged about them.
+struct Rotator<'a> {
+    /// Settings to rotate by.
+    ctx: &'a Context,
+    /// Engine choosing the images.
+    engine: Engine,
+    /// Probe for whether the session is locked.
+    probe: Box<LockProbe>,
+    /// Time between session lock checks while a rotation is deferred.
+    lock_poll: Duration,
+    /// Time when wallsplash started.
+    started: Instant,
+    /// Whether no rotation has happened yet.
+    first: bool,
+    /// Whether the last image could not be set for lack of a display.
+    headless: bool,
+    /// Whether rotations are held.
+    held: bool,
+}
+
+impl<'a> Rotator<'a> {
+    fn new(ctx: &'a Context, engine: Engine, probe: Box<LockProbe>, started: Instant) -> Self {
+        Rotator {
+            ctx: ctx,
+            engine: engine,
+            probe: probe,
+            lock_poll: Duration::from_secs(LOCK_POLL_SECS),
+            started: started,
+            first: true,
+            headless: false,
+            held: false,
+        }
+    }
+
+    /// Put up the first wallpaper without waiting for any source to start up: the wallpaper
+    /// already on screen when the filters accept it, or else the first image at the top of the
+    /// local directories that they accept. Nothing is set when neither is found, and the first
+    /// rotation goes ahead as usual.
+    fn warm_start(&mut self) -> Rotated {
+        if let Some(rotated) = self.gate() {
+            return rotated;
+        }
+
+        let ctx = self.ctx;
+        let current = ctx.setter.current().map(|path| ImageInfo {
+            path: path,
+            source: "current".to_owned(),
+        });
+        let info = match current.filter(|info| self.engine.accepts(info)) {
+            Some(info) => info,
+            None => match self.first_local_image() {
+                Some(info) => info,
+                None => return Rotated::Failed,
+            },
         };
 
-        match path {
-            Ok(path) => {
-                Command::new("feh").arg("--bg-fill").arg(path).output()?;
+        match ctx.setter.set(&info.path) {
+            Ok(_) => {
+                info!(
+                    "startup: first wallpaper from {} set after {}ms",
+                    info.source,
+                    millis(self.started)
+                );
+                self.engine.shown(&info.path);
+                self.first = false;
+                Rotated::Set
             }
+            Err(e) => {
+                debug!("cannot set the first wallpaper early: {}", e);
+                Rotated::Failed
+            }
+        }
+    }
+
+    /// The first image at the top of the local directories that the filters accept, without
+    /// scanning any deeper.
+    fn first_local_image(&self) -> Option<ImageInfo> {
+        let ctx = self.ctx;
+        for dir in &ctx.dirs {
+            let images = match fetchers::list_images(dir, &ctx.extensions, ctx.hidden, false) {
+                Ok(images) => images,
+                Err(_) => continue,
+            };
+            let found = images
+                .into_iter()
+                .map(|path| ImageInfo {
+                    path: path,
+                    source: "local".to_owned(),
+                })
+                .find(|info| self.engine.accepts(info));
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    /// Check whether rotations are held or the session is locked, returning what became of the
+    /// rotation when it cannot go ahead.
+    fn gate(&mut self) -> Option<Rotated> {
+        let ctx = self.ctx;
+        if let Some(ref hold) = ctx.hold_while {
+            let held = is_held(hold);
+            if held != self.held {
+                if held {
+                    info!("holding rotations while {:?}", hold);
+                } else {
+                    info!("hold released, resuming rotations");
+                }
+                self.held = held;
+            }
+            if held {
+                return Some(Rotated::Held);
+            }
+        }
+
+        if let Some(mode) = ctx.on_locked {
+            if !ready_to_rotate(&*self.probe, mode, self.lock_poll) {
+                return Some(Rotated::Skipped);
+            }
+        }
+        None
+    }
+
+    /// Perform the rotation that has come due. Failures are logged rather than returned, since
+    /// the next rotation goes ahead regardless.
+    fn rotate(&mut self) -> Rotated {
+        if let Some(rotated) = self.gate() {
+            return rotated;
+        }
+
+        let ctx = self.ctx;
+        let rotated = match self.engine.next_image().and_then(|i| self.engine.recheck(i)) {
+            Ok(info) => match ctx.setter.set(&info.path) {
+                Ok(_) => {
+                    if self.headless {
+                        info!("display available again");
+                        self.headless = false;
+                    }
+                    if self.first {
+                        info!(
+                            "startup: first wallpaper from {} set after {}ms",
+                            info.source,
+                            millis(self.started)
+                        );
+                    }
+                    Rotated::Set
+                }
+                Err(e) => {
+                    let no_display = matches!(
+                        e.downcast_ref::<WallsplashError>(),
+                        Some(&WallsplashError::NoDisplay)
+                    );
+
+                    if no_display && ctx.headless == Headless::Continue {
+                        if !self.headless {
+                            warn!("no display, rotating headless until one appears");
+                        }
+                        self.headless = !wait_for_display(ctx, &info.path);
+                        Rotated::Headless
+                    } else {
+                        error!("{}", e);
+                        Rotated::Failed
+                    }
+                }
+            },
             Err(e) => {
                 error!("{}", e);
+                Rotated::Failed
+            }
+        };
+
+        self.first = false;
+        rotated
+    }
+}
+
+/// Whether rotations should be held, assuming they should not when the probe fails.
+fn is_held(probe: &HoldProbe) -> bool {
+    probe.is_held().unwrap_or_else(|e| {
+        warn!("{}", e);
+        false
+    })
+}
+
+/// Wait out a rotation while there is no display, trying to set the image again every so often
+/// so that it shows up as soon as a display does. Returns whether the image was set.
+fn wait_for_display(ctx: &Context, path: &Path) -> bool {
+    let start = Instant::now();
+    let poll = Duration::from_secs(DISPLAY_POLL_SECS);
+
+    while start.elapsed() + poll < ctx.timeout {
+        thread::sleep(poll);
+        if ctx.setter.set(path).is_ok() {
+            info!("display available again, wallpaper set");
+            thread::sleep(ctx.timeout.checked_sub(start.elapsed()).unwrap_or_default());
+            return true;
+        }
+    }
+
+    thread::sleep(ctx.timeout.checked_sub(start.elapsed()).unwrap_or_default());
+    false
+}
+
+/// Milliseconds elapsed since the given time.
+fn millis(since: Instant) -> u64 {
+    let elapsed = since.elapsed();
+    elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+}
+
+/// Check the session lock state before a rotation. Returns false when the rotation should be
+/// skipped. When deferring, blocks until the session is unlocked, checking every `poll`, so that
+/// any number of missed rotations result in a single change.
+fn ready_to_rotate(probe: &LockProbe, mode: OnLocked, poll: Duration) -> bool {
+    if !is_locked(probe) {
+        return true;
+    }
+
+    match mode {
+        OnLocked::Skip => {
+            debug!("session locked, skipping rotation");
+            false
+        }
+        OnLocked::Defer => {
+            debug!("session locked, deferring rotation until unlock");
+            while is_locked(probe) {
+                thread::sleep(poll);
             }
+            true
         }
+    }
+}
+
+/// Whether the session is locked, assuming it is not when the state cannot be determined.
+fn is_locked(probe: &LockProbe) -> bool {
+    probe.is_locked().unwrap_or_else(|e| {
+        debug!("{}", e);
+        false
+    })
+}
 
-        do_local = !do_local;
-        thread::sleep(ctx.timeout);
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::rc::Rc;
+
+    use super::*;
+    use fetchers::LocalFetcher;
+    use testing::{self, MockFetcher, MockSetter};
+
+    fn build(limit: u32) -> Result<Context, WallsplashError> {
+        Context::new(
+            "/tmp",
+            "token",
+            limit,
+            Duration::from_secs(60),
+            Duration::from_secs(60 * 60),
+        )
+    }
+
+    #[test]
+    fn limit_up_to_thirty_is_accepted() {
+        assert_eq!(build(1).unwrap().limit, 1);
+        assert_eq!(build(30).unwrap().limit, 30);
+    }
+
+    #[test]
+    fn limit_over_thirty_or_zero_is_rejected() {
+        for &limit in &[0, 31, 100] {
+            match build(limit) {
+                Err(WallsplashError::InvalidLimit) => {}
+                other => panic!("limit {}: {:?}", limit, other.map(|c| c.limit)),
+            }
+        }
+    }
+
+    #[test]
+    fn new_keeps_builder_defaults() {
+        let ctx = build(10).unwrap();
+        let defaults = ContextBuilder::new().dir("/tmp").build().unwrap();
+        assert_eq!(ctx.dirs, vec![PathBuf::from("/tmp")]);
+        assert_eq!(ctx.timeout, Duration::from_secs(60));
+        assert_eq!(ctx.refresh, Duration::from_secs(60 * 60));
+        assert_eq!(ctx.max_refresh, defaults.max_refresh);
+        assert_eq!(ctx.max_files, defaults.max_files);
+    }
+
+    #[test]
+    fn builder_needs_a_local_dir() {
+        for builder in [ContextBuilder::new(), ContextBuilder::new().dir("")] {
+            match builder.build() {
+                Err(WallsplashError::NoLocalDir) => {}
+                other => panic!("{:?}", other.map(|c| c.dirs)),
+            }
+        }
+    }
+
+    /// Lock probe that plays back a script of lock states, `None` being a failed check, and
+    /// reports the session unlocked once the script runs out.
+    struct ScriptedProbe(Rc<RefCell<VecDeque<Option<bool>>>>);
+
+    impl LockProbe for ScriptedProbe {
+        fn is_locked(&self) -> Result<bool, Box<Error>> {
+            match self.0.borrow_mut().pop_front() {
+                Some(Some(locked)) => Ok(locked),
+                Some(None) => Err(Box::new(WallsplashError::LockStateUnknown)),
+                None => Ok(false),
+            }
+        }
+    }
+
+    /// Set up rotations between two images of a scratch directory with the given lock behavior,
+    /// and hand the rotator, the images, the script of lock states and the setter to `check`.
+    fn with_rotator<F>(name: &str, on_locked: OnLocked, check: F)
+    where
+        F: FnOnce(&mut Rotator, &[PathBuf], &RefCell<VecDeque<Option<bool>>>, &MockSetter),
+    {
+        let dir = env::temp_dir().join(format!("wallsplash-lib-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let images = vec![dir.join("a.png"), dir.join("b.png")];
+        for image in &images {
+            testing::write_png(image, 1, 1).unwrap();
+        }
+
+        let setter = MockSetter::new();
+        let ctx = ContextBuilder::new()
+            .dir(&dir)
+            .on_locked(Some(on_locked))
+            .setter(Box::new(setter.clone()))
+            .build()
+            .unwrap();
+        let sources: Vec<Box<Fetch>> = vec![Box::new(MockFetcher::new("local", images.clone()))];
+        let engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+        let script = Rc::new(RefCell::new(VecDeque::new()));
+        let probe = Box::new(ScriptedProbe(script.clone()));
+        let mut rotator = Rotator::new(&ctx, engine, probe, Instant::now());
+        rotator.lock_poll = Duration::from_millis(1);
+
+        check(&mut rotator, &images, &script, &setter);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Filter that passes every image but deletes the given one once it is chosen, as if it went
+    /// away between the engine's checks and the setter.
+    struct DeleteWhenChosen(PathBuf);
+
+    impl Filter for DeleteWhenChosen {
+        fn name(&self) -> &str {
+            "delete-when-chosen"
+        }
+
+        fn accept(&self, info: &ImageInfo, _: &FilterCtx) -> Verdict {
+            if info.path == self.0 {
+                fs::remove_file(&self.0).unwrap();
+            }
+            Verdict::Accept
+        }
+    }
+
+    #[test]
+    fn image_deleted_before_the_set_is_replaced() {
+        with_rotator("deleted", OnLocked::Skip, |rotator, images, _, setter| {
+            rotator.engine.add_filter(Box::new(DeleteWhenChosen(images[0].clone())));
+            assert_eq!(rotator.rotate(), Rotated::Set);
+            assert_eq!(setter.history(), &images[1..]);
+
+            let stats = &rotator.engine.filter_stats()[0];
+            assert_eq!(stats.name, "valid-image");
+            assert_eq!((stats.rejected, stats.recovered), (1, 1));
+        });
+    }
+
+    #[test]
+    fn skip_drops_rotations_while_locked() {
+        with_rotator("skip", OnLocked::Skip, |rotator, images, script, setter| {
+            script.borrow_mut().extend(vec![Some(true), Some(true)]);
+            assert_eq!(rotator.rotate(), Rotated::Skipped);
+            assert_eq!(rotator.rotate(), Rotated::Skipped);
+            assert!(setter.history().is_empty());
+
+            // Skipped rotations do not advance the engine either.
+            assert_eq!(rotator.rotate(), Rotated::Set);
+            assert_eq!(setter.history(), &images[..1]);
+        });
+    }
+
+    #[test]
+    fn defer_coalesces_missed_rotations() {
+        with_rotator("defer", OnLocked::Defer, |rotator, images, script, setter| {
+            script.borrow_mut().extend(vec![Some(true); 5]);
+            assert_eq!(rotator.rotate(), Rotated::Set);
+            assert!(script.borrow().is_empty());
+            assert_eq!(setter.history(), &images[..1]);
+
+            assert_eq!(rotator.rotate(), Rotated::Set);
+            assert_eq!(setter.history(), images);
+        });
+    }
+
+    #[test]
+    fn held_rotations_do_nothing_and_poll_sooner() {
+        let dir = env::temp_dir().join(format!("wallsplash-lib-hold-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("a.png");
+        testing::write_png(&image, 1, 1).unwrap();
+        let flag = dir.join("dnd");
+        fs::write(&flag, b"").unwrap();
+
+        let setter = MockSetter::new();
+        let ctx = ContextBuilder::new()
+            .dir(&dir)
+            .timeout(Duration::from_secs(15 * 60))
+            .hold_while(Some(Hold::File(flag.clone())))
+            .setter(Box::new(setter.clone()))
+            .build()
+            .unwrap();
+        let sources: Vec<Box<Fetch>> = vec![Box::new(MockFetcher::new("local", vec![image]))];
+        let engine = Engine::with_sources(sources, &Order::Alternate).unwrap();
+        let probe = Box::new(ScriptedProbe(Rc::new(RefCell::new(VecDeque::new()))));
+        let mut rotator = Rotator::new(&ctx, engine, probe, Instant::now());
+
+        assert_eq!(rotator.warm_start(), Rotated::Held);
+        assert_eq!(rotator.rotate(), Rotated::Held);
+        assert!(setter.history().is_empty());
+        assert_eq!(pause_after(&ctx, Rotated::Held), Duration::from_secs(HOLD_POLL_SECS));
+        assert_eq!(pause_after(&ctx, Rotated::Set), ctx.timeout);
+        assert_eq!(pause_after(&ctx, Rotated::Headless), Duration::from_secs(0));
+
+        fs::remove_file(&flag).unwrap();
+        assert_eq!(rotator.rotate(), Rotated::Set);
+        assert_eq!(setter.history().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Set up a rotator preferring an Unsplash stand-in that takes `delay` to start up over the
+    /// images of a scratch directory, and hand it the local images, the Unsplash image and the
+    /// setter.
+    fn with_slow_unsplash<F>(name: &str, delay: Duration, check: F)
+    where
+        F: FnOnce(&mut Rotator, &[PathBuf], &Path, &MockSetter),
+    {
+        let dir = env::temp_dir().join(format!("wallsplash-lib-{}-{}", name, process::id()));
+        let cache = dir.join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let images = vec![dir.join("a.png"), dir.join("b.png")];
+        let photo = cache.join("photo.png");
+        for image in images.iter().chain(Some(&photo)) {
+            testing::write_png(image, 1, 1).unwrap();
+        }
+
+        let setter = MockSetter::new();
+        let ctx = ContextBuilder::new()
+            .dir(&dir)
+            .setter(Box::new(setter.clone()))
+            .build()
+            .unwrap();
+        let local = LocalFetcher::new(
+            &ctx.dirs,
+            ctx.max_files,
+            &[],
+            false,
+            false,
+            ctx.rescan,
+            ShuffleMode::Sequential,
+        );
+        let cached = photo.clone();
+        let unsplash = Warming::start("unsplash", move || -> Box<Fetch + Send> {
+            thread::sleep(delay);
+            Box::new(MockFetcher::new("unsplash", vec![cached]))
+        });
+        let sources: Vec<Box<Fetch>> = vec![Box::new(local), Box::new(unsplash)];
+        let order = Order::Priority(vec!["unsplash".to_owned(), "local".to_owned()]);
+        let engine = Engine::with_sources(sources, &order).unwrap();
+        let probe = Box::new(ScriptedProbe(Rc::new(RefCell::new(VecDeque::new()))));
+        let mut rotator = Rotator::new(&ctx, engine, probe, Instant::now());
+
+        check(&mut rotator, &images, &photo, &setter);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn warm_start_does_not_wait_for_a_slow_source() {
+        let delay = Duration::from_millis(500);
+        with_slow_unsplash("warm", delay, |rotator, images, photo, setter| {
+            let start = Instant::now();
+            assert_eq!(rotator.warm_start(), Rotated::Set);
+            assert_eq!(setter.history(), &images[..1]);
+
+            // The local images fill in until Unsplash is ready, carrying on after the first.
+            assert_eq!(rotator.rotate(), Rotated::Set);
+            assert_eq!(setter.history(), images);
+            assert!(start.elapsed() < delay, "took {:?}", start.elapsed());
+
+            // Once it is ready, Unsplash takes over. Allow for a busy machine.
+            thread::sleep(delay);
+            let deadline = Instant::now() + Duration::from_secs(10);
+            loop {
+                assert_eq!(rotator.rotate(), Rotated::Set);
+                if setter.history().last() == Some(&photo.to_path_buf()) {
+                    break;
+                }
+                assert!(Instant::now() < deadline, "unsplash never became ready");
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+    }
+
+    #[test]
+    fn warm_start_keeps_the_current_wallpaper() {
+        let delay = Duration::from_millis(50);
+        with_slow_unsplash("current", delay, |rotator, images, _, setter| {
+            setter.set(&images[1]).unwrap();
+            assert_eq!(rotator.warm_start(), Rotated::Set);
+            assert_eq!(setter.history(), vec![images[1].clone(); 2]);
+        });
+    }
+
+    #[test]
+    fn unknown_lock_state_counts_as_unlocked() {
+        for &mode in &[OnLocked::Skip, OnLocked::Defer] {
+            with_rotator("unknown", mode, |rotator, images, script, setter| {
+                script.borrow_mut().push_back(None);
+                assert_eq!(rotator.rotate(), Rotated::Set);
+                assert_eq!(setter.history(), &images[..1]);
+            });
+        }
     }
 }
@@ -1,3 +1,5 @@
+#![allow(bare_trait_objects, clippy::redundant_field_names)]
+
 #[macro_use]
 extern crate log;
 extern crate clap;
@@ -7,32 +9,223 @@ extern crate serde_derive;
 extern crate toml;
 extern crate wallsplash;
 
+use std::env;
 use std::error::Error;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
+
+use log::LogLevelFilter;
+use wallsplash::ErrorClass;
 
 type ResBoxErr<T> = Result<T, Box<Error>>;
 
+/// Exit status when verify-local found images that failed.
+const EXIT_FAILED_IMAGES: i32 = 1;
+
+/// Exit status when the arguments or configuration are invalid. Restarting will not help.
+const EXIT_CONFIG: i32 = 2;
+
+/// Exit status when the system is missing something needed to run.
+const EXIT_ENVIRONMENT: i32 = 3;
+
+/// Exit status when running failed in a way that may not happen again.
+const EXIT_RUNTIME: i32 = 4;
+
+/// Every exit status and its meaning, for `--print-exit-codes`.
+const EXIT_CODES: &[(i32, &str)] = &[
+    (0, "success"),
+    (EXIT_FAILED_IMAGES, "verify-local found images that failed"),
+    (EXIT_CONFIG, "invalid arguments or configuration"),
+    (
+        EXIT_ENVIRONMENT,
+        "environment error, e.g. cache directory not writable or setter not found",
+    ),
+    (EXIT_RUNTIME, "runtime failure"),
+];
+
 fn main() {
-    env_logger::init().unwrap();
+    let logs = logging::init();
 
-    let args = match args::Args::parse() {
+    let matches = match cli::build_app().get_matches_safe() {
+        Ok(m) => m,
+        // Help and version are printed to stdout with a success status.
+        Err(ref e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            process::exit(EXIT_CONFIG);
+        }
+        Err(e) => e.exit(),
+    };
+    if matches.is_present("print-exit-codes") {
+        for &(code, meaning) in EXIT_CODES {
+            println!("{}\t{}", code, meaning);
+        }
+        process::exit(0);
+    }
+
+    let args = match args::Args::parse(matches) {
         Ok(a) => a,
         Err(e) => {
             error!("{}", e);
-            process::exit(1);
+            process::exit(EXIT_CONFIG);
+        }
+    };
+    logs.set_journal(args.log_journal_level);
+    let log_file = args.log_file.clone();
+    if !matches!(args.command, args::Command::Run) {
+        open_log_file(&logs, log_file.clone());
+    }
+
+    let status = match args.command {
+        args::Command::Run => {
+            let ctx = match args.into_context() {
+                Ok(ctx) => ctx,
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(EXIT_CONFIG);
+                }
+            };
+            // The log file is opened as the user wallsplash runs as, in their home.
+            if let Err(err) = wallsplash::drop_privileges(&ctx) {
+                error!("{}", err);
+                process::exit(exit_status(&*err));
+            }
+            open_log_file(&logs, log_file);
+            match wallsplash::run(&ctx) {
+                Ok(_) => 0,
+                Err(err) => {
+                    error!("{}", err);
+                    exit_status(&*err)
+                }
+            }
+        }
+        args::Command::VerifyLocal { fix } => {
+            let many = args.local_dirs.len() > 1;
+            args.local_dirs.iter().fold(0, |status, dir| {
+                if many {
+                    println!("{}", dir.display());
+                }
+                match verify_local(&args, dir, fix) {
+                    0 => status,
+                    failed => failed,
+                }
+            })
+        }
+        args::Command::Status => print_status(args.budget),
+        args::Command::Slideshow { shuffle } => {
+            let interval = Duration::from_secs(args.timeout as u64);
+            match wallsplash::slideshow(&args.local_dirs[0], interval, shuffle, &*args.setter) {
+                Ok(_) => 0,
+                Err(err) => {
+                    error!("{}", err);
+                    exit_status(&*err)
+                }
+            }
         }
     };
 
-    let ctx = args.into_context();
-    let status = match wallsplash::run(&ctx) {
-        Ok(_) => 0,
+    process::exit(status);
+}
+
+/// Start writing to the debug file, if configured, or exit when it cannot be opened. A path
+/// starting with `~/` is taken to be in the current home directory.
+fn open_log_file(logs: &logging::Handle, file: Option<(PathBuf, LogLevelFilter)>) {
+    let file = file.map(|(path, level)| match path.strip_prefix("~") {
+        Ok(rest) => (env::home_dir().unwrap().join(rest), level),
+        Err(_) => (path, level),
+    });
+    if let Err(e) = logs.open_file(file) {
+        error!("cannot open log file: {}", e);
+        process::exit(EXIT_ENVIRONMENT);
+    }
+}
+
+/// Map an error to the exit status for its class.
+fn exit_status(err: &(Error + 'static)) -> i32 {
+    match wallsplash::classify(err) {
+        ErrorClass::Config => EXIT_CONFIG,
+        ErrorClass::Environment => EXIT_ENVIRONMENT,
+        ErrorClass::Runtime => EXIT_RUNTIME,
+    }
+}
+
+/// Print this month's downloads against the budget and the state of the Unsplash cache.
+fn print_status(budget: Option<u64>) -> i32 {
+    let status = wallsplash::status(budget);
+
+    match status.budget {
+        Some(b) => println!(
+            "downloaded {} of {} budget in {}",
+            megabytes(status.total()),
+            megabytes(b),
+            status.month
+        ),
+        None => println!("downloaded {} in {}", megabytes(status.total()), status.month),
+    }
+    for &(ref source, bytes) in &status.downloaded {
+        println!("    {}: {}", source, megabytes(bytes));
+    }
+    if status.suspended() {
+        println!("downloads suspended until next month");
+    }
+
+    match status.refreshed {
+        Some(age) => println!(
+            "unsplash cache: {} images, refreshed {}s ago",
+            status.cached,
+            age.as_secs()
+        ),
+        None => println!("unsplash cache: {} images, never refreshed", status.cached),
+    }
+    0
+}
+
+/// Format a byte count in megabytes for people to read.
+fn megabytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// Check every local wallpaper and print a report grouped by problem. Returns the exit status,
+/// which is non-zero when any image failed.
+fn verify_local(args: &args::Args, dir: &Path, fix: bool) -> i32 {
+    let report = wallsplash::verify_local(
+        dir,
+        &args.local_extensions,
+        args.local_hidden,
+        args.local_recursive,
+        fix,
+    );
+    let report = match report {
+        Ok(r) => r,
         Err(err) => {
             error!("{}", err);
-            2
+            return exit_status(&*err);
         }
     };
 
-    process::exit(status);
+    let mut last = None;
+    for &(problem, ref path) in &report.failures {
+        if last != Some(problem) {
+            println!("{}:", problem);
+            last = Some(problem);
+        }
+        println!("    {}", path.display());
+    }
+
+    println!(
+        "checked {} files, {} failed",
+        report.checked,
+        report.failures.len()
+    );
+    if let Some(dir) = report.quarantine {
+        println!("moved failures to {}", dir.display());
+    }
+
+    if report.failures.is_empty() {
+        0
+    } else {
+        EXIT_FAILED_IMAGES
+    }
 }
 
 mod cli {
@@ -40,12 +233,30 @@ mod cli {
 
     use clap::App;
     use clap::Arg;
+    use clap::SubCommand;
+    use wallsplash;
 
     pub fn build_app() -> App<'static, 'static> {
         App::new("wallsplash")
             .version("0.1.0")
             .author("Yufeng Wang <yufengwang05@gmail.com>")
             .about("Display wallpapers from local image directory and Unsplash.")
+            .arg(
+                Arg::with_name("collection")
+                    .long("collection")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .value_name("ID")
+                    .help("Unsplash collection to get photos from, may be given more than once"),
+            )
+            .arg(
+                Arg::with_name("command")
+                    .long("command")
+                    .takes_value(true)
+                    .value_name("TEMPLATE")
+                    .help("Command to set the wallpaper with, like 'wbg {path}'"),
+            )
             .arg(
                 Arg::with_name("config")
                     .long("config")
@@ -57,8 +268,20 @@ mod cli {
                 Arg::with_name("dir")
                     .long("dir")
                     .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
                     .value_name("PATH")
-                    .help("Path to local directory of images"),
+                    .help("Path to local directory of images, may be given more than once"),
+            )
+            .arg(
+                Arg::with_name("full-resolution")
+                    .long("full-resolution")
+                    .help("Download the original Unsplash photos instead of resizing them"),
+            )
+            .arg(
+                Arg::with_name("ignore-budget")
+                    .long("ignore-budget")
+                    .help("Download even when the monthly download budget is used up"),
             )
             .arg(
                 Arg::with_name("limit")
@@ -67,6 +290,95 @@ mod cli {
                     .value_name("NUM")
                     .help("Max number of Unsplash images to download and cache, default 10"),
             )
+            .arg(
+                Arg::with_name("local-order")
+                    .long("local-order")
+                    .takes_value(true)
+                    .value_name("ORDER")
+                    .possible_values(&["sequential", "random", "shuffle"])
+                    .help("Order to show local images in, default sequential"),
+            )
+            .arg(
+                Arg::with_name("max-files")
+                    .long("max-files")
+                    .takes_value(true)
+                    .value_name("NUM")
+                    .help("Max number of local files to consider, default 20000"),
+            )
+            .arg(
+                Arg::with_name("max-refresh")
+                    .long("max-refresh")
+                    .takes_value(true)
+                    .value_name("SECS")
+                    .help(
+                        "Max seconds to stretch the Unsplash refresh to, default 604800 (7 days)",
+                    ),
+            )
+            .arg(
+                Arg::with_name("on-locked")
+                    .long("on-locked")
+                    .takes_value(true)
+                    .value_name("ACTION")
+                    .possible_values(&["skip", "defer"])
+                    .help("Skip or defer rotations while the session is locked"),
+            )
+            .arg(
+                Arg::with_name("orientation")
+                    .long("orientation")
+                    .takes_value(true)
+                    .value_name("SHAPE")
+                    .possible_values(&["landscape", "portrait", "squarish"])
+                    .help("Only get Unsplash photos of this shape"),
+            )
+            .arg(
+                Arg::with_name("order")
+                    .long("order")
+                    .takes_value(true)
+                    .value_name("ORDER")
+                    .possible_values(&["alternate", "priority"])
+                    .help("Alternate between sources or try them by priority, default alternate"),
+            )
+            .arg(
+                Arg::with_name("print-exit-codes")
+                    .long("print-exit-codes")
+                    .help("Print the exit statuses and their meanings, then exit"),
+            )
+            .arg(
+                Arg::with_name("priority")
+                    .long("priority")
+                    .takes_value(true)
+                    .value_name("SOURCES")
+                    .use_delimiter(true)
+                    .help("Comma-separated sources to try in order, default unsplash,local"),
+            )
+            .arg(
+                Arg::with_name("unsplash-mode")
+                    .long("unsplash-mode")
+                    .takes_value(true)
+                    .value_name("MODE")
+                    .possible_values(&["latest", "random"])
+                    .help("Whether to get the latest or random Unsplash photos, default latest"),
+            )
+            .arg(
+                Arg::with_name("unsplash-order")
+                    .long("unsplash-order")
+                    .takes_value(true)
+                    .value_name("ORDER")
+                    .possible_values(&["sequential", "random", "shuffle"])
+                    .help("Order to show cached Unsplash images in, default sequential"),
+            )
+            .arg(
+                Arg::with_name("query")
+                    .long("query")
+                    .takes_value(true)
+                    .value_name("QUERY")
+                    .help("Search Unsplash for photos matching this instead of the latest ones"),
+            )
+            .arg(
+                Arg::with_name("recursive")
+                    .long("recursive")
+                    .help("Also use images in subdirectories of the local directory"),
+            )
             .arg(
                 Arg::with_name("refresh")
                     .long("refresh")
@@ -74,6 +386,45 @@ mod cli {
                     .value_name("SECS")
                     .help("Seconds before refreshing Unsplash image cache, default 86400 (1 day)"),
             )
+            .arg(
+                Arg::with_name("region")
+                    .long("region")
+                    .takes_value(true)
+                    .value_name("REGION")
+                    .help("Add this region to the Unsplash query, or 'auto' to go by the timezone"),
+            )
+            .arg(
+                Arg::with_name("resolution")
+                    .long("resolution")
+                    .takes_value(true)
+                    .value_name("WIDTHxHEIGHT")
+                    .help("Screen size to resize Unsplash photos to, detected by default"),
+            )
+            .arg(
+                Arg::with_name("run-as")
+                    .long("run-as")
+                    .takes_value(true)
+                    .value_name("USER:GROUP")
+                    .help("User and group to switch to when started as root"),
+            )
+            .arg(
+                Arg::with_name("scaling")
+                    .long("scaling")
+                    .alias("mode")
+                    .takes_value(true)
+                    .value_name("MODE")
+                    .possible_values(&["fill", "max", "fit", "scale", "stretch", "center", "tile"])
+                    .help("How to fit images onto the screen, default fill"),
+            )
+            .arg(
+                Arg::with_name("setter")
+                    .long("setter")
+                    .alias("backend")
+                    .takes_value(true)
+                    .value_name("SETTER")
+                    .possible_values(wallsplash::SETTERS)
+                    .help("Program to set the wallpaper with, default feh"),
+            )
             .arg(
                 Arg::with_name("timeout")
                     .long("timeout")
@@ -88,9 +439,45 @@ mod cli {
                     .value_name("TOKEN")
                     .help("Unsplash API token"),
             )
+            .subcommand(
+                SubCommand::with_name("slideshow")
+                    .about("Show the images of a directory until interrupted")
+                    .arg(
+                        Arg::with_name("dir")
+                            .required(true)
+                            .value_name("DIR")
+                            .help("Directory to show images from"),
+                    )
+                    .arg(
+                        Arg::with_name("shuffle")
+                            .long("shuffle")
+                            .help("Show every image once per pass in a shuffled order"),
+                    )
+                    .arg(
+                        Arg::with_name("timeout")
+                            .long("timeout")
+                            .takes_value(true)
+                            .value_name("DURATION")
+                            .help("Time to show each image, like 15s, 2m or 1h"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("status")
+                    .about("Show this month's downloads and the state of the Unsplash cache"),
+            )
+            .subcommand(
+                SubCommand::with_name("verify-local")
+                    .about("Check that every local wallpaper is a valid image")
+                    .arg(
+                        Arg::with_name("fix")
+                            .long("fix")
+                            .help("Move failed images into a quarantine subdirectory"),
+                    ),
+            )
     }
 }
 
+#[allow(non_local_definitions)]
 mod cfg {
     //! Module for application-specific configuration file. Defines the structure of the
     //! configuration file format and how to read it into the application.
@@ -103,49 +490,90 @@ mod cfg {
 
     use ResBoxErr;
 
-    #[derive(Debug, Deserialize)]
+    #[derive(Debug, Default, Deserialize)]
     pub struct ConfigTable {
         pub timeout: Option<u32>,
+        pub order: Option<String>,
+        pub priority: Option<Vec<String>>,
+        pub run_as: Option<String>,
+        pub on_locked: Option<String>,
+        pub region: Option<String>,
+        pub env_passthrough: Option<Vec<String>>,
+        pub headless: Option<String>,
+        pub setter: Option<String>,
+        pub command: Option<String>,
+        pub backend: Option<String>,
+        pub scaling: Option<String>,
+        pub mode: Option<String>,
+        pub engine: Option<EngineTable>,
         pub local: Option<LocalTable>,
         pub unsplash: Option<UnsplashTable>,
+        pub network: Option<NetworkTable>,
+        pub logging: Option<LoggingTable>,
     }
 
+    /// A setting that takes either a single value or an array of them.
     #[derive(Debug, Deserialize)]
+    #[serde(untagged)]
+    pub enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    impl<T: Clone> OneOrMany<T> {
+        pub fn to_vec(&self) -> Vec<T> {
+            match *self {
+                OneOrMany::One(ref s) => vec![s.clone()],
+                OneOrMany::Many(ref v) => v.clone(),
+            }
+        }
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    pub struct EngineTable {
+        pub hold_while: Option<String>,
+        pub blacklist: Option<Vec<String>>,
+        pub min_resolution: Option<String>,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
     pub struct LocalTable {
-        pub dir: Option<String>,
+        pub dir: Option<OneOrMany<String>>,
+        pub max_files: Option<usize>,
+        pub extensions: Option<Vec<String>>,
+        pub hidden: Option<bool>,
+        pub recursive: Option<bool>,
+        pub rescan: Option<u32>,
+        pub order: Option<String>,
     }
 
-    #[derive(Debug, Deserialize)]
+    #[derive(Debug, Default, Deserialize)]
     pub struct UnsplashTable {
         pub token: Option<String>,
         pub limit: Option<u32>,
+        pub mode: Option<String>,
+        pub query: Option<String>,
+        pub collection: Option<OneOrMany<u64>>,
+        pub orientation: Option<String>,
+        pub resolution: Option<String>,
+        pub full_resolution: Option<bool>,
         pub refresh: Option<u32>,
+        pub adaptive_refresh: Option<bool>,
+        pub max_refresh: Option<u32>,
+        pub max_image_age: Option<u32>,
+        pub order: Option<String>,
     }
 
-    impl Default for ConfigTable {
-        fn default() -> ConfigTable {
-            ConfigTable {
-                timeout: None,
-                local: Default::default(),
-                unsplash: Default::default(),
-            }
-        }
-    }
-
-    impl Default for LocalTable {
-        fn default() -> LocalTable {
-            LocalTable { dir: None }
-        }
+    #[derive(Debug, Default, Deserialize)]
+    pub struct NetworkTable {
+        pub monthly_budget_mb: Option<u64>,
     }
 
-    impl Default for UnsplashTable {
-        fn default() -> UnsplashTable {
-            UnsplashTable {
-                token: None,
-                limit: None,
-                refresh: None,
-            }
-        }
+    #[derive(Debug, Default, Deserialize)]
+    pub struct LoggingTable {
+        pub journal_level: Option<String>,
+        pub file: Option<String>,
+        pub file_level: Option<String>,
     }
 
     /// Read the configuration file into a structure. Will default to an empty structure when the
@@ -169,6 +597,7 @@ mod cfg {
     }
 }
 
+#[allow(clippy::redundant_static_lifetimes)]
 mod def {
     //! Module for application-specific default values. Fallback to these when user does not
     //! provide or set these using other means.
@@ -179,16 +608,72 @@ mod def {
     /// 30 minutes in seconds.
     pub const TIMEOUT: u32 = 30 * 60;
 
+    /// Shortest allowed timeout, in seconds.
+    pub const MIN_TIMEOUT: u32 = 5;
+
+    /// Local directories with more files than this are truncated.
+    pub const LOCAL_MAX_FILES: usize = 20_000;
+
+    /// Rescan the local directories at least hourly, in seconds.
+    pub const LOCAL_RESCAN: u32 = 60 * 60;
+
+    /// Shortest allowed time between rescans of the local directories, in seconds.
+    pub const MIN_LOCAL_RESCAN: u32 = 10;
+
     /// 10 images from Unsplash.
     pub const UNSPLASH_LIMIT: u32 = 10;
 
+    /// Most images Unsplash returns in one page.
+    pub const MAX_UNSPLASH_LIMIT: u32 = 30;
+
     /// 24 hours in seconds.
     pub const UNSPLASH_REFRESH: u32 = 24 * 60 * 60;
 
+    /// Shortest allowed refresh interval, in seconds.
+    pub const MIN_UNSPLASH_REFRESH: u32 = 60;
+
+    /// Adapt the Unsplash refresh to how often new images appear.
+    pub const UNSPLASH_ADAPTIVE_REFRESH: bool = true;
+
+    /// 7 days in seconds.
+    pub const UNSPLASH_MAX_REFRESH: u32 = 7 * 24 * 60 * 60;
+
+    /// Show local images in order.
+    pub const LOCAL_ORDER: &'static str = "sequential";
+
+    /// Cache the latest Unsplash photos.
+    pub const UNSPLASH_MODE: &'static str = "latest";
+
+    /// Show cached Unsplash images in order.
+    pub const UNSPLASH_ORDER: &'static str = "sequential";
+
+    /// Alternate between the sources.
+    pub const ORDER: &'static str = "alternate";
+
+    /// Set wallpapers with feh.
+    pub const SETTER: &'static str = "feh";
+
+    /// Scale images to cover the screen.
+    pub const SCALING: &'static str = "fill";
+
+    /// Log every failure to set the wallpaper without a display.
+    pub const HEADLESS: &'static str = "fail";
+
+    /// Unsplash first, local images as a fallback.
+    pub const PRIORITY: &'static [&'static str] = &["unsplash", "local"];
+
+    /// Write everything to the debug file, when there is one.
+    pub const LOG_FILE_LEVEL: &'static str = "debug";
+
+    /// Get the user's home directory. This assumes that the user has a valid home directory.
+    pub fn home_dir() -> PathBuf {
+        env::home_dir().unwrap()
+    }
+
     /// Get the default configuration file path expected by the application. This assumes that the
     /// user has a valid home directory.
     pub fn config_path() -> PathBuf {
-        let mut p = env::home_dir().unwrap();
+        let mut p = home_dir();
         p.push(".config");
         p.push("wallsplash");
         p.push("config.toml");
@@ -199,25 +684,70 @@ mod def {
 mod args {
     //! Module for parsing and massaging application-specific arguments.
 
-    use std::path::Path;
+    use std::fmt::Display;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
     use std::time::Duration;
 
     use clap::ArgMatches;
+    use log::LogLevelFilter;
     use wallsplash;
+    use wallsplash::{CommandSetter, Headless, Hold, OnLocked, Order, Orientation, Resolution,
+                     ShuffleMode, Scaling, UnsplashMode, WallpaperSetter};
 
     use cfg;
-    use cli;
     use def;
 
     use ResBoxErr;
 
+    /// What the application was asked to do.
+    #[derive(Clone, Copy)]
+    pub enum Command {
+        /// Rotate wallpapers forever.
+        Run,
+        /// Check the local wallpapers, optionally quarantining failures.
+        VerifyLocal { fix: bool },
+        /// Show the images of a directory until interrupted.
+        Slideshow { shuffle: bool },
+        /// Show this month's downloads and the state of the Unsplash cache.
+        Status,
+    }
+
     /// Arguments that are merged, normalized, and flattened.
     pub struct Args {
+        pub command: Command,
         pub timeout: u32,
-        pub local_dir: String,
+        pub local_dirs: Vec<PathBuf>,
+        pub local_max_files: usize,
+        pub local_extensions: Vec<String>,
+        pub local_hidden: bool,
+        pub local_recursive: bool,
+        pub local_rescan: u32,
+        pub local_order: ShuffleMode,
         pub unsplash_token: String,
         pub unsplash_limit: u32,
+        pub region: Option<String>,
+        pub unsplash_mode: UnsplashMode,
+        pub unsplash_query: Option<String>,
+        pub unsplash_collections: Vec<u64>,
+        pub unsplash_orientation: Option<Orientation>,
+        pub unsplash_resolution: Option<Resolution>,
+        pub unsplash_detect_resolution: bool,
         pub unsplash_refresh: u32,
+        pub unsplash_max_refresh: u32,
+        pub unsplash_max_image_age: Option<u32>,
+        pub unsplash_order: ShuffleMode,
+        pub order: Order,
+        pub run_as: Option<String>,
+        pub on_locked: Option<OnLocked>,
+        pub budget: Option<u64>,
+        pub log_journal_level: Option<LogLevelFilter>,
+        pub log_file: Option<(PathBuf, LogLevelFilter)>,
+        pub headless: Headless,
+        pub hold_while: Option<Hold>,
+        pub blacklist: Vec<PathBuf>,
+        pub min_resolution: Option<Resolution>,
+        pub setter: Box<WallpaperSetter>,
     }
 
     impl Args {
@@ -234,22 +764,55 @@ mod args {
         ///
         /// Possible errors including file I/O issues, configuration file convertion issues,
         /// missing required arguments, or invalid argument formats.
-        pub fn parse() -> ResBoxErr<Args> {
-            let matches = cli::build_app().get_matches();
-            let table = ArgsParser::parse_config_file(&matches)?;
+        pub fn parse(matches: ArgMatches) -> ResBoxErr<Args> {
+            // A slideshow stands apart from the normal rotation and its configuration.
+            let table = if matches.subcommand_matches("slideshow").is_some() {
+                cfg::ConfigTable::default()
+            } else {
+                ArgsParser::parse_config_file(&matches)?
+            };
             let parser = ArgsParser::new(matches, table);
             parser.to_args()
         }
 
         /// Consume and convert arguments to a context object understood by the application engine.
-        pub fn into_context(self) -> wallsplash::Context {
-            wallsplash::Context::new(
-                &self.local_dir,
-                &self.unsplash_token,
-                self.unsplash_limit,
-                Duration::from_secs(self.timeout as u64),
-                Duration::from_secs(self.unsplash_refresh as u64),
-            )
+        pub fn into_context(self) -> Result<wallsplash::Context, wallsplash::WallsplashError> {
+            let builder = self.local_dirs
+                .into_iter()
+                .fold(wallsplash::ContextBuilder::new(), |b, dir| b.dir(dir));
+            builder
+                .max_files(self.local_max_files)
+                .extensions(self.local_extensions)
+                .hidden(self.local_hidden)
+                .recursive(self.local_recursive)
+                .rescan(Duration::from_secs(self.local_rescan as u64))
+                .local_order(self.local_order)
+                .token(&self.unsplash_token)
+                .limit(self.unsplash_limit)
+                .region(self.region)
+                .unsplash_mode(self.unsplash_mode)
+                .query(self.unsplash_query)
+                .collections(self.unsplash_collections)
+                .orientation(self.unsplash_orientation)
+                .resolution(self.unsplash_resolution)
+                .detect_resolution(self.unsplash_detect_resolution)
+                .timeout(Duration::from_secs(self.timeout as u64))
+                .refresh(Duration::from_secs(self.unsplash_refresh as u64))
+                .max_refresh(Duration::from_secs(self.unsplash_max_refresh as u64))
+                .max_image_age(
+                    self.unsplash_max_image_age.map(|secs| Duration::from_secs(secs as u64)),
+                )
+                .unsplash_order(self.unsplash_order)
+                .order(self.order)
+                .run_as(self.run_as)
+                .on_locked(self.on_locked)
+                .budget(self.budget)
+                .setter(self.setter)
+                .headless(self.headless)
+                .hold_while(self.hold_while)
+                .blacklist(self.blacklist)
+                .min_resolution(self.min_resolution)
+                .build()
         }
     }
 
@@ -279,60 +842,1048 @@ mod args {
         }
 
         fn to_args(&self) -> ResBoxErr<Args> {
+            let command = self.parse_command();
+            let refresh = self.parse_refresh()?;
+            let resolution = self.parse_resolution()?;
+            let detect = matches!(command, Command::Run)
+                && resolution.is_none()
+                && !self.parse_full_resolution();
             Ok(Args {
+                command: command,
                 timeout: self.parse_timeout()?,
-                local_dir: self.parse_local_dir()?,
-                unsplash_token: self.parse_token()?,
+                local_dirs: self.parse_local_dirs()?,
+                local_max_files: self.parse_max_files()?,
+                local_extensions: self.parse_extensions(),
+                local_hidden: self.parse_hidden(),
+                local_recursive: self.parse_recursive(),
+                local_rescan: self.parse_rescan()?,
+                local_order: self.parse_local_order()?,
+                unsplash_token: match command {
+                    Command::Run => self.parse_token()?,
+                    _ => String::new(),
+                },
                 unsplash_limit: self.parse_limit()?,
-                unsplash_refresh: self.parse_refresh()?,
+                region: self.parse_region(),
+                unsplash_mode: self.parse_unsplash_mode()?,
+                unsplash_query: self.parse_query(),
+                unsplash_collections: self.parse_collections()?,
+                unsplash_orientation: self.parse_orientation()?,
+                unsplash_resolution: resolution,
+                unsplash_detect_resolution: detect,
+                unsplash_refresh: refresh,
+                unsplash_max_refresh: self.parse_max_refresh(refresh)?,
+                unsplash_max_image_age: self.parse_max_image_age()?,
+                unsplash_order: self.parse_unsplash_order()?,
+                order: self.parse_order()?,
+                run_as: self.parse_run_as()?,
+                on_locked: self.parse_on_locked()?,
+                budget: self.parse_budget()?,
+                log_journal_level: self.parse_journal_level()?,
+                log_file: self.parse_log_file()?,
+                headless: self.parse_headless()?,
+                hold_while: self.parse_hold_while()?,
+                blacklist: self.parse_blacklist(),
+                min_resolution: self.parse_min_resolution()?,
+                setter: self.parse_setter()?,
             })
         }
 
+        fn parse_command(&self) -> Command {
+            if let Some(m) = self.matches.subcommand_matches("slideshow") {
+                return Command::Slideshow {
+                    shuffle: m.is_present("shuffle"),
+                };
+            }
+            if self.matches.subcommand_matches("status").is_some() {
+                return Command::Status;
+            }
+            match self.matches.subcommand_matches("verify-local") {
+                Some(m) => Command::VerifyLocal {
+                    fix: m.is_present("fix"),
+                },
+                None => Command::Run,
+            }
+        }
+
         fn parse_timeout(&self) -> ResBoxErr<u32> {
-            let secs = match self.matches.value_of("timeout") {
-                Some(secs) => Some(secs.parse::<u32>()?),
-                None => None,
+            let slideshow = self.matches
+                .subcommand_matches("slideshow")
+                .and_then(|m| m.value_of("timeout"));
+            if let Some(value) = slideshow {
+                let secs = parse_secs(value)
+                    .map_err(|e| format!("invalid value '{}' for --timeout: {}", value, e))?;
+                return in_range(Named(secs, "--timeout".to_owned()), def::MIN_TIMEOUT, None);
+            }
+
+            let secs = self.number("timeout", "timeout", self.table.timeout, def::TIMEOUT)?;
+            in_range(secs, def::MIN_TIMEOUT, None)
+        }
+
+        fn parse_local_dirs(&self) -> ResBoxErr<Vec<PathBuf>> {
+            if self.matches.subcommand_matches("status").is_some() {
+                return Ok(Vec::new());
+            }
+            if let Some(m) = self.matches.subcommand_matches("slideshow") {
+                if let Some(dir) = m.value_of_os("dir") {
+                    return Ok(vec![PathBuf::from(dir)]);
+                }
+            }
+
+            let dirs: Vec<PathBuf> = match self.matches.values_of_os("dir") {
+                Some(values) => values.map(PathBuf::from).collect(),
+                None => self.table
+                    .local
+                    .as_ref()
+                    .and_then(|t| t.dir.as_ref())
+                    .map(|d| d.to_vec().into_iter().map(PathBuf::from).collect())
+                    .unwrap_or_default(),
             };
-            Ok(secs.or(self.table.timeout).unwrap_or(def::TIMEOUT))
+            if dirs.is_empty() {
+                return Err(From::from("need a local directory, set --dir or local.dir"));
+            }
+            Ok(dirs)
         }
 
-        fn parse_local_dir(&self) -> ResBoxErr<String> {
-            Ok(self.matches
-                .value_of("dir")
-                .map(|s| s.to_string())
-                .or(self.table.local.as_ref().and_then(|t| t.dir.to_owned()))
-                .expect("need a local directory"))
+        fn parse_max_files(&self) -> ResBoxErr<usize> {
+            let num = self.number(
+                "max-files",
+                "local.max_files",
+                self.table.local.as_ref().and_then(|t| t.max_files),
+                def::LOCAL_MAX_FILES,
+            )?;
+            in_range(num, 1, None)
+        }
+
+        fn parse_rescan(&self) -> ResBoxErr<u32> {
+            let secs = match self.table.local.as_ref().and_then(|t| t.rescan) {
+                Some(secs) => Named(secs, "local.rescan".to_owned()),
+                None => return Ok(def::LOCAL_RESCAN),
+            };
+            in_range(secs, def::MIN_LOCAL_RESCAN, None)
+        }
+
+        fn parse_extensions(&self) -> Vec<String> {
+            self.table
+                .local
+                .as_ref()
+                .and_then(|t| t.extensions.to_owned())
+                .unwrap_or_default()
+        }
+
+        fn parse_hidden(&self) -> bool {
+            self.table
+                .local
+                .as_ref()
+                .and_then(|t| t.hidden)
+                .unwrap_or(false)
+        }
+
+        fn parse_recursive(&self) -> bool {
+            self.matches.is_present("recursive")
+                || self.table
+                    .local
+                    .as_ref()
+                    .and_then(|t| t.recursive)
+                    .unwrap_or(false)
         }
 
         fn parse_token(&self) -> ResBoxErr<String> {
-            Ok(self.matches
+            self.matches
                 .value_of("token")
                 .map(|s| s.to_string())
                 .or(self.table
                     .unsplash
                     .as_ref()
                     .and_then(|t| t.token.to_owned()))
-                .expect("need unsplash token"))
+                .ok_or_else(|| From::from("need an Unsplash token, set --token or unsplash.token"))
+        }
+
+        fn parse_region(&self) -> Option<String> {
+            let region = self.matches
+                .value_of("region")
+                .map(|s| s.to_owned())
+                .or(self.table.region.to_owned())
+                .and_then(|r| if r.trim().is_empty() { None } else { Some(r) })?;
+            if region != "auto" {
+                return Some(region);
+            }
+
+            let detected = wallsplash::timezone_region();
+            match detected {
+                Some(ref r) => debug!("region from timezone: {}", r),
+                None => info!("cannot tell the region from the timezone, not localizing photos"),
+            }
+            detected
         }
 
         fn parse_limit(&self) -> ResBoxErr<u32> {
-            let num = match self.matches.value_of("limit") {
-                Some(n) => Some(n.parse::<u32>()?),
-                None => None,
-            };
-            Ok(num.or(self.table.unsplash.as_ref().and_then(|t| t.limit))
-                .unwrap_or(def::UNSPLASH_LIMIT))
+            let num = self.number(
+                "limit",
+                "unsplash.limit",
+                self.table.unsplash.as_ref().and_then(|t| t.limit),
+                def::UNSPLASH_LIMIT,
+            )?;
+            in_range(num, 1, Some(def::MAX_UNSPLASH_LIMIT))
         }
 
         fn parse_refresh(&self) -> ResBoxErr<u32> {
-            let secs = match self.matches.value_of("refresh") {
-                Some(secs) => Some(secs.parse::<u32>()?),
+            let secs = self.number(
+                "refresh",
+                "unsplash.refresh",
+                self.table.unsplash.as_ref().and_then(|t| t.refresh),
+                def::UNSPLASH_REFRESH,
+            )?;
+            in_range(secs, def::MIN_UNSPLASH_REFRESH, None)
+        }
+
+        fn parse_max_refresh(&self, refresh: u32) -> ResBoxErr<u32> {
+            let adaptive = self.table
+                .unsplash
+                .as_ref()
+                .and_then(|t| t.adaptive_refresh)
+                .unwrap_or(def::UNSPLASH_ADAPTIVE_REFRESH);
+            if !adaptive {
+                if self.matches.is_present("max-refresh") {
+                    warn!("ignoring --max-refresh since unsplash.adaptive_refresh is off");
+                }
+                return Ok(refresh);
+            }
+
+            let secs = self.number(
+                "max-refresh",
+                "unsplash.max_refresh",
+                self.table.unsplash.as_ref().and_then(|t| t.max_refresh),
+                def::UNSPLASH_MAX_REFRESH,
+            )?;
+            match secs {
+                // The default only caps refresh intervals shorter than itself.
+                Named(default, ref name) if name == "default" => Ok(default.max(refresh)),
+                secs => in_range(secs, refresh, None),
+            }
+        }
+
+        fn parse_max_image_age(&self) -> ResBoxErr<Option<u32>> {
+            let age = self.table.unsplash.as_ref().and_then(|t| t.max_image_age);
+            match age {
+                // Expiring sooner would force refreshes more often than the refresh allows.
+                Some(secs) => Ok(Some(in_range(
+                    Named(secs, "unsplash.max_image_age".to_owned()),
+                    def::MIN_UNSPLASH_REFRESH,
+                    None,
+                )?)),
+                None => Ok(None),
+            }
+        }
+
+        fn parse_local_order(&self) -> ResBoxErr<ShuffleMode> {
+            let order = self.matches
+                .value_of("local-order")
+                .or(self.table
+                    .local
+                    .as_ref()
+                    .and_then(|t| t.order.as_deref()))
+                .unwrap_or(def::LOCAL_ORDER);
+            Ok(order.parse::<ShuffleMode>()?)
+        }
+
+        fn parse_unsplash_mode(&self) -> ResBoxErr<UnsplashMode> {
+            let mode = self.matches
+                .value_of("unsplash-mode")
+                .or(self.table
+                    .unsplash
+                    .as_ref()
+                    .and_then(|t| t.mode.as_deref()))
+                .unwrap_or(def::UNSPLASH_MODE);
+            Ok(mode.parse::<UnsplashMode>()?)
+        }
+
+        fn parse_orientation(&self) -> ResBoxErr<Option<Orientation>> {
+            let orientation = self.matches.value_of("orientation").or(self.table
+                .unsplash
+                .as_ref()
+                .and_then(|t| t.orientation.as_deref()));
+            match orientation {
+                Some(o) => Ok(Some(o.parse::<Orientation>()?)),
+                None => Ok(None),
+            }
+        }
+
+        fn parse_full_resolution(&self) -> bool {
+            self.matches.is_present("full-resolution")
+                || self.table
+                    .unsplash
+                    .as_ref()
+                    .and_then(|t| t.full_resolution)
+                    .unwrap_or(false)
+        }
+
+        /// The configured resolution. Without one, it is detected while the engine starts up,
+        /// unless full-size photos were asked for.
+        fn parse_resolution(&self) -> ResBoxErr<Option<Resolution>> {
+            if self.parse_full_resolution() {
+                return Ok(None);
+            }
+
+            let configured = self.matches.value_of("resolution").or(self.table
+                .unsplash
+                .as_ref()
+                .and_then(|t| t.resolution.as_deref()));
+            match configured {
+                Some(r) => Ok(Some(r.parse::<Resolution>()?)),
+                None => Ok(None),
+            }
+        }
+
+        fn parse_query(&self) -> Option<String> {
+            self.matches
+                .value_of("query")
+                .map(|s| s.to_owned())
+                .or(self.table.unsplash.as_ref().and_then(|t| t.query.to_owned()))
+                .and_then(|q| if q.trim().is_empty() { None } else { Some(q) })
+        }
+
+        fn parse_collections(&self) -> ResBoxErr<Vec<u64>> {
+            let ids = match self.matches.values_of("collection") {
+                Some(values) => values
+                    .map(|v| {
+                        v.parse::<u64>().map_err(|e| {
+                            From::from(format!("invalid value '{}' for --collection: {}", v, e))
+                        })
+                    })
+                    .collect::<ResBoxErr<Vec<u64>>>()?,
+                None => self.table
+                    .unsplash
+                    .as_ref()
+                    .and_then(|t| t.collection.as_ref())
+                    .map(|c| c.to_vec())
+                    .unwrap_or_default(),
+            };
+            let mut unique = Vec::new();
+            for id in ids {
+                if !unique.contains(&id) {
+                    unique.push(id);
+                }
+            }
+            Ok(unique)
+        }
+
+        fn parse_unsplash_order(&self) -> ResBoxErr<ShuffleMode> {
+            let order = self.matches
+                .value_of("unsplash-order")
+                .or(self.table
+                    .unsplash
+                    .as_ref()
+                    .and_then(|t| t.order.as_deref()))
+                .unwrap_or(def::UNSPLASH_ORDER);
+            Ok(order.parse::<ShuffleMode>()?)
+        }
+
+        fn parse_order(&self) -> ResBoxErr<Order> {
+            let order = self.matches
+                .value_of("order")
+                .or(self.table.order.as_deref())
+                .unwrap_or(def::ORDER);
+            let priority = match self.matches.values_of("priority") {
+                Some(names) => Some(names.map(|s| s.to_string()).collect::<Vec<_>>()),
+                None => self.table.priority.to_owned(),
+            };
+
+            match order {
+                "alternate" => match priority {
+                    Some(_) => Err(From::from("priority is only used with order \"priority\"")),
+                    None => Ok(Order::Alternate),
+                },
+                "priority" => {
+                    let names = priority
+                        .unwrap_or_else(|| def::PRIORITY.iter().map(|s| s.to_string()).collect());
+                    if names.is_empty() {
+                        return Err(From::from("priority needs at least one source"));
+                    }
+                    for (i, name) in names.iter().enumerate() {
+                        if !wallsplash::SOURCES.contains(&name.as_str()) {
+                            return Err(From::from(format!(
+                                "unknown source '{}' in priority",
+                                name
+                            )));
+                        }
+                        if names[..i].contains(name) {
+                            return Err(From::from(format!(
+                                "duplicate source '{}' in priority",
+                                name
+                            )));
+                        }
+                    }
+                    Ok(Order::Priority(names))
+                }
+                _ => Err(From::from(format!(
+                    "invalid order '{}', expected alternate or priority",
+                    order
+                ))),
+            }
+        }
+
+        fn parse_run_as(&self) -> ResBoxErr<Option<String>> {
+            Ok(self.matches
+                .value_of("run-as")
+                .map(|s| s.to_string())
+                .or(self.table.run_as.to_owned()))
+        }
+
+        fn parse_on_locked(&self) -> ResBoxErr<Option<OnLocked>> {
+            let action = self.matches
+                .value_of("on-locked")
+                .or(self.table.on_locked.as_deref());
+            match action {
+                Some(a) => Ok(Some(a.parse::<OnLocked>()?)),
+                None => Ok(None),
+            }
+        }
+
+        fn parse_headless(&self) -> ResBoxErr<Headless> {
+            let headless = self.table.headless.as_deref().unwrap_or(def::HEADLESS);
+            Ok(headless.parse::<Headless>()?)
+        }
+
+        fn parse_setter(&self) -> ResBoxErr<Box<WallpaperSetter>> {
+            let passthrough = self.table.env_passthrough.to_owned().unwrap_or_default();
+            let command = self.matches
+                .value_of("command")
+                .or(self.table.command.as_deref());
+            if let Some(template) = command {
+                return Ok(Box::new(CommandSetter::new(template, passthrough)?));
+            }
+
+            let name = self.matches
+                .value_of("setter")
+                .or(self.table.setter.as_deref())
+                .or(self.table.backend.as_deref())
+                .unwrap_or(def::SETTER);
+            Ok(wallsplash::setter_by_name(name, passthrough, self.parse_scaling()?)?)
+        }
+
+        fn parse_scaling(&self) -> ResBoxErr<Scaling> {
+            let scaling = self.matches
+                .value_of("scaling")
+                .or(self.table.scaling.as_deref())
+                .or(self.table.mode.as_deref())
+                .unwrap_or(def::SCALING);
+            Ok(scaling.parse::<Scaling>()?)
+        }
+
+        fn parse_hold_while(&self) -> ResBoxErr<Option<Hold>> {
+            let hold = self.table
+                .engine
+                .as_ref()
+                .and_then(|t| t.hold_while.as_ref());
+            match hold {
+                Some(h) => Ok(Some(h.parse::<Hold>()?)),
+                None => Ok(None),
+            }
+        }
+
+        fn parse_blacklist(&self) -> Vec<PathBuf> {
+            self.table
+                .engine
+                .as_ref()
+                .and_then(|t| t.blacklist.as_ref())
+                .map(|paths| paths.iter().map(PathBuf::from).collect())
+                .unwrap_or_default()
+        }
+
+        fn parse_min_resolution(&self) -> ResBoxErr<Option<Resolution>> {
+            let min = self.table
+                .engine
+                .as_ref()
+                .and_then(|t| t.min_resolution.as_ref());
+            match min {
+                Some(r) => Ok(Some(r.parse::<Resolution>()?)),
+                None => Ok(None),
+            }
+        }
+
+        fn parse_journal_level(&self) -> ResBoxErr<Option<LogLevelFilter>> {
+            let level = self.table
+                .logging
+                .as_ref()
+                .and_then(|t| t.journal_level.as_ref());
+            match level {
+                Some(l) => Ok(Some(parse_level(l)?)),
+                None => Ok(None),
+            }
+        }
+
+        fn parse_log_file(&self) -> ResBoxErr<Option<(PathBuf, LogLevelFilter)>> {
+            let table = match self.table.logging {
+                Some(ref t) => t,
+                None => return Ok(None),
+            };
+            let path = match table.file {
+                // A leading ~ is expanded once the log file is opened.
+                Some(ref p) => PathBuf::from(p),
+                None => return Ok(None),
+            };
+            let level = table.file_level.as_deref().unwrap_or(def::LOG_FILE_LEVEL);
+            Ok(Some((path, parse_level(level)?)))
+        }
+
+        fn parse_budget(&self) -> ResBoxErr<Option<u64>> {
+            if self.matches.is_present("ignore-budget") {
+                return Ok(None);
+            }
+            let mb = match self.table.network.as_ref().and_then(|t| t.monthly_budget_mb) {
+                Some(mb) => mb,
+                None => return Ok(None),
+            };
+            match mb.checked_mul(1024 * 1024) {
+                Some(bytes) => Ok(Some(bytes)),
+                None => Err(From::from(format!(
+                    "invalid value '{}' for network.monthly_budget_mb: too large",
+                    mb
+                ))),
+            }
+        }
+
+        /// Get a numeric setting from the command-line flag, the configuration key, or the
+        /// default, in that order, along with where it came from for error messages.
+        fn number<T>(
+            &self,
+            flag: &str,
+            key: &str,
+            table: Option<T>,
+            default: T,
+        ) -> ResBoxErr<Named<T>>
+        where
+            T: FromStr,
+            T::Err: Display,
+        {
+            if let Some(value) = self.matches.value_of(flag) {
+                return match value.parse::<T>() {
+                    Ok(v) => Ok(Named(v, format!("--{}", flag))),
+                    Err(e) => Err(From::from(format!(
+                        "invalid value '{}' for --{}: {}",
+                        value, flag, e
+                    ))),
+                };
+            }
+            match table {
+                Some(v) => Ok(Named(v, key.to_owned())),
+                None => Ok(Named(default, "default".to_owned())),
+            }
+        }
+    }
+
+    /// Parse a log level name such as `info` or `debug`.
+    fn parse_level(name: &str) -> ResBoxErr<LogLevelFilter> {
+        name.parse::<LogLevelFilter>().map_err(|_| {
+            From::from(format!(
+                "invalid log level '{}', expected off, error, warn, info, debug, or trace",
+                name
+            ))
+        })
+    }
+
+    /// Parse a number of seconds, optionally followed by `s`, or by `m` or `h` for minutes or
+    /// hours.
+    fn parse_secs(value: &str) -> Result<u32, String> {
+        let (digits, scale) = if let Some(n) = value.strip_suffix('h') {
+            (n, 60 * 60)
+        } else if let Some(n) = value.strip_suffix('m') {
+            (n, 60)
+        } else {
+            (value.strip_suffix('s').unwrap_or(value), 1)
+        };
+        let n = digits.parse::<u32>().map_err(|e| e.to_string())?;
+        n.checked_mul(scale).ok_or_else(|| "too large".to_owned())
+    }
+
+    /// A setting's value along with the flag or configuration key it came from.
+    struct Named<T>(T, String);
+
+    /// Check that a numeric setting is at least `min` and, if given, at most `max`.
+    fn in_range<T>(setting: Named<T>, min: T, max: Option<T>) -> ResBoxErr<T>
+    where
+        T: PartialOrd + Display,
+    {
+        let Named(value, name) = setting;
+        let high = max.as_ref().is_some_and(|m| value > *m);
+        if value < min || high {
+            let range = match max {
+                Some(m) => format!("between {} and {}", min, m),
+                None => format!("at least {}", min),
+            };
+            return Err(From::from(format!(
+                "invalid value {} for {}, expected {}",
+                value, name, range
+            )));
+        }
+        Ok(value)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use toml;
+
+        use super::*;
+        use cli;
+
+        /// Parser over the given flags and configuration file contents.
+        fn parser(flags: &[&str], config: &str) -> ArgsParser<'static> {
+            let mut argv = vec!["wallsplash"];
+            argv.extend_from_slice(flags);
+            let matches = cli::build_app().get_matches_from_safe(argv).unwrap();
+            ArgsParser::new(matches, toml::from_str(config).unwrap())
+        }
+
+        type Check = fn(&ArgsParser) -> ResBoxErr<u64>;
+
+        fn limit(p: &ArgsParser) -> ResBoxErr<u64> {
+            p.parse_limit().map(|v| v as u64)
+        }
+
+        fn timeout(p: &ArgsParser) -> ResBoxErr<u64> {
+            p.parse_timeout().map(|v| v as u64)
+        }
+
+        fn refresh(p: &ArgsParser) -> ResBoxErr<u64> {
+            p.parse_refresh().map(|v| v as u64)
+        }
+
+        fn max_files(p: &ArgsParser) -> ResBoxErr<u64> {
+            p.parse_max_files().map(|v| v as u64)
+        }
+
+        fn rescan(p: &ArgsParser) -> ResBoxErr<u64> {
+            p.parse_rescan().map(|v| v as u64)
+        }
+
+        fn max_refresh(p: &ArgsParser) -> ResBoxErr<u64> {
+            p.parse_refresh().and_then(|r| p.parse_max_refresh(r)).map(|v| v as u64)
+        }
+
+        fn max_image_age(p: &ArgsParser) -> ResBoxErr<u64> {
+            p.parse_max_image_age().map(|v| v.unwrap_or(0) as u64)
+        }
+
+        /// Expected outcome of a setting: the parsed value, or words the error must contain.
+        enum Expect {
+            Value(u64),
+            Error(&'static [&'static str]),
+        }
+
+        use self::Expect::{Error, Value};
+
+        fn check(name: &str, check: Check, p: &ArgsParser, expect: &Expect) {
+            match (check(p), expect) {
+                (Ok(v), &Value(want)) => assert_eq!(v, want, "{}", name),
+                (Err(e), &Error(words)) => {
+                    let msg = e.to_string();
+                    for word in words {
+                        assert!(msg.contains(word), "{}: '{}' lacks '{}'", name, msg, word);
+                    }
+                }
+                (Ok(v), _) => panic!("{}: accepted as {}", name, v),
+                (Err(e), _) => panic!("{}: {}", name, e),
+            }
+        }
+
+        #[test]
+        fn numeric_flags() {
+            let cases: &[(&str, Check, Expect)] = &[
+                ("--limit=30", limit, Value(30)),
+                ("--limit=1", limit, Value(1)),
+                ("--limit=abc", limit, Error(&["--limit", "'abc'"])),
+                ("--limit=-5", limit, Error(&["--limit", "'-5'"])),
+                ("--limit=31", limit, Error(&["--limit", "31", "between 1 and 30"])),
+                ("--limit=0", limit, Error(&["--limit", "between 1 and 30"])),
+                ("--timeout=5", timeout, Value(5)),
+                ("--timeout=4", timeout, Error(&["--timeout", "4", "at least 5"])),
+                ("--timeout=-1", timeout, Error(&["--timeout", "'-1'"])),
+                ("--timeout=99999999999", timeout, Error(&["--timeout", "'99999999999'"])),
+                ("--refresh=60", refresh, Value(60)),
+                ("--refresh=59", refresh, Error(&["--refresh", "at least 60"])),
+                ("--refresh=1h", refresh, Error(&["--refresh", "'1h'"])),
+                ("--max-files=1", max_files, Value(1)),
+                ("--max-files=0", max_files, Error(&["--max-files", "at least 1"])),
+                ("--max-files=-3", max_files, Error(&["--max-files", "'-3'"])),
+                ("--max-refresh=86400", max_refresh, Value(86400)),
+                ("--max-refresh=86399", max_refresh, Error(&["--max-refresh", "at least 86400"])),
+            ];
+            for &(flag, f, ref expect) in cases {
+                check(flag, f, &parser(&[flag], ""), expect);
+            }
+        }
+
+        #[test]
+        fn slideshow_timeout_takes_a_unit() {
+            let cases: &[(&str, Expect)] = &[
+                ("15", Value(15)),
+                ("15s", Value(15)),
+                ("2m", Value(120)),
+                ("1h", Value(3600)),
+                ("4s", Error(&["--timeout", "4", "at least 5"])),
+                ("15x", Error(&["--timeout", "'15x'"])),
+                ("s", Error(&["--timeout", "'s'"])),
+                ("-1m", Error(&["--timeout", "'-1m'"])),
+                ("2000000h", Error(&["--timeout", "'2000000h'", "too large"])),
+            ];
+            for &(value, ref expect) in cases {
+                let flag = format!("--timeout={}", value);
+                let p = parser(&["slideshow", "/tmp", &flag], "");
+                check(value, timeout, &p, expect);
+            }
+        }
+
+        #[test]
+        fn numeric_config_values() {
+            let cases: &[(&str, Check, Expect)] = &[
+                ("[unsplash]\nlimit = 30", limit, Value(30)),
+                ("[unsplash]\nlimit = 31", limit, Error(&["unsplash.limit", "between 1 and 30"])),
+                ("timeout = 5", timeout, Value(5)),
+                ("timeout = 4", timeout, Error(&["timeout", "at least 5"])),
+                ("[unsplash]\nrefresh = 59", refresh, Error(&["unsplash.refresh", "at least 60"])),
+                ("[local]\nmax_files = 0", max_files, Error(&["local.max_files", "at least 1"])),
+                ("", rescan, Value(60 * 60)),
+                ("[local]\nrescan = 10", rescan, Value(10)),
+                ("[local]\nrescan = 0", rescan, Error(&["local.rescan", "at least 10"])),
+                ("[local]\nrescan = 9", rescan, Error(&["local.rescan", "9", "at least 10"])),
+                ("", max_refresh, Value(7 * 24 * 60 * 60)),
+                ("[unsplash]\nmax_refresh = 86400", max_refresh, Value(86400)),
+                (
+                    "[unsplash]\nmax_refresh = 3600",
+                    max_refresh,
+                    Error(&["unsplash.max_refresh", "3600", "at least 86400"]),
+                ),
+                (
+                    "[unsplash]\nrefresh = 3600\nmax_refresh = 600",
+                    max_refresh,
+                    Error(&["unsplash.max_refresh", "600", "at least 3600"]),
+                ),
+                ("[unsplash]\nrefresh = 864000", max_refresh, Value(864000)),
+                (
+                    "[unsplash]\nadaptive_refresh = false\nmax_refresh = 60",
+                    max_refresh,
+                    Value(24 * 60 * 60),
+                ),
+                ("", max_image_age, Value(0)),
+                ("[unsplash]\nmax_image_age = 60", max_image_age, Value(60)),
+                (
+                    "[unsplash]\nmax_image_age = 0",
+                    max_image_age,
+                    Error(&["unsplash.max_image_age", "at least 60"]),
+                ),
+                (
+                    "[unsplash]\nmax_image_age = 59",
+                    max_image_age,
+                    Error(&["unsplash.max_image_age", "59", "at least 60"]),
+                ),
+            ];
+            for &(config, f, ref expect) in cases {
+                check(config, f, &parser(&[], config), expect);
+            }
+        }
+
+        #[test]
+        fn flags_take_precedence_over_config_values() {
+            let p = parser(&["--limit=20"], "[unsplash]\nlimit = 31");
+            check("limit", limit, &p, &Value(20));
+        }
+
+        #[test]
+        fn non_numeric_and_negative_config_values_are_rejected() {
+            let cases = &[
+                ("[unsplash]\nlimit = \"ten\"", "unsplash.limit", "\"ten\""),
+                ("[unsplash]\nlimit = -5", "unsplash.limit", "-5"),
+                ("[local]\nrescan = -1", "local.rescan", "-1"),
+                ("[unsplash]\nmax_image_age = 4294967296", "unsplash.max_image_age", "4294967296"),
+                ("timeout = 1.5", "timeout", "1.5"),
+            ];
+            for &(config, key, value) in cases {
+                let msg = toml::from_str::<cfg::ConfigTable>(config).unwrap_err().to_string();
+                assert!(msg.contains(key) && msg.contains(value), "{}: {}", config, msg);
+            }
+        }
+    }
+}
+
+mod logging {
+    //! Module for the application logger. Messages always go to stderr, which ends up in the
+    //! journal under systemd, and can also go to a debug file with its own level.
+
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Write};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use env_logger;
+    use log::{self, Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter};
+
+    /// Size in bytes after which the debug file is rotated.
+    const MAX_FILE_BYTES: u64 = 10 * 1024 * 1024;
+
+    /// Handle for changing where messages go after the logger is installed.
+    pub struct Handle {
+        sinks: Arc<Mutex<Sinks>>,
+        max_level: MaxLogLevelFilter,
+    }
+
+    struct Logger {
+        sinks: Arc<Mutex<Sinks>>,
+    }
+
+    struct Sinks {
+        /// Filter from `RUST_LOG`, used for stderr unless a level is configured.
+        env: env_logger::Logger,
+        /// Configured level for stderr, if any.
+        journal: Option<LogLevelFilter>,
+        /// Debug file, if any.
+        file: Option<FileSink>,
+    }
+
+    struct FileSink {
+        path: PathBuf,
+        level: LogLevelFilter,
+        file: File,
+        written: u64,
+    }
+
+    /// Install the logger, writing to stderr as filtered by `RUST_LOG` until configured.
+    pub fn init() -> Handle {
+        let mut builder = env_logger::LogBuilder::new();
+        if let Ok(filters) = env::var("RUST_LOG") {
+            builder.parse(&filters);
+        }
+        let env = builder.build();
+        let env_filter = env.filter();
+
+        let sinks = Arc::new(Mutex::new(Sinks {
+            env: env,
+            journal: None,
+            file: None,
+        }));
+        let logger = Logger {
+            sinks: sinks.clone(),
+        };
+
+        let mut max_level = None;
+        log::set_logger(|max| {
+            max.set(env_filter);
+            max_level = Some(max);
+            Box::new(logger)
+        }).expect("logger installed twice");
+
+        Handle {
+            sinks: sinks,
+            max_level: max_level.unwrap(),
+        }
+    }
+
+    impl Handle {
+        /// Send messages up to `journal` to stderr instead of following `RUST_LOG`.
+        pub fn set_journal(&self, journal: Option<LogLevelFilter>) {
+            let mut sinks = self.sinks.lock().unwrap();
+            sinks.journal = journal;
+            self.max_level.set(sinks.max_level());
+        }
+
+        /// Send messages up to the file's level to the file, replacing any file opened before.
+        pub fn open_file(&self, file: Option<(PathBuf, LogLevelFilter)>) -> io::Result<()> {
+            let file = match file {
+                Some((path, level)) => Some(FileSink::open(path, level)?),
                 None => None,
             };
-            Ok(
-                secs.or(self.table.unsplash.as_ref().and_then(|t| t.refresh))
-                    .unwrap_or(def::UNSPLASH_REFRESH),
-            )
+
+            let mut sinks = self.sinks.lock().unwrap();
+            sinks.file = file;
+            self.max_level.set(sinks.max_level());
+            Ok(())
+        }
+    }
+
+    impl Log for Logger {
+        fn enabled(&self, metadata: &LogMetadata) -> bool {
+            let route = self.sinks.lock().unwrap().route(metadata);
+            route.journal || route.file
+        }
+
+        fn log(&self, record: &LogRecord) {
+            let mut sinks = self.sinks.lock().unwrap();
+            let route = sinks.route(record.metadata());
+            if route.journal {
+                let _ = writeln!(
+                    io::stderr(),
+                    "{}:{}: {}",
+                    record.level(),
+                    record.location().module_path(),
+                    record.args()
+                );
+            }
+            if let Some(ref mut file) = sinks.file {
+                if route.file {
+                    file.write(record);
+                }
+            }
+        }
+    }
+
+    /// Where a message goes.
+    #[derive(Debug, PartialEq)]
+    struct Route {
+        journal: bool,
+        file: bool,
+    }
+
+    impl Sinks {
+        fn route(&self, metadata: &LogMetadata) -> Route {
+            let env = || self.env.enabled(metadata);
+            route(metadata.level(), self.journal, env, self.file.as_ref().map(|f| f.level))
+        }
+
+        /// Most verbose level any sink takes.
+        fn max_level(&self) -> LogLevelFilter {
+            let file = self.file.as_ref().map(|f| f.level);
+            max_level(self.journal.unwrap_or_else(|| self.env.filter()), file)
+        }
+    }
+
+    /// Route a message of the given level: to stderr when it is within the configured journal
+    /// level, or when `RUST_LOG` lets it through without one, and to the file when there is one
+    /// and the message is within its level.
+    fn route<F>(
+        level: LogLevel,
+        journal: Option<LogLevelFilter>,
+        env: F,
+        file: Option<LogLevelFilter>,
+    ) -> Route
+    where
+        F: FnOnce() -> bool,
+    {
+        Route {
+            journal: match journal {
+                Some(max) => level <= max,
+                None => env(),
+            },
+            file: file.is_some_and(|max| level <= max),
+        }
+    }
+
+    fn max_level(journal: LogLevelFilter, file: Option<LogLevelFilter>) -> LogLevelFilter {
+        match file {
+            Some(f) if f > journal => f,
+            _ => journal,
+        }
+    }
+
+    impl FileSink {
+        fn open(path: PathBuf, level: LogLevelFilter) -> io::Result<FileSink> {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let written = file.metadata()?.len();
+            Ok(FileSink {
+                path: path,
+                level: level,
+                file: file,
+                written: written,
+            })
+        }
+
+        /// Append the message, moving the file aside to `<name>.1` once it grows too large.
+        fn write(&mut self, record: &LogRecord) {
+            let secs = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let line = format!(
+                "{} {}:{}: {}\n",
+                secs,
+                record.level(),
+                record.location().module_path(),
+                record.args()
+            );
+            if self.file.write_all(line.as_bytes()).is_ok() {
+                self.written += line.len() as u64;
+            }
+
+            if self.written >= MAX_FILE_BYTES {
+                if let Err(e) = self.rotate() {
+                    let path = self.path.display();
+                    let _ = writeln!(io::stderr(), "failed to rotate {}: {}", path, e);
+                }
+            }
+        }
+
+        fn rotate(&mut self) -> io::Result<()> {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, &rotated)?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.written = 0;
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const LEVELS: [LogLevel; 5] = [
+            LogLevel::Error,
+            LogLevel::Warn,
+            LogLevel::Info,
+            LogLevel::Debug,
+            LogLevel::Trace,
+        ];
+
+        #[test]
+        fn journal_level_takes_messages_up_to_it() {
+            for (i, &max) in LEVELS.iter().enumerate() {
+                for (j, &level) in LEVELS.iter().enumerate() {
+                    let env = || panic!("RUST_LOG consulted despite a journal level");
+                    let route = route(level, Some(max.to_log_level_filter()), env, None);
+                    assert_eq!(route.journal, j <= i, "{} message, journal at {}", level, max);
+                    assert!(!route.file);
+                }
+            }
+
+            let off = route(LogLevel::Error, Some(LogLevelFilter::Off), || true, None);
+            assert!(!off.journal);
+        }
+
+        #[test]
+        fn rust_log_decides_without_a_journal_level() {
+            for &level in &LEVELS {
+                assert!(route(level, None, || true, None).journal);
+                assert!(!route(level, None, || false, None).journal);
+            }
+        }
+
+        #[test]
+        fn file_takes_messages_up_to_its_own_level() {
+            for (i, &max) in LEVELS.iter().enumerate() {
+                for (j, &level) in LEVELS.iter().enumerate() {
+                    let file = Some(max.to_log_level_filter());
+                    let quiet = route(level, Some(LogLevelFilter::Error), || false, file);
+                    assert_eq!(quiet.file, j <= i, "{} message, file at {}", level, max);
+                    assert_eq!(quiet.journal, j == 0);
+                }
+            }
+        }
+
+        #[test]
+        fn max_level_is_the_most_verbose_sink() {
+            let cases = [
+                (LogLevelFilter::Info, None, LogLevelFilter::Info),
+                (LogLevelFilter::Info, Some(LogLevelFilter::Debug), LogLevelFilter::Debug),
+                (LogLevelFilter::Trace, Some(LogLevelFilter::Warn), LogLevelFilter::Trace),
+                (LogLevelFilter::Off, Some(LogLevelFilter::Error), LogLevelFilter::Error),
+                (LogLevelFilter::Off, None, LogLevelFilter::Off),
+            ];
+            for &(journal, file, want) in &cases {
+                assert_eq!(max_level(journal, file), want, "{} and {:?}", journal, file);
+            }
         }
     }
 }
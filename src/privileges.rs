@@ -0,0 +1,262 @@
+//! Module for dropping root privileges before doing any network or file work.
+
+use std::env;
+use std::error::Error;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use libc;
+
+use errors::WallsplashError;
+use cache;
+use usage;
+
+/// Directory holding the runtime directory of each logged-in user, named by user ID.
+const RUNTIME_ROOT: &'static str = "/run/user";
+
+/// What needs to happen to run as the target user.
+#[derive(Debug, PartialEq)]
+pub struct Plan {
+    /// User ID to switch to.
+    pub uid: libc::uid_t,
+    /// Group ID to switch to.
+    pub gid: libc::gid_t,
+    /// Home directory of the target user.
+    pub home: PathBuf,
+    /// Directories to create and hand over to the target user, parents first.
+    pub dirs: Vec<PathBuf>,
+    /// Runtime directory of the target user, if they have one.
+    pub runtime_dir: Option<PathBuf>,
+}
+
+/// Whether the process is running as root.
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Drop root privileges to the `user:group` (or `user`, using their primary group) given in the
+/// spec. Does nothing when not running as root.
+///
+/// # Errors
+///
+/// Returns an error when the user or group cannot be found, or when any step of switching users
+/// fails, in which case the caller must not continue running as root.
+pub fn drop_to(spec: &str) -> Result<(), Box<Error>> {
+    if !is_root() {
+        debug!("not running as root, ignoring run_as {}", spec);
+        return Ok(());
+    }
+
+    let plan = plan(spec)?;
+    debug!("privilege drop plan: {:?}", plan);
+    apply(&plan)
+}
+
+/// Resolve the spec into the IDs and directories needed to run as that user.
+pub fn plan(spec: &str) -> Result<Plan, Box<Error>> {
+    let (user, group) = split_spec(spec);
+    let (uid, primary_gid, home) = lookup_user(user)?;
+    let gid = match group {
+        Some(g) => lookup_group(g)?,
+        None => primary_gid,
+    };
+    Ok(plan_for(uid, gid, home))
+}
+
+/// Split a `user[:group]` spec into its user and group names.
+fn split_spec(spec: &str) -> (&str, Option<&str>) {
+    let mut parts = spec.splitn(2, ':');
+    (parts.next().unwrap_or(""), parts.next())
+}
+
+/// Work out which directories need handing over to the user with the given home.
+fn plan_for(uid: libc::uid_t, gid: libc::gid_t, home: PathBuf) -> Plan {
+    let mut dirs = Vec::new();
+    let config = home.join(".config");
+    if !config.is_dir() {
+        dirs.push(config);
+    }
+    let cache = cache::cache_path(&home);
+    if let Some(app) = cache.parent() {
+        dirs.push(app.to_path_buf());
+    }
+    dirs.push(cache);
+
+    // Parents of the state directory that already exist may be shared with other programs, so
+    // only those created here are handed over. Root's XDG_STATE_HOME is no place for the target
+    // user's state, so it is not followed.
+    let state = usage::home_state_path(&home);
+    if let Ok(rel) = state.strip_prefix(&home) {
+        let mut parent = home.clone();
+        for part in rel.parent().into_iter().flat_map(|p| p.iter()) {
+            parent.push(part);
+            if !parent.is_dir() {
+                dirs.push(parent.clone());
+            }
+        }
+    }
+    dirs.push(state);
+
+    let runtime_dir = Path::new(RUNTIME_ROOT).join(uid.to_string());
+    Plan {
+        uid: uid,
+        gid: gid,
+        home: home,
+        dirs: dirs,
+        runtime_dir: if runtime_dir.is_dir() { Some(runtime_dir) } else { None },
+    }
+}
+
+/// Create the directories owned by the target user and switch to it for good.
+fn apply(plan: &Plan) -> Result<(), Box<Error>> {
+    for dir in &plan.dirs {
+        fs::create_dir_all(dir)?;
+        chown(dir, plan.uid, plan.gid)?;
+    }
+
+    // The rest of root's session must not follow into the target user's, least of all into the
+    // setters, which see these variables.
+    env::set_var("HOME", &plan.home);
+    env::remove_var("XDG_STATE_HOME");
+    env::remove_var("DBUS_SESSION_BUS_ADDRESS");
+    match plan.runtime_dir {
+        Some(ref dir) => {
+            env::set_var("XDG_RUNTIME_DIR", dir);
+            let bus = dir.join("bus");
+            if bus.exists() {
+                env::set_var("DBUS_SESSION_BUS_ADDRESS", format!("unix:path={}", bus.display()));
+            }
+        }
+        None => env::remove_var("XDG_RUNTIME_DIR"),
+    }
+
+    unsafe {
+        if libc::setgroups(1, &plan.gid) != 0 || libc::setgid(plan.gid) != 0
+            || libc::setuid(plan.uid) != 0
+        {
+            return Err(Box::new(io::Error::last_os_error()));
+        }
+        if libc::setuid(0) == 0 {
+            return Err(Box::new(WallsplashError::PrivilegeDropFailed));
+        }
+    }
+
+    info!("dropped privileges to uid {} gid {}", plan.uid, plan.gid);
+    Ok(())
+}
+
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t, PathBuf), Box<Error>> {
+    let c_name = CString::new(name)?;
+    unsafe {
+        let pw = libc::getpwnam(c_name.as_ptr());
+        if pw.is_null() {
+            return Err(Box::new(WallsplashError::UnknownUser));
+        }
+        let home = CStr::from_ptr((*pw).pw_dir).to_bytes();
+        let home = PathBuf::from(OsStr::from_bytes(home));
+        Ok(((*pw).pw_uid, (*pw).pw_gid, home))
+    }
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t, Box<Error>> {
+    let c_name = CString::new(name)?;
+    unsafe {
+        let gr = libc::getgrnam(c_name.as_ptr());
+        if gr.is_null() {
+            return Err(Box::new(WallsplashError::UnknownGroup));
+        }
+        Ok((*gr).gr_gid)
+    }
+}
+
+fn chown(path: &Path, uid: libc::uid_t, gid: libc::gid_t) -> Result<(), Box<Error>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+        return Err(Box::new(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn split_user_and_group() {
+        assert_eq!(split_spec("kiosk"), ("kiosk", None));
+        assert_eq!(split_spec("kiosk:video"), ("kiosk", Some("video")));
+        assert_eq!(split_spec("kiosk:"), ("kiosk", Some("")));
+        assert_eq!(split_spec(":video"), ("", Some("video")));
+        assert_eq!(split_spec("a:b:c"), ("a", Some("b:c")));
+        assert_eq!(split_spec(""), ("", None));
+    }
+
+    #[test]
+    fn plan_resolves_root() {
+        let root = plan("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert!(root.dirs.contains(&cache::cache_path(&root.home)));
+        assert_eq!(root.dirs.last(), Some(&usage::home_state_path(&root.home)));
+
+        let root = plan("root:root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+    }
+
+    #[test]
+    fn plan_rejects_unknown_names() {
+        let err = plan("wallsplash-no-such-user").unwrap_err();
+        assert!(err.is::<WallsplashError>());
+        assert_eq!(err.to_string(), WallsplashError::UnknownUser.to_string());
+
+        let err = plan("root:wallsplash-no-such-group").unwrap_err();
+        assert_eq!(err.to_string(), WallsplashError::UnknownGroup.to_string());
+
+        let err = plan("root:").unwrap_err();
+        assert_eq!(err.to_string(), WallsplashError::UnknownGroup.to_string());
+
+        assert!(plan("").is_err());
+        assert!(plan("ro\0ot").is_err());
+    }
+
+    #[test]
+    fn plan_creates_missing_parent_dirs() {
+        let home = env::temp_dir().join(format!("wallsplash-home-{}", process::id()));
+        let _ = fs::remove_dir_all(&home);
+        // Root's own state directory must not leak into the plan.
+        env::set_var("XDG_STATE_HOME", "/root/.local/state");
+
+        let fresh = plan_for(1000, 100, home.clone());
+        assert_eq!((fresh.uid, fresh.gid), (1000, 100));
+        assert_eq!(fresh.runtime_dir.is_some(), Path::new("/run/user/1000").is_dir());
+        assert_eq!(
+            fresh.dirs,
+            vec![
+                home.join(".config"),
+                home.join(".config/wallsplash"),
+                home.join(".config/wallsplash/cache"),
+                home.join(".local"),
+                home.join(".local/state"),
+                home.join(".local/state/wallsplash"),
+            ]
+        );
+
+        fs::create_dir_all(home.join(".config")).unwrap();
+        fs::create_dir_all(home.join(".local/state")).unwrap();
+        let existing = plan_for(1000, 100, home.clone());
+        assert_eq!(
+            existing.dirs,
+            vec![
+                home.join(".config/wallsplash"),
+                home.join(".config/wallsplash/cache"),
+                home.join(".local/state/wallsplash"),
+            ]
+        );
+    }
+}
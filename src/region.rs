@@ -0,0 +1,114 @@
+//! Module for working out a coarse region that remote images can be localized to.
+
+use std::env;
+use std::fs;
+use std::io::Read;
+
+/// Derive a coarse region from the system timezone, like `Los Angeles` for
+/// `America/Los_Angeles`. Nothing is looked up over the network.
+pub fn from_timezone() -> Option<String> {
+    let zone = env::var("TZ")
+        .ok()
+        .and_then(|tz| zone_of_tz(&tz))
+        .or_else(|| {
+            let mut content = String::new();
+            fs::File::open("/etc/timezone")
+                .and_then(|mut f| f.read_to_string(&mut content))
+                .ok()
+                .map(|_| content.trim().to_owned())
+                .and_then(|zone| if zone.is_empty() { None } else { Some(zone) })
+        })
+        .or_else(|| {
+            let target = fs::read_link("/etc/localtime").ok()?;
+            zone_of_link(&target.to_string_lossy())
+        })?;
+    region_of(&zone)
+}
+
+/// The timezone named by a `TZ` value, which may start with a colon.
+fn zone_of_tz(tz: &str) -> Option<String> {
+    let zone = tz.trim().trim_start_matches(':');
+    if zone.is_empty() {
+        None
+    } else {
+        Some(zone.to_owned())
+    }
+}
+
+/// The timezone a `/etc/localtime` symlink points at, going by its path into the zoneinfo
+/// database.
+fn zone_of_link(target: &str) -> Option<String> {
+    let start = target.find("zoneinfo/")? + "zoneinfo/".len();
+    // Some systems keep variants of the database in `posix/` or `right/` subdirectories.
+    let zone = target[start..]
+        .trim_start_matches("posix/")
+        .trim_start_matches("right/");
+    if zone.is_empty() {
+        None
+    } else {
+        Some(zone.to_owned())
+    }
+}
+
+/// The place a timezone is named after, if it names one.
+fn region_of(zone: &str) -> Option<String> {
+    // Zones like UTC or Etc/GMT+2 say nothing about where the machine is.
+    let mut parts = zone.split('/');
+    let area = parts.next()?;
+    let place = parts.next_back()?;
+    if area == "Etc" || place.is_empty() {
+        return None;
+    }
+    Some(place.replace('_', " "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn region_of_named_zones() {
+        assert_eq!(region_of("America/Los_Angeles"), Some("Los Angeles".to_owned()));
+        assert_eq!(region_of("Europe/Lisbon"), Some("Lisbon".to_owned()));
+        assert_eq!(
+            region_of("America/Argentina/Buenos_Aires"),
+            Some("Buenos Aires".to_owned())
+        );
+    }
+
+    #[test]
+    fn region_of_zones_without_a_place() {
+        assert_eq!(region_of("UTC"), None);
+        assert_eq!(region_of("Etc/GMT+2"), None);
+        assert_eq!(region_of("Etc/UTC"), None);
+        assert_eq!(region_of("America/"), None);
+        assert_eq!(region_of(""), None);
+    }
+
+    #[test]
+    fn zone_from_tz() {
+        assert_eq!(zone_of_tz("Asia/Tokyo"), Some("Asia/Tokyo".to_owned()));
+        assert_eq!(zone_of_tz(":Asia/Tokyo"), Some("Asia/Tokyo".to_owned()));
+        assert_eq!(zone_of_tz(" Asia/Tokyo\n"), Some("Asia/Tokyo".to_owned()));
+        assert_eq!(zone_of_tz(":"), None);
+        assert_eq!(zone_of_tz(""), None);
+    }
+
+    #[test]
+    fn zone_from_localtime_link() {
+        assert_eq!(
+            zone_of_link("/usr/share/zoneinfo/Europe/Berlin"),
+            Some("Europe/Berlin".to_owned())
+        );
+        assert_eq!(
+            zone_of_link("../usr/share/zoneinfo/posix/Australia/Sydney"),
+            Some("Australia/Sydney".to_owned())
+        );
+        assert_eq!(
+            zone_of_link("/var/db/timezone/zoneinfo/America/New_York"),
+            Some("America/New_York".to_owned())
+        );
+        assert_eq!(zone_of_link("/usr/share/zoneinfo/"), None);
+        assert_eq!(zone_of_link("/etc/localtime.orig"), None);
+    }
+}
@@ -0,0 +1,399 @@
+//! Module for choosing the order that a source's images are shown in.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use rand::{self, Rng, XorShiftRng};
+
+/// Order to walk through a source's images.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShuffleMode {
+    /// One after another, in listing order.
+    Sequential,
+    /// Any image but the one shown last, picked independently each time.
+    Random,
+    /// Every image once per pass in a random order, reshuffling after each pass.
+    ShuffledCycle,
+}
+
+impl FromStr for ShuffleMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ShuffleMode, String> {
+        match s {
+            "sequential" => Ok(ShuffleMode::Sequential),
+            "random" => Ok(ShuffleMode::Random),
+            "shuffle" => Ok(ShuffleMode::ShuffledCycle),
+            _ => Err(format!(
+                "invalid order '{}', expected sequential, random or shuffle",
+                s
+            )),
+        }
+    }
+}
+
+/// Position within a source's images.
+pub struct Rotation {
+    /// Order to walk through the images.
+    order: ShuffleMode,
+    /// Index of next image to use, when sequential.
+    next: usize,
+    /// Indices not yet shown in this pass, when shuffling.
+    bag: Vec<usize>,
+    /// Number of images the bag was filled for.
+    bag_len: usize,
+    /// Index of the image shown last.
+    last: Option<usize>,
+    /// Generator the random picks and shuffled passes come from.
+    rng: XorShiftRng,
+    /// File the position is kept in across restarts, if any.
+    path: Option<PathBuf>,
+}
+
+impl fmt::Debug for Rotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rotation")
+            .field("order", &self.order)
+            .field("next", &self.next)
+            .field("bag", &self.bag)
+            .field("bag_len", &self.bag_len)
+            .field("last", &self.last)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl Rotation {
+    pub fn new(order: ShuffleMode) -> Rotation {
+        Rotation::with_rng(order, rand::thread_rng().gen())
+    }
+
+    /// Create a rotation that picks with the given generator, so that a seeded one gives the
+    /// same order every time.
+    pub fn with_rng(order: ShuffleMode, rng: XorShiftRng) -> Rotation {
+        Rotation {
+            order: order,
+            next: 0,
+            bag: Vec::new(),
+            bag_len: 0,
+            last: None,
+            rng: rng,
+            path: None,
+        }
+    }
+
+    /// Create a rotation that picks up where the one kept in the given file left off, and keeps
+    /// its own position there from now on. A file that cannot be read starts from scratch.
+    pub fn load(order: ShuffleMode, path: &Path) -> Rotation {
+        let mut rotation = Rotation::new(order);
+        let mut content = String::new();
+        let read = fs::File::open(path).and_then(|mut f| f.read_to_string(&mut content));
+        if read.is_ok() {
+            rotation.restore(&content);
+        }
+        rotation.path = Some(path.to_path_buf());
+        rotation
+    }
+
+    /// Pick the index of the next image out of `len` images. Returns `None` when there are no
+    /// images. A shuffled pass starts over whenever the number of images changes.
+    pub fn next(&mut self, len: usize) -> Option<usize> {
+        if len == 0 {
+            return None;
+        }
+
+        let idx = match self.order {
+            ShuffleMode::Sequential => {
+                let idx = self.next % len;
+                self.next = idx + 1;
+                idx
+            }
+            ShuffleMode::Random => match self.last {
+                // Skip over the image shown last by picking among the others.
+                Some(last) if len > 1 && last < len => {
+                    let idx = self.rng.gen_range(0, len - 1);
+                    if idx >= last {
+                        idx + 1
+                    } else {
+                        idx
+                    }
+                }
+                _ => self.rng.gen_range(0, len),
+            },
+            ShuffleMode::ShuffledCycle => {
+                if self.bag.is_empty() || self.bag_len != len {
+                    self.refill(len);
+                }
+                self.bag.pop().unwrap()
+            }
+        };
+
+        self.last = Some(idx);
+        self.save();
+        Some(idx)
+    }
+
+    /// Pick one of `len` indices at random, with the rotation's own generator.
+    pub fn random_index(&mut self, len: usize) -> usize {
+        self.rng.gen_range(0, len)
+    }
+
+    /// Continue from the given index, starting a fresh pass when shuffling.
+    pub fn restart_at(&mut self, idx: usize) {
+        self.next = idx;
+        self.bag.clear();
+        self.save();
+    }
+
+    /// Start a new shuffled pass over `len` images, never starting with the image shown last.
+    fn refill(&mut self, len: usize) {
+        self.bag = (0..len).collect();
+        self.rng.shuffle(&mut self.bag);
+        self.bag_len = len;
+
+        if len > 1 && self.bag.last() == self.last.as_ref() {
+            self.bag.swap(0, len - 1);
+        }
+    }
+
+    /// Take over the position written by `save`, ignoring anything malformed.
+    fn restore(&mut self, content: &str) {
+        for line in content.lines() {
+            let mut parts = line.split_whitespace();
+            let key = parts.next();
+            let values: Option<Vec<usize>> = parts.map(|n| n.parse().ok()).collect();
+            match (key, values) {
+                (Some("next"), Some(ref v)) if v.len() == 1 => self.next = v[0],
+                (Some("last"), Some(ref v)) if v.len() == 1 => self.last = Some(v[0]),
+                (Some("len"), Some(ref v)) if v.len() == 1 => self.bag_len = v[0],
+                (Some("bag"), Some(v)) => self.bag = v,
+                _ => {}
+            }
+        }
+
+        if self.bag.iter().any(|&i| i >= self.bag_len) {
+            self.bag.clear();
+        }
+    }
+
+    /// Write the position to the rotation's file, if it has one.
+    fn save(&self) {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return,
+        };
+        if let Err(e) = self.write(path) {
+            warn!("failed to save rotation to {}: {}", path.display(), e);
+        }
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let mut content = format!("next {}\nlen {}\n", self.next, self.bag_len);
+        if let Some(last) = self.last {
+            content.push_str(&format!("last {}\n", last));
+        }
+        let bag: Vec<String> = self.bag.iter().map(|i| i.to_string()).collect();
+        content.push_str(&format!("bag {}\n", bag.join(" ")));
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension(format!("{}", process::id()));
+        fs::File::create(&tmp)?.write_all(content.as_bytes())?;
+        fs::rename(&tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn seeded(order: ShuffleMode, seed: u32) -> Rotation {
+        Rotation::with_rng(order, XorShiftRng::from_seed([seed, 1, 2, 3]))
+    }
+
+    /// Walk `passes` full passes over `len` images, checking every pass shows each image once
+    /// and that no image is shown twice in a row.
+    fn check_passes(rotation: &mut Rotation, len: usize, passes: usize, last: &mut Option<usize>) {
+        for _ in 0..passes {
+            let mut seen = vec![false; len];
+            for _ in 0..len {
+                let idx = rotation.next(len).unwrap();
+                assert!(idx < len);
+                assert!(!seen[idx], "image {} shown twice in one pass of {}", idx, len);
+                if len > 1 {
+                    assert!(*last != Some(idx), "image {} repeated across passes", idx);
+                }
+                seen[idx] = true;
+                *last = Some(idx);
+            }
+        }
+    }
+
+    #[test]
+    fn shuffle_covers_every_image_without_repeats() {
+        for seed in 0..200 {
+            let len = seed as usize % 19 + 1;
+            let mut rotation = seeded(ShuffleMode::ShuffledCycle, seed);
+            check_passes(&mut rotation, len, 5, &mut None);
+        }
+    }
+
+    #[test]
+    fn shuffle_never_repeats_when_the_count_changes() {
+        for seed in 0..200 {
+            let mut rotation = seeded(ShuffleMode::ShuffledCycle, seed);
+            let mut last = None;
+            for step in 0..5 {
+                let len = (seed as usize + step * 7) % 10 + 2;
+                check_passes(&mut rotation, len, 2, &mut last);
+            }
+        }
+    }
+
+    #[test]
+    fn shuffle_is_repeatable_with_the_same_seed() {
+        let walk = |seed| {
+            let mut rotation = seeded(ShuffleMode::ShuffledCycle, seed);
+            (0..20).map(|_| rotation.next(8).unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(walk(7), walk(7));
+        assert!(walk(7) != walk(8));
+    }
+
+    #[test]
+    fn shuffled_pass_survives_a_restart() {
+        let path = env::temp_dir().join(format!("wallsplash-rotation-{}", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut before = Rotation::load(ShuffleMode::ShuffledCycle, &path);
+        let mut shown: Vec<usize> = (0..4).map(|_| before.next(10).unwrap()).collect();
+        drop(before);
+
+        let mut after = Rotation::load(ShuffleMode::ShuffledCycle, &path);
+        shown.extend((0..6).map(|_| after.next(10).unwrap()));
+        shown.sort();
+        assert_eq!(shown, (0..10).collect::<Vec<_>>());
+
+        let mut last = after.last;
+        check_passes(&mut after, 10, 2, &mut last);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sequential_position_survives_a_restart() {
+        let path = env::temp_dir().join(format!("wallsplash-rotation-seq-{}", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut before = Rotation::load(ShuffleMode::Sequential, &path);
+        before.next(5);
+        before.next(5);
+        let mut after = Rotation::load(ShuffleMode::Sequential, &path);
+        assert_eq!(after.next(5), Some(2));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn malformed_state_starts_from_scratch() {
+        let mut rotation = seeded(ShuffleMode::ShuffledCycle, 1);
+        rotation.restore("next x\nlen 3\nbag 0 7 1\nlast 2 3\n");
+        assert_eq!(rotation.next, 0);
+        assert_eq!(rotation.last, None);
+        assert!(rotation.bag.is_empty());
+        check_passes(&mut rotation, 3, 2, &mut None);
+    }
+
+    #[test]
+    fn shuffle_restart_starts_a_fresh_pass() {
+        let mut rotation = seeded(ShuffleMode::ShuffledCycle, 3);
+        rotation.next(6);
+        rotation.next(6);
+        rotation.restart_at(0);
+        let mut last = rotation.last;
+        check_passes(&mut rotation, 6, 3, &mut last);
+    }
+
+    #[test]
+    fn sequential_walks_in_order() {
+        let mut rotation = Rotation::new(ShuffleMode::Sequential);
+        let shown: Vec<usize> = (0..7).map(|_| rotation.next(3).unwrap()).collect();
+        assert_eq!(shown, vec![0, 1, 2, 0, 1, 2, 0]);
+        check_passes(&mut rotation, 3, 3, &mut Some(0));
+
+        rotation.restart_at(2);
+        assert_eq!(rotation.next(4), Some(2));
+        assert_eq!(rotation.next(2), Some(1));
+        assert_eq!(rotation.next(2), Some(0));
+    }
+
+    #[test]
+    fn no_images_gives_nothing() {
+        for &order in &[ShuffleMode::Sequential, ShuffleMode::Random, ShuffleMode::ShuffledCycle] {
+            let mut rotation = Rotation::new(order);
+            assert_eq!(rotation.next(0), None);
+            assert_eq!(rotation.next(1), Some(0));
+            assert_eq!(rotation.next(1), Some(0));
+        }
+    }
+
+    #[test]
+    fn random_picks_independently_but_never_repeats() {
+        let walk = |seed| {
+            let mut rotation = seeded(ShuffleMode::Random, seed);
+            (0..200).map(|_| rotation.next(5).unwrap()).collect::<Vec<_>>()
+        };
+        let picks = walk(7);
+        assert_eq!(picks, walk(7));
+        assert!(picks != walk(8));
+        assert!(picks.windows(2).all(|w| w[0] != w[1]), "{:?}", picks);
+        for idx in 0..5 {
+            assert!(picks.contains(&idx), "image {} never picked", idx);
+        }
+        // Unlike a shuffled pass, an image may come back before every other one was shown.
+        let cycled = picks.chunks(5).all(|pass| {
+            let mut pass = pass.to_vec();
+            pass.sort();
+            pass == vec![0, 1, 2, 3, 4]
+        });
+        assert!(!cycled);
+    }
+
+    #[test]
+    fn random_handles_a_shrinking_listing() {
+        let mut rotation = seeded(ShuffleMode::Random, 5);
+        rotation.last = Some(9);
+        for _ in 0..50 {
+            let idx = rotation.next(3).unwrap();
+            assert!(idx < 3);
+        }
+        rotation.last = Some(0);
+        assert_eq!(rotation.next(2), Some(1));
+        assert_eq!(rotation.next(1), Some(0));
+    }
+
+    #[test]
+    fn sequential_ignores_the_generator() {
+        for seed in 0..5 {
+            let mut rotation = seeded(ShuffleMode::Sequential, seed);
+            let shown: Vec<usize> = (0..5).map(|_| rotation.next(4).unwrap()).collect();
+            assert_eq!(shown, vec![0, 1, 2, 3, 0]);
+        }
+    }
+
+    #[test]
+    fn parse_order() {
+        assert_eq!("sequential".parse(), Ok(ShuffleMode::Sequential));
+        assert_eq!("random".parse(), Ok(ShuffleMode::Random));
+        assert_eq!("shuffle".parse(), Ok(ShuffleMode::ShuffledCycle));
+        assert!("shuffled".parse::<ShuffleMode>().is_err());
+        assert!("Random".parse::<ShuffleMode>().is_err());
+    }
+}
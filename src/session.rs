@@ -0,0 +1,99 @@
+//! Module for detecting whether the user session is locked.
+
+use std::env;
+use std::error::Error;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use errors::WallsplashError;
+use hold;
+use setters;
+
+/// Screen lockers that run only while the screen is locked.
+const SCREEN_LOCKERS: &'static [&'static str] = &[
+    "i3lock",
+    "swaylock",
+    "slock",
+    "xsecurelock",
+    "physlock",
+    "waylock",
+];
+
+/// What to do with a rotation that comes due while the session is locked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnLocked {
+    /// Drop the rotation entirely.
+    Skip,
+    /// Hold the rotation and perform it as soon as the session is unlocked.
+    Defer,
+}
+
+impl FromStr for OnLocked {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OnLocked, String> {
+        match s {
+            "skip" => Ok(OnLocked::Skip),
+            "defer" => Ok(OnLocked::Defer),
+            _ => Err(format!(
+                "invalid on_locked value '{}', expected skip or defer",
+                s
+            )),
+        }
+    }
+}
+
+pub trait LockProbe {
+    /// Returns whether the user session is currently locked.
+    fn is_locked(&self) -> Result<bool, Box<Error>>;
+}
+
+/// Probe that asks logind for the session's `LockedHint`, falling back to looking for a running
+/// screen locker process when logind cannot answer.
+#[derive(Debug)]
+pub struct SessionLockProbe;
+
+impl LockProbe for SessionLockProbe {
+    fn is_locked(&self) -> Result<bool, Box<Error>> {
+        match logind_locked_hint() {
+            Ok(locked) => Ok(locked),
+            Err(e) => {
+                debug!("logind lock state unavailable: {}", e);
+                Ok(locker_running())
+            }
+        }
+    }
+}
+
+/// Query the `LockedHint` property of the current logind session.
+fn logind_locked_hint() -> Result<bool, Box<Error>> {
+    let session = env::var("XDG_SESSION_ID")?;
+    let mut loginctl = setters::sanitized_command("loginctl", &[]);
+    loginctl
+        .arg("show-session")
+        .arg(session)
+        .arg("--property=LockedHint")
+        .stdout(Stdio::piped());
+    let output = match hold::run_bounded(&mut loginctl)? {
+        Some(output) => output,
+        None => return Err(Box::new(WallsplashError::LockStateUnknown)),
+    };
+
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "LockedHint=yes" => Ok(true),
+        "LockedHint=no" => Ok(false),
+        _ => Err(Box::new(WallsplashError::LockStateUnknown)),
+    }
+}
+
+/// Whether any known screen locker process is running.
+fn locker_running() -> bool {
+    SCREEN_LOCKERS.iter().any(|name| {
+        let mut pgrep = setters::sanitized_command("pgrep", &[]);
+        pgrep.arg("-x").arg(name).stdout(Stdio::null());
+        match hold::run_bounded(&mut pgrep) {
+            Ok(Some(output)) => output.status.success(),
+            _ => false,
+        }
+    })
+}
@@ -0,0 +1,904 @@
+//! Module for setters that put an image on the desktop.
+
+use std::cell::RefCell;
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::fs;
+use std::io::Read;
+#[cfg(target_os = "macos")]
+use std::process;
+#[cfg(target_os = "macos")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use reqwest::Url;
+
+use cache;
+use errors::WallsplashError;
+
+/// Names of the setters that can be chosen by name.
+#[cfg(not(target_os = "macos"))]
+pub const SETTERS: &'static [&'static str] = &["feh", "gnome", "nitrogen", "sway", "swaybg"];
+
+/// Names of the setters that can be chosen by name.
+#[cfg(target_os = "macos")]
+pub const SETTERS: &'static [&'static str] =
+    &["feh", "gnome", "macos", "nitrogen", "sway", "swaybg"];
+
+/// Placeholder for the image path in a setter command template.
+const PATH_PLACEHOLDER: &'static str = "{path}";
+
+/// GSettings schema holding the GNOME desktop background.
+const GNOME_BACKGROUND: &'static str = "org.gnome.desktop.background";
+
+/// Environment variables passed on to the wallpaper setter.
+const SETTER_ENV: &'static [&'static str] = &[
+    "DISPLAY",
+    "XAUTHORITY",
+    "WAYLAND_DISPLAY",
+    "XDG_RUNTIME_DIR",
+    "DBUS_SESSION_BUS_ADDRESS",
+    "HOME",
+    "PATH",
+];
+
+/// Phrases, in lowercase, that setters print when there is no display to draw on.
+const NO_DISPLAY_MESSAGES: &'static [&'static str] = &[
+    "can't open x display",
+    "cannot open display",
+    "unable to open display",
+    "no display",
+];
+
+/// Environment variables swaymsg finds the running Sway by, on top of the usual ones.
+const SWAY_ENV: &'static [&'static str] = &["SWAYSOCK", "I3SOCK"];
+
+/// What to do when there is no display to set the wallpaper on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Headless {
+    /// Log the failure on every rotation.
+    Fail,
+    /// Keep rotating quietly and set the current image as soon as a display appears.
+    Continue,
+}
+
+impl FromStr for Headless {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Headless, String> {
+        match s {
+            "fail" => Ok(Headless::Fail),
+            "continue" => Ok(Headless::Continue),
+            _ => Err(format!(
+                "invalid headless value '{}', expected fail or continue",
+                s
+            )),
+        }
+    }
+}
+
+/// How an image is fit onto the screen, for setters that support it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scaling {
+    /// Scale to cover the screen, cropping what does not fit.
+    Fill,
+    /// Scale to fit inside the screen, keeping the aspect ratio.
+    Max,
+    /// Stretch to the screen size, ignoring the aspect ratio.
+    Scale,
+    /// Show at its original size in the middle of the screen.
+    Center,
+    /// Repeat at its original size across the screen.
+    Tile,
+}
+
+impl FromStr for Scaling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Scaling, String> {
+        match s {
+            "fill" => Ok(Scaling::Fill),
+            "max" | "fit" => Ok(Scaling::Max),
+            "scale" | "stretch" => Ok(Scaling::Scale),
+            "center" => Ok(Scaling::Center),
+            "tile" => Ok(Scaling::Tile),
+            _ => Err(format!(
+                "invalid scaling '{}', expected fill, max, scale, center, or tile",
+                s
+            )),
+        }
+    }
+}
+
+/// Build the setter with the given name, passing the extra environment variables on to it.
+pub fn setter_by_name(
+    name: &str,
+    passthrough: Vec<String>,
+    scaling: Scaling,
+) -> Result<Box<WallpaperSetter>, String> {
+    match name {
+        "feh" => Ok(Box::new(FehSetter::new(passthrough, scaling))),
+        "gnome" => Ok(Box::new(GnomeSetter::new(passthrough))),
+        #[cfg(target_os = "macos")]
+        "macos" => Ok(Box::new(MacosSetter::new(passthrough))),
+        "nitrogen" => Ok(Box::new(NitrogenSetter::new(passthrough, scaling))),
+        "sway" => Ok(Box::new(SwaySetter::new(passthrough, scaling))),
+        "swaybg" => Ok(Box::new(SwaybgSetter::new(passthrough, scaling))),
+        _ => Err(format!(
+            "unknown setter '{}', expected one of {}",
+            name,
+            SETTERS.join(", ")
+        )),
+    }
+}
+
+/// Program or API that puts an image on the desktop.
+///
+/// # Examples
+///
+/// ```
+/// use std::error::Error;
+/// use std::path::Path;
+///
+/// use wallsplash::WallpaperSetter;
+///
+/// /// Setter that only prints the image it was given.
+/// #[derive(Debug)]
+/// struct PrintSetter;
+///
+/// impl WallpaperSetter for PrintSetter {
+///     fn set(&self, path: &Path) -> Result<(), Box<Error>> {
+///         println!("wallpaper: {}", path.display());
+///         Ok(())
+///     }
+/// }
+///
+/// PrintSetter.set(Path::new("/tmp/wallpaper.jpg")).unwrap();
+/// ```
+pub trait WallpaperSetter: Debug {
+    /// Sets the image as the desktop wallpaper.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NoDisplay` when there is no display to set the wallpaper on, so that headless
+    /// mode can tell it apart from other failures.
+    fn set(&self, path: &Path) -> Result<(), Box<Error>>;
+
+    /// Returns the image currently set as the wallpaper, when this setter can tell.
+    fn current(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Setter that runs `feh` with one of its `--bg-*` modes.
+#[derive(Debug)]
+pub struct FehSetter {
+    /// Extra environment variables to pass on to feh.
+    passthrough: Vec<String>,
+    /// How to fit the image onto the screen.
+    scaling: Scaling,
+}
+
+impl FehSetter {
+    pub fn new(passthrough: Vec<String>, scaling: Scaling) -> Self {
+        FehSetter {
+            passthrough: passthrough,
+            scaling: scaling,
+        }
+    }
+}
+
+impl Default for FehSetter {
+    fn default() -> FehSetter {
+        FehSetter::new(Vec::new(), Scaling::Fill)
+    }
+}
+
+impl WallpaperSetter for FehSetter {
+    /// Returns `SetterCommandFailed` when feh fails for any reason other than a missing display,
+    /// and the I/O error when it cannot be run at all.
+    fn set(&self, path: &Path) -> Result<(), Box<Error>> {
+        let mode = match self.scaling {
+            Scaling::Fill => "--bg-fill",
+            Scaling::Max => "--bg-max",
+            Scaling::Scale => "--bg-scale",
+            Scaling::Center => "--bg-center",
+            Scaling::Tile => "--bg-tile",
+        };
+        run_setter(
+            "feh",
+            sanitized_command("feh", &self.passthrough)
+                .arg(mode)
+                .arg(absolute(path)?),
+        )
+    }
+
+    /// Reads the image from the `~/.fehbg` script that feh writes after setting a wallpaper.
+    fn current(&self) -> Option<PathBuf> {
+        let mut script = String::new();
+        fs::File::open(env::home_dir()?.join(".fehbg"))
+            .and_then(|mut f| f.read_to_string(&mut script))
+            .ok()?;
+
+        let line = script.lines().rfind(|l| l.starts_with("feh "))?;
+        let end = line.rfind('\'')?;
+        let start = line[..end].rfind('\'')?;
+        let path = PathBuf::from(&line[start + 1..end]);
+        if path.is_file() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+}
+
+/// Setter that points the GNOME desktop background at the image through `gsettings`.
+#[derive(Debug)]
+pub struct GnomeSetter {
+    /// Extra environment variables to pass on to gsettings.
+    passthrough: Vec<String>,
+}
+
+impl GnomeSetter {
+    pub fn new(passthrough: Vec<String>) -> Self {
+        GnomeSetter {
+            passthrough: passthrough,
+        }
+    }
+
+    /// Whether this GNOME version has a separate background for the dark style.
+    fn has_dark_uri(&self) -> bool {
+        sanitized_command("gsettings", &self.passthrough)
+            .arg("list-keys")
+            .arg(GNOME_BACKGROUND)
+            .output()
+            .map(|o| String::from_utf8_lossy(&o.stdout).lines().any(|k| k == "picture-uri-dark"))
+            .unwrap_or(false)
+    }
+}
+
+impl WallpaperSetter for GnomeSetter {
+    /// Sets `picture-uri`, and `picture-uri-dark` when it exists. Returns `SetterCommandFailed`
+    /// when gsettings fails, and the I/O error when it cannot be run at all.
+    fn set(&self, path: &Path) -> Result<(), Box<Error>> {
+        let uri = file_uri(path)?;
+
+        let mut keys = vec!["picture-uri"];
+        if self.has_dark_uri() {
+            keys.push("picture-uri-dark");
+        }
+        for key in keys {
+            run_setter(
+                "gsettings",
+                sanitized_command("gsettings", &self.passthrough)
+                    .arg("set")
+                    .arg(GNOME_BACKGROUND)
+                    .arg(key)
+                    .arg(&uri),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn current(&self) -> Option<PathBuf> {
+        let output = sanitized_command("gsettings", &self.passthrough)
+            .arg("get")
+            .arg(GNOME_BACKGROUND)
+            .arg("picture-uri")
+            .output()
+            .ok()?;
+        let value = String::from_utf8_lossy(&output.stdout);
+        Url::parse(value.trim().trim_matches('\''))
+            .ok()?
+            .to_file_path()
+            .ok()
+    }
+}
+
+/// Setter that points the macOS desktop picture at the image through Finder with `osascript`.
+/// Finder does not pick up a new image under a path it has shown before, so every image is shown
+/// from a fresh copy in the temporary directory.
+#[cfg(target_os = "macos")]
+#[derive(Debug)]
+pub struct MacosSetter {
+    /// Extra environment variables to pass on to osascript.
+    passthrough: Vec<String>,
+    /// Copy of the image shown currently, removed once the next one is shown.
+    copy: RefCell<Option<PathBuf>>,
+}
+
+#[cfg(target_os = "macos")]
+impl MacosSetter {
+    pub fn new(passthrough: Vec<String>) -> Self {
+        MacosSetter {
+            passthrough: passthrough,
+            copy: RefCell::new(None),
+        }
+    }
+
+    /// Copy the image to a path in the temporary directory that has not been used before.
+    fn fresh_copy(&self, path: &Path) -> io::Result<PathBuf> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+            .unwrap_or(0);
+        let ext = path.extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("jpg");
+        let copy = env::temp_dir().join(format!(
+            "wallsplash-{}-{}.{}",
+            process::id(),
+            nanos,
+            ext
+        ));
+        fs::copy(path, &copy)?;
+        Ok(copy)
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl WallpaperSetter for MacosSetter {
+    /// Returns `SetterCommandFailed` when osascript fails, and the I/O error when the image cannot
+    /// be copied or osascript cannot be run at all.
+    fn set(&self, path: &Path) -> Result<(), Box<Error>> {
+        let copy = self.fresh_copy(&absolute(path)?)?;
+        let quoted = copy.to_string_lossy().replace('\\', "\\\\").replace('"', "\\\"");
+        let script = format!(
+            "tell application \"Finder\" to set desktop picture to POSIX file \"{}\"",
+            quoted
+        );
+
+        let result = run_setter(
+            "osascript",
+            sanitized_command("osascript", &self.passthrough)
+                .arg("-e")
+                .arg(script),
+        );
+        let stale = match result {
+            Ok(_) => self.copy.replace(Some(copy)),
+            Err(_) => Some(copy),
+        };
+        if let Some(stale) = stale {
+            if let Err(e) = fs::remove_file(&stale) {
+                debug!("failed to remove wallpaper copy {}: {}", stale.display(), e);
+            }
+        }
+        result
+    }
+}
+
+/// Setter that runs a command given as a template, such as `wbg {path}`. The template is split
+/// into words at whitespace without involving a shell, though single and double quotes and
+/// backslashes group words the way a shell would. `{path}` is replaced by the image path within
+/// its word, so a path with spaces stays a single argument.
+#[derive(Debug)]
+pub struct CommandSetter {
+    /// Program to run.
+    program: String,
+    /// Arguments to the program, possibly containing `{path}`.
+    args: Vec<String>,
+    /// Extra environment variables to pass on to the program.
+    passthrough: Vec<String>,
+}
+
+impl CommandSetter {
+    /// Parse the command template. Fails when it is empty, has an unterminated quote, or lacks
+    /// the `{path}` placeholder.
+    pub fn new(template: &str, passthrough: Vec<String>) -> Result<Self, String> {
+        let mut words = split_words(template)?.into_iter();
+        let program = match words.next() {
+            Some(p) => p,
+            None => return Err("setter command is empty".to_owned()),
+        };
+        let args: Vec<String> = words.collect();
+        if !args.iter().any(|a| a.contains(PATH_PLACEHOLDER)) {
+            return Err(format!(
+                "setter command '{}' does not contain {}",
+                template, PATH_PLACEHOLDER
+            ));
+        }
+
+        Ok(CommandSetter {
+            program: program,
+            args: args,
+            passthrough: passthrough,
+        })
+    }
+
+    /// Arguments for showing the image, with the placeholder replaced by the path as it is,
+    /// even when it is not valid UTF-8.
+    fn args_for(&self, path: &Path) -> Vec<OsString> {
+        self.args
+            .iter()
+            .map(|a| {
+                let mut arg = OsString::new();
+                for (i, part) in a.split(PATH_PLACEHOLDER).enumerate() {
+                    if i > 0 {
+                        arg.push(path);
+                    }
+                    arg.push(part);
+                }
+                arg
+            })
+            .collect()
+    }
+}
+
+impl WallpaperSetter for CommandSetter {
+    /// Returns `SetterCommandFailed` when the command fails, and the I/O error when it cannot be
+    /// run at all.
+    fn set(&self, path: &Path) -> Result<(), Box<Error>> {
+        let args = self.args_for(&absolute(path)?);
+        run_setter(
+            &self.program,
+            sanitized_command(&self.program, &self.passthrough).args(&args),
+        )
+    }
+}
+
+/// Setter that runs `nitrogen`, which also saves the choice for restoring after a restart.
+#[derive(Debug)]
+pub struct NitrogenSetter {
+    /// Extra environment variables to pass on to nitrogen.
+    passthrough: Vec<String>,
+    /// How to fit the image onto the screen.
+    scaling: Scaling,
+}
+
+impl NitrogenSetter {
+    pub fn new(passthrough: Vec<String>, scaling: Scaling) -> Self {
+        NitrogenSetter {
+            passthrough: passthrough,
+            scaling: scaling,
+        }
+    }
+}
+
+impl WallpaperSetter for NitrogenSetter {
+    /// Returns `SetterCommandFailed` when nitrogen fails, and the I/O error when it cannot be run
+    /// at all.
+    fn set(&self, path: &Path) -> Result<(), Box<Error>> {
+        let mode = match self.scaling {
+            Scaling::Fill => "--set-zoom-fill",
+            Scaling::Max => "--set-zoom",
+            Scaling::Scale => "--set-scaled",
+            Scaling::Center => "--set-centered",
+            Scaling::Tile => "--set-tiled",
+        };
+        run_setter(
+            "nitrogen",
+            sanitized_command("nitrogen", &self.passthrough)
+                .arg(mode)
+                .arg("--save")
+                .arg(absolute(path)?),
+        )
+    }
+}
+
+/// Setter that starts `swaybg` for Wayland compositors like Sway. Since swaybg keeps running to
+/// draw the wallpaper, the one started for the previous image is stopped first.
+#[derive(Debug)]
+pub struct SwaybgSetter {
+    /// Extra environment variables to pass on to swaybg.
+    passthrough: Vec<String>,
+    /// How to fit the image onto the screen.
+    scaling: Scaling,
+    /// The swaybg drawing the current wallpaper, if any.
+    child: RefCell<Option<Child>>,
+}
+
+impl SwaybgSetter {
+    pub fn new(passthrough: Vec<String>, scaling: Scaling) -> Self {
+        SwaybgSetter {
+            passthrough: passthrough,
+            scaling: scaling,
+            child: RefCell::new(None),
+        }
+    }
+}
+
+impl WallpaperSetter for SwaybgSetter {
+    /// Returns the I/O error when swaybg cannot be started. Failures after it has started only
+    /// show up in its own output.
+    fn set(&self, path: &Path) -> Result<(), Box<Error>> {
+        let mut child = self.child.borrow_mut();
+        if let Some(mut previous) = child.take() {
+            // It may have exited on its own already, so only reaping it matters.
+            let _ = previous.kill();
+            previous.wait()?;
+        }
+
+        let spawned = sanitized_command("swaybg", &self.passthrough)
+            .arg("-i")
+            .arg(absolute(path)?)
+            .arg("-m")
+            .arg(swaybg_mode(self.scaling))
+            .spawn();
+        match spawned {
+            Ok(c) => *child = Some(c),
+            Err(e) => return Err(Box::new(not_found("swaybg", e))),
+        }
+        Ok(())
+    }
+}
+
+/// Setter that asks Sway through `swaymsg` to draw the image on every output. Sway replaces the
+/// swaybg it started for the previous image by itself.
+#[derive(Debug)]
+pub struct SwaySetter {
+    /// Extra environment variables to pass on to swaymsg, including Sway's socket.
+    passthrough: Vec<String>,
+    /// How to fit the image onto the screen.
+    scaling: Scaling,
+}
+
+impl SwaySetter {
+    pub fn new(mut passthrough: Vec<String>, scaling: Scaling) -> Self {
+        passthrough.extend(SWAY_ENV.iter().map(|name| name.to_string()));
+        SwaySetter {
+            passthrough: passthrough,
+            scaling: scaling,
+        }
+    }
+}
+
+impl WallpaperSetter for SwaySetter {
+    /// Returns `SetterCommandFailed` when Sway cannot be reached or rejects the command, and the
+    /// I/O error when swaymsg cannot be run at all.
+    fn set(&self, path: &Path) -> Result<(), Box<Error>> {
+        let path = absolute(path)?;
+        let path = path.to_string_lossy().replace('\\', "\\\\").replace('"', "\\\"");
+        let command = format!("output * bg \"{}\" {}", path, swaybg_mode(self.scaling));
+        run_setter(
+            "swaymsg",
+            sanitized_command("swaymsg", &self.passthrough).arg(command),
+        )
+    }
+}
+
+/// Run a setter program, classifying its failure from the error output.
+fn run_setter(program: &str, cmd: &mut Command) -> Result<(), Box<Error>> {
+    let output = match cmd.output() {
+        Ok(o) => o,
+        Err(e) => return Err(Box::new(not_found(program, e))),
+    };
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    debug!("{} exited with {}: {}", program, output.status, stderr.trim());
+    if is_no_display(&stderr) {
+        Err(Box::new(WallsplashError::NoDisplay))
+    } else {
+        Err(Box::new(WallsplashError::SetterCommandFailed))
+    }
+}
+
+/// Split a command line into words like a shell would, minus any expansions. Single quotes keep
+/// everything up to the next single quote, double quotes allow backslash escapes of `"` and `\`,
+/// and a backslash elsewhere escapes the next character.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(format!("unterminated ' in '{}'", line)),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if c == '"' || c == '\\' => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(format!("unterminated \" in '{}'", line)),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(format!("unterminated \" in '{}'", line)),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                match chars.next() {
+                    Some(c) => word.push(c),
+                    None => return Err(format!("trailing \\ in '{}'", line)),
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(word.clone());
+                    word.clear();
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Name of the scaling mode in swaybg and Sway.
+fn swaybg_mode(scaling: Scaling) -> &'static str {
+    match scaling {
+        Scaling::Fill => "fill",
+        Scaling::Max => "fit",
+        Scaling::Scale => "stretch",
+        Scaling::Center => "center",
+        Scaling::Tile => "tile",
+    }
+}
+
+/// Explain a failure to run a setter program that is not installed, keeping other errors as is.
+fn not_found(program: &str, err: io::Error) -> io::Error {
+    if err.kind() == io::ErrorKind::NotFound {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("setter program {} not found, is it installed?", program),
+        )
+    } else {
+        err
+    }
+}
+
+/// Get the `file://` URI for the image, percent-encoding spaces and non-ASCII characters.
+fn file_uri(path: &Path) -> Result<String, Box<Error>> {
+    let path = absolute(path)?;
+    match Url::from_file_path(&path) {
+        Ok(url) => Ok(url.into_string()),
+        Err(_) => Err(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot make a file URI for {}", path.display()),
+        ))),
+    }
+}
+
+/// Whether a setter's error output says there is no display to set the wallpaper on.
+fn is_no_display(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    NO_DISPLAY_MESSAGES.iter().any(|m| stderr.contains(m))
+}
+
+/// Resolve a relative path against the current directory, since setters run from the state
+/// directory instead.
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(env::current_dir()?.join(path))
+    }
+}
+
+/// Build a command for running the wallpaper setter or another helper program. It only sees the
+/// allowed environment variables, which keeps secrets like the Unsplash token away from it, runs
+/// from the state directory, and has no stdin.
+pub fn sanitized_command(program: &str, passthrough: &[String]) -> Command {
+    let mut cmd = Command::new(program);
+    cmd.env_clear().stdin(Stdio::null());
+
+    let names = SETTER_ENV.iter().copied().chain(passthrough.iter().map(|s| s.as_str()));
+    for name in names {
+        if let Some(value) = env::var_os(name) {
+            cmd.env(name, value);
+        }
+    }
+
+    if let Some(home) = env::home_dir() {
+        if let Some(state) = cache::cache_path(&home).parent() {
+            // Running from a directory that does not exist would fail as if the program did not.
+            if state.is_dir() {
+                cmd.current_dir(state);
+            }
+        }
+    }
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::process;
+
+    use super::*;
+
+    /// Run `env` the way a setter is run and collect the environment it saw.
+    fn setter_env(passthrough: &[String]) -> BTreeMap<String, String> {
+        let output = sanitized_command("env", passthrough).output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) => Some((name.to_owned(), value.to_owned())),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn setter_only_sees_allowed_environment() {
+        env::set_var("XAUTHORITY", "/tmp/wallsplash-test-xauth");
+        env::set_var("WALLSPLASH_TEST_TOKEN", "secret");
+        env::set_var("WALLSPLASH_TEST_EXTRA", "extra");
+
+        let seen = setter_env(&[]);
+        assert_eq!(
+            seen.get("XAUTHORITY").map(|s| s.as_str()),
+            Some("/tmp/wallsplash-test-xauth")
+        );
+        assert!(!seen.contains_key("WALLSPLASH_TEST_TOKEN"));
+        assert!(!seen.contains_key("WALLSPLASH_TEST_EXTRA"));
+        for name in seen.keys() {
+            assert!(SETTER_ENV.contains(&name.as_str()), "{} leaked to the setter", name);
+        }
+
+        let seen = setter_env(&["WALLSPLASH_TEST_EXTRA".to_owned()]);
+        assert_eq!(seen.get("WALLSPLASH_TEST_EXTRA").map(|s| s.as_str()), Some("extra"));
+        assert!(!seen.contains_key("WALLSPLASH_TEST_TOKEN"));
+    }
+
+    fn words(line: &str) -> Vec<String> {
+        split_words(line).unwrap()
+    }
+
+    #[test]
+    fn split_plain_words() {
+        assert_eq!(words("wbg {path}"), vec!["wbg", "{path}"]);
+        assert_eq!(words("  feh\t--bg-fill   {path} "), vec!["feh", "--bg-fill", "{path}"]);
+        assert!(words("").is_empty());
+        assert!(words("   ").is_empty());
+    }
+
+    #[test]
+    fn split_quoted_words() {
+        assert_eq!(
+            words("'/opt/My Tools/setbg' --title 'two words' {path}"),
+            vec!["/opt/My Tools/setbg", "--title", "two words", "{path}"]
+        );
+        assert_eq!(
+            words(r#"setbg "--file={path}" "say \"hi\" \\ \n""#),
+            vec!["setbg", "--file={path}", r#"say "hi" \ \n"#]
+        );
+        assert_eq!(words(r"setbg a\ b \'c"), vec!["setbg", "a b", "'c"]);
+        assert_eq!(words("setbg '' \"\" x''y"), vec!["setbg", "", "", "xy"]);
+        assert_eq!(words("setbg 'it'\"'\"'s'"), vec!["setbg", "it's"]);
+    }
+
+    #[test]
+    fn split_rejects_unterminated_quotes() {
+        assert!(split_words("setbg 'oops {path}").is_err());
+        assert!(split_words("setbg \"oops {path}").is_err());
+        assert!(split_words("setbg {path} \\").is_err());
+    }
+
+    #[test]
+    fn command_setter_substitutes_the_path() {
+        let setter = CommandSetter::new("'/opt/My Tools/setbg' -i {path}", vec![]).unwrap();
+        assert_eq!(setter.program, "/opt/My Tools/setbg");
+        assert_eq!(
+            setter.args_for(Path::new("/home/me/My Pictures/a b.jpg")),
+            vec!["-i", "/home/me/My Pictures/a b.jpg"]
+        );
+
+        let setter = CommandSetter::new("setbg --file={path} --also \"{path}\"", vec![]).unwrap();
+        assert_eq!(
+            setter.args_for(Path::new("/tmp/x.png")),
+            vec!["--file=/tmp/x.png", "--also", "/tmp/x.png"]
+        );
+    }
+
+    #[test]
+    fn command_setter_needs_a_path_placeholder() {
+        assert!(CommandSetter::new("", vec![]).is_err());
+        assert!(CommandSetter::new("setbg --random", vec![]).is_err());
+        assert!(CommandSetter::new("{path}", vec![]).is_err());
+        assert!(CommandSetter::new("'setbg {path}'", vec![]).is_err());
+    }
+
+    #[test]
+    fn command_setter_runs_with_the_path() {
+        let name = format!("wallsplash-command-setter-{}.txt", process::id());
+        let out = env::temp_dir().join(name);
+        let template = format!("sh -c 'printf %s \"$1\" > {}' sh {{path}}", out.display());
+        let setter = CommandSetter::new(&template, vec![]).unwrap();
+        setter.set(Path::new("/tmp/with space.jpg")).unwrap();
+
+        let mut written = String::new();
+        fs::File::open(&out).unwrap().read_to_string(&mut written).unwrap();
+        assert_eq!(written, "/tmp/with space.jpg");
+        fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn command_setter_passes_latin1_paths_unchanged() {
+        let name = format!("wallsplash-command-setter-latin1-{}.txt", process::id());
+        let out = env::temp_dir().join(name);
+        let template = format!("sh -c 'printf %s \"$1\" > {}' sh {{path}}", out.display());
+        let setter = CommandSetter::new(&template, vec![]).unwrap();
+        let path = Path::new(OsStr::from_bytes(b"/tmp/caf\xe9 cr\xe8me.jpg"));
+        assert_eq!(setter.args_for(path)[3], path.as_os_str());
+        setter.set(path).unwrap();
+
+        let mut written = Vec::new();
+        fs::File::open(&out).unwrap().read_to_end(&mut written).unwrap();
+        assert_eq!(written, b"/tmp/caf\xe9 cr\xe8me.jpg");
+        fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn no_display_from_canned_stderr() {
+        let missing = [
+            "feh ERROR: Can't open X display. It *is* running, yeah?\n",
+            "nitrogen: cannot open display\n",
+            "(gsettings:1234): dconf-WARNING **: Unable to open display\n",
+            "swaybg: error: No display\n",
+            "Error: Can't open X display \":0\"\n",
+        ];
+        for stderr in &missing {
+            assert!(is_no_display(stderr), "{:?}", stderr);
+        }
+
+        let failed = [
+            "",
+            "feh: No loadable images specified.\n",
+            "swaymsg: Error: Unknown/invalid command 'output'\n",
+            "Unable to connect to /run/user/1000/sway-ipc.sock\n",
+            "display.jpg: permission denied\n",
+        ];
+        for stderr in &failed {
+            assert!(!is_no_display(stderr), "{:?}", stderr);
+        }
+    }
+
+    #[test]
+    fn missing_sway_socket_is_not_a_missing_display() {
+        let stderr = "00:00:00.000 [swaymsg/main.c:402] Unable to retrieve socket path\n";
+        assert!(!is_no_display(stderr));
+    }
+
+    #[test]
+    fn sway_setter_passes_its_socket() {
+        let setter = SwaySetter::new(vec!["LANG".to_owned()], Scaling::Fill);
+        assert_eq!(setter.passthrough, vec!["LANG", "SWAYSOCK", "I3SOCK"]);
+
+        env::set_var("SWAYSOCK", "/run/user/1000/sway-ipc.sock");
+        let seen = setter_env(&setter.passthrough);
+        assert_eq!(
+            seen.get("SWAYSOCK").map(|s| s.as_str()),
+            Some("/run/user/1000/sway-ipc.sock")
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Module for one-off slideshows over a single directory, outside the normal rotation.
+
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use engine::{Engine, Order};
+use fetchers::{Fetch, LocalFetcher};
+use rotation::ShuffleMode;
+use setters::WallpaperSetter;
+
+/// Milliseconds between checks for an interrupt while waiting for the next image.
+const INTERRUPT_POLL_MILLIS: u64 = 100;
+
+/// Set once the slideshow has been asked to stop.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Show the images of `dir` one after another, or shuffled, until interrupted with Ctrl-C or
+/// `SIGTERM`. Nothing is read from or written to the Unsplash cache. The wallpaper shown before
+/// is put back afterwards when the setter can tell what it was.
+pub fn slideshow(
+    dir: &Path,
+    interval: Duration,
+    shuffle: bool,
+    setter: &WallpaperSetter,
+) -> Result<(), Box<Error>> {
+    let previous = setter.current();
+    if previous.is_none() {
+        info!("cannot tell the current wallpaper, it will not be restored afterwards");
+    }
+
+    let order = if shuffle {
+        ShuffleMode::ShuffledCycle
+    } else {
+        ShuffleMode::Sequential
+    };
+    let dirs = [dir.to_path_buf()];
+    let local = LocalFetcher::new(&dirs, usize::MAX, &[], false, false, interval, order);
+    let sources: Vec<Box<Fetch>> = vec![Box::new(local)];
+    let mut engine = Engine::with_sources(sources, &Order::Alternate)?;
+
+    let handler = interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+
+    let result = show_until_interrupted(&mut engine, interval, setter);
+
+    if let Some(path) = previous {
+        debug!("restoring wallpaper {}", path.display());
+        if let Err(e) = setter.set(&path) {
+            warn!("failed to restore wallpaper {}: {}", path.display(), e);
+        }
+    }
+    result
+}
+
+fn show_until_interrupted(
+    engine: &mut Engine,
+    interval: Duration,
+    setter: &WallpaperSetter,
+) -> Result<(), Box<Error>> {
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        let info = engine.next_image()?;
+        debug!("slideshow: {}", info.path.display());
+        setter.set(&info.path)?;
+
+        let shown = Instant::now();
+        while shown.elapsed() < interval && !INTERRUPTED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(INTERRUPT_POLL_MILLIS));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+//! Module for reporting what the daemon has been up to, for the `status` command.
+
+use std::env;
+use std::time::Duration;
+
+use cache;
+use usage::{self, Usage};
+
+/// Snapshot of this month's downloads and the Unsplash cache.
+#[derive(Debug)]
+pub struct Status {
+    /// Month the download totals belong to, as `YYYY-MM` in local time.
+    pub month: String,
+    /// Bytes downloaded this month by each source.
+    pub downloaded: Vec<(String, u64)>,
+    /// Monthly download budget in bytes, if limited.
+    pub budget: Option<u64>,
+    /// Number of images in the Unsplash cache.
+    pub cached: usize,
+    /// Time since the Unsplash cache was last refreshed, if ever.
+    pub refreshed: Option<Duration>,
+}
+
+impl Status {
+    /// Total bytes downloaded by all sources this month.
+    pub fn total(&self) -> u64 {
+        self.downloaded.iter().map(|&(_, n)| n).sum()
+    }
+
+    /// Whether downloads are suspended until next month because the budget is used up.
+    pub fn suspended(&self) -> bool {
+        self.budget.is_some_and(|b| self.total() >= b)
+    }
+}
+
+/// Gather the status of the current user's daemon, checking downloads against the given budget.
+pub fn status(budget: Option<u64>) -> Status {
+    let home = env::home_dir().unwrap();
+    let mut usage = Usage::load(&usage::state_path(&home));
+    let dir = cache::cache_path(&home);
+
+    Status {
+        month: usage.month().to_owned(),
+        downloaded: usage.by_source(),
+        budget: budget,
+        cached: cache::count_images(&dir),
+        refreshed: cache::read_marker(&dir).map(|(age, _)| age),
+    }
+}
@@ -0,0 +1,105 @@
+//! Module of stand-in sources and setters for exercising the engine without a network or a
+//! display. Only built with the `testing` feature.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use errors::WallsplashError;
+use fetchers::Fetch;
+use setters::WallpaperSetter;
+
+/// Source that offers a fixed list of images in order, starting over at the end.
+#[derive(Debug)]
+pub struct MockFetcher {
+    /// Name of this source.
+    name: String,
+    /// Images to offer.
+    paths: Vec<PathBuf>,
+    /// Index of the next image to offer.
+    next: usize,
+}
+
+impl MockFetcher {
+    pub fn new(name: &str, paths: Vec<PathBuf>) -> Self {
+        MockFetcher {
+            name: name.to_owned(),
+            paths: paths,
+            next: 0,
+        }
+    }
+}
+
+impl Fetch for MockFetcher {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn next_image_path(&mut self) -> Result<PathBuf, Box<Error>> {
+        if self.paths.is_empty() {
+            return Err(Box::new(WallsplashError::LocalNoImage));
+        }
+        let path = self.paths[self.next % self.paths.len()].clone();
+        self.next += 1;
+        Ok(path)
+    }
+}
+
+/// Write the smallest PNG whose header describes an image of the given size. There is no image
+/// data, but it is enough to pass the image checks.
+pub fn write_png(path: &Path, width: u32, height: u32) -> io::Result<()> {
+    let mut data = b"\x89PNG\r\n\x1A\n\x00\x00\x00\x0DIHDR".to_vec();
+    data.extend_from_slice(&be32(width));
+    data.extend_from_slice(&be32(height));
+    data.extend_from_slice(b"\x08\x02\x00\x00\x00\x00\x00\x00\x00");
+    fs::write(path, data)
+}
+
+/// Write the smallest JPEG whose header describes an image of the given size. There is no image
+/// data, but it is enough to pass the image checks.
+pub fn write_jpeg(path: &Path, width: u16, height: u16) -> io::Result<()> {
+    let mut data = b"\xFF\xD8\xFF\xE0\x00\x10JFIF\x00".to_vec();
+    data.extend_from_slice(b"\x01\x01\x00\x00\x01\x00\x01\x00\x00");
+    data.extend_from_slice(b"\xFF\xC0\x00\x11\x08");
+    data.extend_from_slice(&be32(u32::from(height))[2..]);
+    data.extend_from_slice(&be32(u32::from(width))[2..]);
+    data.extend_from_slice(b"\x03\x01\x22\x00\x02\x11\x01\x03\x11\x01\xFF\xD9");
+    fs::write(path, data)
+}
+
+fn be32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+/// Setter that only records the images it was asked to set. Clones share the record, so one can
+/// be kept to look at after handing another to a context.
+#[derive(Debug, Clone, Default)]
+pub struct MockSetter {
+    /// Images set so far, oldest first.
+    set: Rc<RefCell<Vec<PathBuf>>>,
+}
+
+impl MockSetter {
+    pub fn new() -> Self {
+        MockSetter::default()
+    }
+
+    /// Images set so far, oldest first.
+    pub fn history(&self) -> Vec<PathBuf> {
+        self.set.borrow().clone()
+    }
+}
+
+impl WallpaperSetter for MockSetter {
+    fn set(&self, path: &Path) -> Result<(), Box<Error>> {
+        self.set.borrow_mut().push(path.to_path_buf());
+        Ok(())
+    }
+
+    fn current(&self) -> Option<PathBuf> {
+        self.set.borrow().last().cloned()
+    }
+}
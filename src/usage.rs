@@ -0,0 +1,275 @@
+//! Module for tracking how many bytes each source downloads per calendar month. The running
+//! totals are kept in a state directory and named after the machine, rather than in a cache that
+//! may be shared with other machines, so they survive restarts and only count local downloads.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+
+use libc;
+
+/// Prefix of the name of the file recording the bytes downloaded this month.
+const USAGE_FILE: &'static str = "usage";
+
+/// Get the directory for state that belongs to this machine, for the given home directory.
+/// Follows `XDG_STATE_HOME` when it is set.
+pub fn state_path(home: &Path) -> PathBuf {
+    match env::var_os("XDG_STATE_HOME") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir).join("wallsplash"),
+        _ => home_state_path(home),
+    }
+}
+
+/// Get the default directory for state under the given home directory, whatever
+/// `XDG_STATE_HOME` says.
+pub fn home_state_path(home: &Path) -> PathBuf {
+    home.join(".local").join("state").join("wallsplash")
+}
+
+/// Source of the current calendar month, so that tests can control it. Clocks are `Send` so
+/// that a fetcher keeping count can start up on another thread.
+pub trait Clock: fmt::Debug + Send {
+    /// The current calendar month in local time, as `YYYY-MM`.
+    fn month(&self) -> String;
+}
+
+/// Clock reading the system time in the local time zone.
+#[derive(Debug)]
+pub struct LocalClock;
+
+impl Clock for LocalClock {
+    fn month(&self) -> String {
+        unsafe {
+            let now = libc::time(ptr::null_mut());
+            let mut tm: libc::tm = mem::zeroed();
+            libc::localtime_r(&now, &mut tm);
+            format!("{:04}-{:02}", tm.tm_year + 1900, tm.tm_mon + 1)
+        }
+    }
+}
+
+/// Bytes downloaded per source during one calendar month.
+#[derive(Debug)]
+pub struct Usage {
+    /// File the totals are persisted to.
+    path: PathBuf,
+    /// Clock telling which month it is.
+    clock: Box<Clock>,
+    /// Month the totals belong to, as `YYYY-MM` in local time.
+    month: String,
+    /// Bytes downloaded by each source.
+    bytes: BTreeMap<String, u64>,
+}
+
+impl Usage {
+    /// Load this month's totals from the given state directory. Totals recorded for an earlier
+    /// month, or that cannot be read, start over from zero.
+    pub fn load(dir: &Path) -> Usage {
+        Usage::with_clock(dir, Box::new(LocalClock))
+    }
+
+    /// Load this month's totals, going by the given clock.
+    pub fn with_clock(dir: &Path, clock: Box<Clock>) -> Usage {
+        let path = dir.join(state_file(USAGE_FILE));
+        let month = clock.month();
+        let mut bytes = BTreeMap::new();
+
+        let mut content = String::new();
+        let read = fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut content));
+        if read.is_ok() {
+            let mut lines = content.lines();
+            if lines.next() == Some(month.as_str()) {
+                for line in lines {
+                    let mut parts = line.split_whitespace();
+                    let source = parts.next();
+                    let count = parts.next().and_then(|n| n.parse::<u64>().ok());
+                    if let (Some(source), Some(count)) = (source, count) {
+                        bytes.insert(source.to_owned(), count);
+                    }
+                }
+            }
+        }
+
+        Usage {
+            path: path,
+            clock: clock,
+            month: month,
+            bytes: bytes,
+        }
+    }
+
+    /// Month the totals belong to, as `YYYY-MM` in local time.
+    pub fn month(&mut self) -> &str {
+        self.roll_over();
+        &self.month
+    }
+
+    /// Total bytes downloaded by all sources this month.
+    pub fn total(&mut self) -> u64 {
+        self.roll_over();
+        self.bytes.values().sum()
+    }
+
+    /// Bytes downloaded by each source this month, by source name.
+    pub fn by_source(&mut self) -> Vec<(String, u64)> {
+        self.roll_over();
+        self.bytes.iter().map(|(s, n)| (s.clone(), *n)).collect()
+    }
+
+    /// Add downloaded bytes to a source's total and persist the new totals.
+    pub fn add(&mut self, source: &str, count: u64) -> io::Result<()> {
+        self.roll_over();
+        *self.bytes.entry(source.to_owned()).or_insert(0) += count;
+        self.save()
+    }
+
+    /// Start over from zero once the calendar month has changed.
+    fn roll_over(&mut self) {
+        let month = self.clock.month();
+        if month != self.month {
+            let old = mem::replace(&mut self.month, month);
+            info!("new month, download totals for {} reset", old);
+            self.bytes.clear();
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut content = format!("{}\n", self.month);
+        for (source, count) in &self.bytes {
+            content.push_str(&format!("{} {}\n", source, count));
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension(format!("{}", process::id()));
+        fs::File::create(&tmp)?.write_all(content.as_bytes())?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Name of the state file with the given prefix, which includes the host name in case the state
+/// directory is on a home directory shared between machines.
+pub fn state_file(prefix: &str) -> String {
+    let mut buf = [0 as libc::c_char; 256];
+    let named = unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len() - 1) } == 0;
+    let host = if named {
+        unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned()
+    } else {
+        String::new()
+    };
+
+    let host: String = host.chars().filter(|c| c.is_alphanumeric() || *c == '-').collect();
+    if host.is_empty() {
+        prefix.to_owned()
+    } else {
+        format!("{}-{}", prefix, host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Clock showing whichever month the test sets.
+    #[derive(Debug, Clone)]
+    struct FakeClock(Arc<Mutex<String>>);
+
+    impl FakeClock {
+        fn new(month: &str) -> FakeClock {
+            FakeClock(Arc::new(Mutex::new(month.to_owned())))
+        }
+
+        fn set(&self, month: &str) {
+            *self.0.lock().unwrap() = month.to_owned();
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn month(&self) -> String {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("wallsplash-usage-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn restart_mid_month_restores_the_total() {
+        let dir = state_dir("restart");
+        let clock = FakeClock::new("2017-06");
+
+        let mut usage = Usage::with_clock(&dir, Box::new(clock.clone()));
+        usage.add("unsplash", 1000).unwrap();
+        usage.add("unsplash", 500).unwrap();
+        usage.add("other", 20).unwrap();
+        assert_eq!(usage.total(), 1520);
+        drop(usage);
+
+        let mut usage = Usage::with_clock(&dir, Box::new(clock.clone()));
+        assert_eq!(usage.total(), 1520);
+        assert_eq!(
+            usage.by_source(),
+            vec![("other".to_owned(), 20), ("unsplash".to_owned(), 1500)]
+        );
+    }
+
+    #[test]
+    fn restart_in_a_new_month_starts_over() {
+        let dir = state_dir("new-month");
+        let clock = FakeClock::new("2017-06");
+        Usage::with_clock(&dir, Box::new(clock.clone()))
+            .add("unsplash", 1000)
+            .unwrap();
+
+        clock.set("2017-07");
+        let mut usage = Usage::with_clock(&dir, Box::new(clock.clone()));
+        assert_eq!(usage.month(), "2017-07");
+        assert_eq!(usage.total(), 0);
+    }
+
+    #[test]
+    fn rolls_over_while_running() {
+        let dir = state_dir("running");
+        let clock = FakeClock::new("2017-12");
+        let mut usage = Usage::with_clock(&dir, Box::new(clock.clone()));
+        usage.add("unsplash", 1000).unwrap();
+
+        clock.set("2018-01");
+        assert_eq!(usage.total(), 0);
+        usage.add("unsplash", 7).unwrap();
+        assert_eq!(usage.total(), 7);
+
+        // The saved totals belong to the new month only.
+        let mut usage = Usage::with_clock(&dir, Box::new(clock.clone()));
+        assert_eq!(usage.total(), 7);
+        clock.set("2017-12");
+        assert_eq!(Usage::with_clock(&dir, Box::new(clock.clone())).total(), 0);
+    }
+
+    #[test]
+    fn unreadable_totals_start_over() {
+        let dir = state_dir("corrupt");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(state_file(USAGE_FILE)), "2017-06\nunsplash lots\nother 5\n").unwrap();
+
+        let mut usage = Usage::with_clock(&dir, Box::new(FakeClock::new("2017-06")));
+        assert_eq!(usage.total(), 5);
+    }
+}
@@ -0,0 +1,422 @@
+//! Module for verifying that local wallpapers are usable images.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use fetchers;
+
+/// Number of threads used to check images.
+const WORKERS: usize = 4;
+
+/// Name of the subdirectory that failed images are moved into when fixing.
+const QUARANTINE_DIR: &'static str = ".quarantine";
+
+/// Reason an image failed verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Problem {
+    /// The file is empty.
+    Empty,
+    /// The file could not be read.
+    Unreadable,
+    /// The file does not start with the header of a supported image format.
+    UnknownFormat,
+    /// The header is cut short or does not describe an image.
+    BadHeader,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Problem::Empty => "empty file",
+            Problem::Unreadable => "unreadable file",
+            Problem::UnknownFormat => "not a supported image",
+            Problem::BadHeader => "truncated or corrupt image header",
+        })
+    }
+}
+
+/// Outcome of verifying a directory of images.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of files checked.
+    pub checked: usize,
+    /// Files that failed, sorted by problem and then path.
+    pub failures: Vec<(Problem, PathBuf)>,
+    /// Directory failures were moved into, if fixing.
+    pub quarantine: Option<PathBuf>,
+}
+
+/// Check every image in the local directory, in parallel, finding them the same way rotation
+/// does: by extension, skipping hidden files unless `hidden` is set, and searching the
+/// subdirectories with `recursive`. When `fix` is set, failures are moved into a quarantine
+/// subdirectory, under the same relative path, so they drop out of the rotation.
+///
+/// # Errors
+///
+/// Returns an error when the directory cannot be listed or a failure cannot be moved.
+pub fn verify_local(
+    dir: &Path,
+    extensions: &[String],
+    hidden: bool,
+    recursive: bool,
+    fix: bool,
+) -> Result<Report, Box<Error>> {
+    let quarantine = dir.join(QUARANTINE_DIR);
+    let files: Vec<PathBuf> = fetchers::list_images(dir, extensions, hidden, recursive)?
+        .into_iter()
+        .filter(|p| !p.starts_with(&quarantine))
+        .collect();
+    let chunk = files.len().div_ceil(WORKERS);
+
+    let mut report = Report {
+        checked: files.len(),
+        ..Report::default()
+    };
+
+    if chunk > 0 {
+        let workers: Vec<_> = files
+            .chunks(chunk)
+            .map(|paths| {
+                let paths = paths.to_vec();
+                thread::spawn(move || {
+                    paths
+                        .into_iter()
+                        .filter_map(|p| check_image(&p).err().map(|e| (e, p)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            report
+                .failures
+                .extend(worker.join().expect("verify worker panicked"));
+        }
+        report.failures.sort();
+    }
+
+    if fix && !report.failures.is_empty() {
+        for (_, path) in &report.failures {
+            if let Ok(relative) = path.strip_prefix(dir) {
+                let target = quarantine.join(relative);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                debug!("quarantining {}", path.display());
+                fs::rename(path, target)?;
+            }
+        }
+        report.quarantine = Some(quarantine);
+    }
+
+    Ok(report)
+}
+
+/// Check that the file starts with a complete header of a supported image format.
+pub fn check_image(path: &Path) -> Result<(), Problem> {
+    image_size(path).map(|_| ())
+}
+
+/// Read the width and height of an image from its header, which must be complete. Only the
+/// header is read, so the image data itself may still be damaged.
+pub fn image_size(path: &Path) -> Result<(u32, u32), Problem> {
+    let mut file = fs::File::open(path).map_err(|_| Problem::Unreadable)?;
+    let mut header = [0; 30];
+    let len = read_up_to(&mut file, &mut header).map_err(|_| Problem::Unreadable)?;
+    let header = &header[..len];
+
+    if header.is_empty() {
+        return Err(Problem::Empty);
+    }
+
+    let size = if header.starts_with(b"\xFF\xD8\xFF") {
+        jpeg_size(&mut file)
+    } else if header.starts_with(b"\x89PNG\r\n\x1A\n") {
+        png_size(header)
+    } else if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+        gif_size(header)
+    } else if header.starts_with(b"BM") {
+        bmp_size(header)
+    } else if header.starts_with(b"RIFF") && header.len() >= 12 && &header[8..12] == b"WEBP" {
+        webp_size(header)
+    } else {
+        return Err(Problem::UnknownFormat);
+    };
+
+    match size {
+        Some((width, height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(Problem::BadHeader),
+    }
+}
+
+/// Fill as much of the buffer as the file has, returning how much that was.
+fn read_up_to(file: &mut fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+fn be16(b: &[u8]) -> u32 {
+    (u32::from(b[0]) << 8) | u32::from(b[1])
+}
+
+fn be32(b: &[u8]) -> u32 {
+    (be16(b) << 16) | be16(&b[2..])
+}
+
+fn le16(b: &[u8]) -> u32 {
+    u32::from(b[0]) | (u32::from(b[1]) << 8)
+}
+
+fn le24(b: &[u8]) -> u32 {
+    le16(b) | (u32::from(b[2]) << 16)
+}
+
+fn le32(b: &[u8]) -> u32 {
+    le16(b) | (le16(&b[2..]) << 16)
+}
+
+/// The size from the `IHDR` chunk, which must come first.
+fn png_size(header: &[u8]) -> Option<(u32, u32)> {
+    if header.len() < 24 || be32(&header[8..]) != 13 || &header[12..16] != b"IHDR" {
+        return None;
+    }
+    Some((be32(&header[16..]), be32(&header[20..])))
+}
+
+/// The size from the logical screen descriptor.
+fn gif_size(header: &[u8]) -> Option<(u32, u32)> {
+    if header.len() < 10 {
+        return None;
+    }
+    Some((le16(&header[6..]), le16(&header[8..])))
+}
+
+/// The size from the info header, whose height is negative for top-down bitmaps.
+fn bmp_size(header: &[u8]) -> Option<(u32, u32)> {
+    if header.len() < 26 {
+        return None;
+    }
+    let width = le32(&header[18..]) as i32;
+    let height = le32(&header[22..]) as i32;
+    if width <= 0 || height == 0 {
+        return None;
+    }
+    Some((width as u32, height.checked_abs()? as u32))
+}
+
+/// The size from the first chunk, which differs for lossy, lossless, and extended WebP.
+fn webp_size(header: &[u8]) -> Option<(u32, u32)> {
+    if header.len() < 30 {
+        return None;
+    }
+    match &header[12..16] {
+        b"VP8 " if &header[23..26] == b"\x9D\x01\x2A" => Some((
+            le16(&header[26..]) & 0x3FFF,
+            le16(&header[28..]) & 0x3FFF,
+        )),
+        b"VP8L" if header[20] == 0x2F => {
+            let bits = le32(&header[21..]);
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+        }
+        b"VP8X" => Some((le24(&header[24..]) + 1, le24(&header[27..]) + 1)),
+        _ => None,
+    }
+}
+
+/// The size from the start of frame segment, skipping the segments before it.
+fn jpeg_size(file: &mut fs::File) -> Option<(u32, u32)> {
+    file.seek(SeekFrom::Start(2)).ok()?;
+    let mut marker = [0; 2];
+    loop {
+        read_exact(file, &mut marker[..1])?;
+        if marker[0] != 0xFF {
+            return None;
+        }
+        // Any number of 0xFF bytes may pad the space before a marker.
+        loop {
+            read_exact(file, &mut marker[1..])?;
+            if marker[1] != 0xFF {
+                break;
+            }
+        }
+
+        match marker[1] {
+            // Standalone markers without a length.
+            0x01 | 0xD0..=0xD7 => continue,
+            // End of image or start of scan before any frame.
+            0xD9 | 0xDA => return None,
+            _ => {}
+        }
+
+        let mut len = [0; 2];
+        read_exact(file, &mut len)?;
+        let len = be16(&len);
+        if len < 2 {
+            return None;
+        }
+
+        let sof = match marker[1] {
+            0xC0..=0xCF => marker[1] != 0xC4 && marker[1] != 0xC8 && marker[1] != 0xCC,
+            _ => false,
+        };
+        if sof {
+            let mut frame = [0; 5];
+            read_exact(file, &mut frame)?;
+            return Some((be16(&frame[3..]), be16(&frame[1..])));
+        }
+        file.seek(SeekFrom::Current(i64::from(len) - 2)).ok()?;
+    }
+}
+
+/// Read exactly enough to fill the buffer, or nothing when the file is cut short.
+fn read_exact(file: &mut fs::File, buf: &mut [u8]) -> Option<()> {
+    match read_up_to(file, buf) {
+        Ok(n) if n == buf.len() => Some(()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+    use testing;
+
+    fn temp(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("wallsplash-verify-{}-{}", process::id(), name))
+    }
+
+    fn written(name: &str, data: &[u8]) -> PathBuf {
+        let path = temp(name);
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn png_size_from_ihdr() {
+        let path = temp("ok.png");
+        testing::write_png(&path, 1920, 1080).unwrap();
+        assert_eq!(image_size(&path), Ok((1920, 1080)));
+        assert_eq!(check_image(&path), Ok(()));
+    }
+
+    #[test]
+    fn truncated_png_fails() {
+        let path = temp("full.png");
+        testing::write_png(&path, 1920, 1080).unwrap();
+        let mut data = Vec::new();
+        fs::File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+
+        for &len in &[8, 12, 16, 20, 23] {
+            let cut = written(&format!("cut-{}.png", len), &data[..len]);
+            assert_eq!(check_image(&cut), Err(Problem::BadHeader), "cut at {}", len);
+        }
+
+        let mut zero = data.clone();
+        zero[16..20].copy_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(check_image(&written("zero.png", &zero)), Err(Problem::BadHeader));
+
+        let mut chunk = data.clone();
+        chunk[12..16].copy_from_slice(b"IDAT");
+        assert_eq!(check_image(&written("idat.png", &chunk)), Err(Problem::BadHeader));
+    }
+
+    #[test]
+    fn jpeg_size_from_sof() {
+        let path = temp("ok.jpg");
+        testing::write_jpeg(&path, 640, 480).unwrap();
+        assert_eq!(image_size(&path), Ok((640, 480)));
+
+        // Truncated before the frame header is complete.
+        let mut data = Vec::new();
+        fs::File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        for &len in &[3, 4, 20, 24, 27] {
+            let cut = written(&format!("cut-{}.jpg", len), &data[..len]);
+            assert_eq!(check_image(&cut), Err(Problem::BadHeader), "cut at {}", len);
+        }
+        assert_eq!(
+            check_image(&written("magic.jpg", b"\xFF\xD8\xFF\xE0")),
+            Err(Problem::BadHeader)
+        );
+    }
+
+    #[test]
+    fn other_formats() {
+        let gif = written("ok.gif", b"GIF89a\x20\x03\x58\x02\x00\x00\x00");
+        assert_eq!(image_size(&gif), Ok((800, 600)));
+
+        let mut bmp = b"BM".to_vec();
+        bmp.extend_from_slice(&[0; 16]);
+        bmp.extend_from_slice(&[0x20, 0x03, 0, 0, 0xA8, 0xFD, 0xFF, 0xFF]);
+        assert_eq!(image_size(&written("ok.bmp", &bmp)), Ok((800, 600)));
+
+        let mut webp = b"RIFF\x00\x00\x00\x00WEBPVP8X\x0A\x00\x00\x00".to_vec();
+        webp.extend_from_slice(&[0, 0, 0, 0, 0x1F, 0x03, 0, 0x57, 0x02, 0]);
+        assert_eq!(image_size(&written("ok.webp", &webp)), Ok((800, 600)));
+
+        let webp = written("cut.webp", b"RIFF\x00\x00\x00\x00WEBPVP8 ");
+        assert_eq!(check_image(&webp), Err(Problem::BadHeader));
+    }
+
+    #[test]
+    fn unknown_and_empty_files() {
+        assert_eq!(check_image(&written("empty.jpg", b"")), Err(Problem::Empty));
+        assert_eq!(
+            check_image(&written("page.jpg", b"<html>rate limited</html>")),
+            Err(Problem::UnknownFormat)
+        );
+        assert_eq!(check_image(&temp("missing.jpg")), Err(Problem::Unreadable));
+    }
+
+    #[test]
+    fn verify_local_checks_the_images_rotation_uses() {
+        let dir = temp("local");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        testing::write_jpeg(&dir.join("good.jpg"), 640, 480).unwrap();
+        fs::write(dir.join("bad.png"), b"not a png").unwrap();
+        fs::write(dir.join("README.txt"), b"my wallpapers").unwrap();
+        fs::write(dir.join(".directory"), b"[Desktop Entry]").unwrap();
+        fs::write(dir.join("sub").join("bad.jpg"), b"").unwrap();
+
+        let flat = verify_local(&dir, &[], false, false, false).unwrap();
+        assert_eq!(flat.checked, 2);
+        assert_eq!(flat.failures, vec![(Problem::UnknownFormat, dir.join("bad.png"))]);
+
+        let report = verify_local(&dir, &[], false, true, true).unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(
+            report.failures,
+            vec![
+                (Problem::Empty, dir.join("sub").join("bad.jpg")),
+                (Problem::UnknownFormat, dir.join("bad.png")),
+            ]
+        );
+        let quarantine = dir.join(QUARANTINE_DIR);
+        assert!(quarantine.join("bad.png").exists());
+        assert!(quarantine.join("sub").join("bad.jpg").exists());
+        assert!(dir.join("README.txt").exists());
+        assert!(dir.join(".directory").exists());
+        assert!(dir.join("good.jpg").exists());
+
+        // Quarantined files are not checked again, even with hidden files included.
+        let again = verify_local(&dir, &[], true, true, false).unwrap();
+        assert_eq!(again.checked, 1);
+        assert!(again.failures.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+//! Runs the wallsplash binary with broken arguments and configuration, and checks the exit
+//! status each failure maps to.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output, Stdio};
+
+/// Scratch home directory for one test, so that no real configuration or cache is read.
+fn home(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("wallsplash-exit-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run wallsplash from the given home directory.
+fn wallsplash(home: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_wallsplash"))
+        .args(args)
+        .env("HOME", home)
+        .env_remove("XDG_STATE_HOME")
+        .env_remove("RUST_LOG")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+/// Run wallsplash with the given configuration file contents.
+fn with_config(name: &str, config: &str, args: &[&str]) -> Output {
+    let home = home(name);
+    let path = home.join("config.toml");
+    fs::write(&path, config).unwrap();
+
+    let mut all = vec!["--config", path.to_str().unwrap()];
+    all.extend_from_slice(args);
+    let output = wallsplash(&home, &all);
+    fs::remove_dir_all(&home).unwrap();
+    output
+}
+
+fn status(output: &Output) -> i32 {
+    output.status.code().expect("killed by a signal")
+}
+
+#[test]
+fn help_and_version_succeed() {
+    let home = home("help");
+    for flag in &["--help", "-h", "--version", "-V", "--print-exit-codes"] {
+        let output = wallsplash(&home, &[flag]);
+        assert_eq!(status(&output), 0, "{}", flag);
+        assert!(!output.stdout.is_empty(), "{}", flag);
+    }
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn bad_arguments_are_config_errors() {
+    let home = home("args");
+    let cases: &[&[&str]] = &[
+        &["--no-such-flag"],
+        &["--order", "sideways"],
+        &["--limit"],
+        &["no-such-command"],
+        &["verify-local", "--no-such-flag"],
+    ];
+    for args in cases {
+        let output = wallsplash(&home, args);
+        assert_eq!(status(&output), 2, "{:?}", args);
+        assert!(output.stdout.is_empty(), "{:?}", args);
+        assert!(!output.stderr.is_empty(), "{:?}", args);
+    }
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn bad_configuration_is_a_config_error() {
+    let cases = [
+        ("syntax", "timeout = [", vec!["--token", "t", "--dir", "/tmp"]),
+        ("type", "timeout = \"soon\"", vec!["--token", "t", "--dir", "/tmp"]),
+        ("token", "", vec!["--dir", "/tmp"]),
+        ("limit", "", vec!["--token", "t", "--dir", "/tmp", "--limit", "31"]),
+    ];
+    for &(name, config, ref args) in &cases {
+        let output = with_config(name, config, args);
+        assert_eq!(status(&output), 2, "{}", name);
+    }
+}
+
+#[test]
+fn unopenable_log_file_is_an_environment_error() {
+    let config = "[logging]\nfile = \"/proc/wallsplash/missing/wallsplash.log\"\n";
+    let output = with_config("log", config, &["--token", "t", "--dir", "/tmp"]);
+    assert_eq!(status(&output), 3);
+}
+
+#[test]
+fn failed_images_exit_with_one() {
+    let home = home("verify");
+    let dir = home.join("walls");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("broken.png"), b"not a png").unwrap();
+
+    let output = wallsplash(&home, &["--dir", dir.to_str().unwrap(), "verify-local"]);
+    assert_eq!(status(&output), 1);
+    fs::remove_dir_all(&home).unwrap();
+}
+
+#[test]
+fn slideshow_ignores_the_configuration() {
+    let config = "timeout = [\n[unsplash]\nlimit = 99\n";
+    let args = ["slideshow", "/nonexistent/wallsplash", "--timeout", "15s"];
+    // Fails for want of images, not because of the broken configuration.
+    assert_eq!(status(&with_config("slideshow", config, &args)), 4);
+    assert_eq!(status(&with_config("slideshow", config, &["status"])), 2);
+}